@@ -15,111 +15,237 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 use anyhow::{anyhow, Result};
-use azure_storage_blobs::prelude::*;
-use futures::stream::StreamExt;
+use bytes::Bytes;
+use futures::stream::{FuturesUnordered, StreamExt, TryStreamExt};
 use std::{
-    collections::HashMap,
-    fmt::Display,
+    collections::{HashMap, HashSet},
     io::{Read, Seek, Write},
-    os::unix::prelude::{MetadataExt, PermissionsExt},
+    path::Path,
 };
 use walkdir;
 
+use crate::compression::{self, Compression};
 use crate::config::Config;
+use crate::consistency::{self, Policy};
+use crate::content_hash::{self, ContentHasher};
+use crate::crypto::{self, MasterKey};
+use crate::dedup::{self, ChunkStore};
+use crate::incremental::{self, BlockMap};
+use crate::index::{blob_name, create_remote_index, FileType, Index, Version};
+use crate::resume;
+use crate::storage::{self, Storage};
+use crate::throttle::{self, Throttle};
+
+/// How long versions are kept, see the config template for the meaning of the values.
+struct Retention {
+    min_update_age: u64,
+    num_daily: u64,
+    num_weekly: u64,
+    num_monthly: u64,
+}
+
+/// How the content of files is encoded and uploaded.
+struct Encoding<'a> {
+    compression: Option<&'a Compression>,
+    encryption: Option<&'a MasterKey>,
+    chunks: Option<&'a ChunkStore<'a>>,
+    /// Unchanged blocks are copied from the previous version, see incremental.rs
+    incremental: bool,
+    /// The versions that have a block map
+    block_maps: &'a HashSet<String>,
+    /// Hashes the content, the hash is committed with the blob so it can be verified later
+    checksums: &'a ContentHasher,
+    /// How many files are uploaded at once
+    file_concurrency: usize,
+    /// How many blocks of a file are uploaded at once. Every block in flight is held in memory.
+    block_concurrency: usize,
+    /// Limits the upload rate, shared by all uploads
+    throttle: Option<&'a Throttle>,
+    /// What happens to files that are modified while they are uploaded
+    on_modified: Policy,
+}
 
-pub async fn run(conf: &Config) -> Result<()> {
+/// Files are uploaded in blocks of at least this size.
+const MIN_BLOCK_SIZE: u64 = 4 << 20;
+
+/// The size of the blocks a file of `len` bytes is uploaded in, for a storage allowing `max_blocks`
+/// blocks per blob.
+pub fn block_size(len: u64, max_blocks: u64) -> u64 {
+    // Azure block storage expects a whole bunch of blocks to be uploaded and then merged into a blob.
+    // We choose at least 4MiB per block, or try to aim for half of the maximum number of blocks, i.e.
+    // 25000 blocks on azure.
+    std::cmp::max(MIN_BLOCK_SIZE, len / (max_blocks / 2))
+}
+
+/// The outcome of a backup run.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The paths that could not be backed up, with the reason. Their stored versions are left as
+    /// they are, so they are tried again on the next run.
+    pub failed: Vec<(String, String)>,
+}
+
+/// Backs up the configured local root into `storage`. `now` is the unix timestamp the run is
+/// considered to happen at, new versions are uploaded with it and retention is computed relative to it.
+/// Files that can't be read or uploaded don't stop the run, they are listed in the report instead.
+pub async fn run(conf: &Config, storage: &dyn Storage, now: u64) -> Result<Report> {
     // Get the config values
-    let local_root = conf.get_string("local_root")?;
-    let sas_url = conf.get_string("sas_url")?;
-    let min_update_age = conf.get_i64("min_update_age")?;
-    let num_daily = conf.get_i64("num_daily")?;
-    let num_weekly = conf.get_i64("num_weekly")?;
-    let num_monthly = conf.get_i64("num_monthly")?;
-
-    if num_daily < 0 || num_daily > 7 {
-        return Err(anyhow!(
-            "Malformed config: num daily has to be in the interval of [1;7], but is {}",
-            num_daily
-        ));
-    }
-    if num_weekly < 0 || num_weekly > 4 {
-        return Err(anyhow!(
-            "Malformed config: num weekly has to be in the interval of [0;4], but is {}",
-            num_weekly
-        ));
+    let local_root = &conf.local_root;
+    let compression = compression::from_config(conf);
+    let encryption = crypto::from_config(conf)?;
+    let dedup_min_size = dedup::from_config(conf);
+    let incremental = conf.block_incremental;
+    let hasher = content_hash::from_config(conf, encryption.as_ref());
+    let throttle = throttle::from_config(conf);
+
+    if incremental && encryption.is_some() {
+        log::warn!("Encrypted files are always uploaded in full, block_incremental has no effect");
     }
-    if num_monthly < 0 {
-        return Err(anyhow!(
-            "Malformed config: num num_monthly has to be non-negative, but is {}",
-            num_monthly
-        ));
-    }
-    if num_monthly == 0 && num_weekly == 0 && num_daily == 0 {
-        return Err(anyhow!(
-            "Malformed config: requested for no backups to be kept."
-        ));
+    if incremental && !storage.can_copy_blocks() {
+        log::warn!("The storage can't copy blocks, block_incremental has no effect");
     }
 
     log::info!("Uploading {}", local_root);
 
+    let chunks = match dedup_min_size {
+        Some(min_size) => Some(ChunkStore::load(storage, encryption.as_ref(), min_size).await?),
+        None => None,
+    };
+    let block_maps = if incremental && storage.can_copy_blocks() {
+        incremental::load_names(storage).await?
+    } else {
+        HashSet::new()
+    };
+    let encoding = Encoding {
+        compression: compression.as_ref(),
+        encryption: encryption.as_ref(),
+        chunks: chunks.as_ref(),
+        incremental,
+        block_maps: &block_maps,
+        checksums: &ContentHasher::new(encryption.as_ref()),
+        file_concurrency: conf.upload_concurrency,
+        block_concurrency: conf.block_upload_concurrency,
+        throttle: throttle.as_ref(),
+        on_modified: conf.modified_during_upload,
+    };
+
     // Create the local index
     log::info!("Begin indexing of the local storage");
-    let local = create_local_index(&local_root)?;
+    let mut report = Report::default();
+    let mut local = create_local_index(
+        local_root,
+        now,
+        &encoding,
+        hasher.as_ref(),
+        &mut report.failed,
+    )?;
     log::info!("Indexed the local storage with {} files", local.files.len());
 
     // Create the remote index
     log::info!("Begin indexing of the remote storage");
-    let mut remote = create_remote_index(&sas_url).await?;
+    let mut remote = create_remote_index(storage, "/").await?;
     log::info!(
         "Indexed the remote storage with {} files",
         remote.files.len()
     );
+    resume::adopt(storage, &mut local, &remote).await?;
+    let had_deduplicated = remote
+        .files
+        .values()
+        .flatten()
+        .any(|version| version.deduplicated);
 
     // Run an update
     log::info!("Begin syncronization of the local and remote storage");
+    let retention = Retention {
+        min_update_age: conf.min_update_age,
+        num_daily: conf.num_daily,
+        num_weekly: conf.num_weekly,
+        num_monthly: conf.num_monthly,
+    };
     sync_remote_index(
         &local,
         &mut remote,
-        &sas_url,
-        &local_root,
-        min_update_age as u64,
-        num_daily as u64,
-        num_weekly as u64,
-        num_monthly as u64,
+        storage,
+        local_root,
+        &encoding,
+        &retention,
+        now,
+        &mut report.failed,
     )
     .await?;
 
-    Ok(())
+    // Versions keep referencing their chunks after deduplication is disabled, so garbage is
+    // collected as long as there are deduplicated versions
+    if chunks.is_some() || had_deduplicated {
+        dedup::collect_garbage(storage, encryption.as_ref(), &remote).await?;
+    }
+    incremental::collect_garbage(storage, &remote).await?;
+    consistency::collect_garbage(storage, &remote).await?;
+
+    Ok(report)
 }
 
-fn create_local_index(root: &str) -> Result<Index> {
+/// Indexes the files below `root`. The versions are flagged with the way their content is going to
+/// be encoded, so versions uploaded without encryption don't match them. With a `hasher` the
+/// content of regular files is hashed as well, which means reading every file. Paths that can't be
+/// read are added to `failed` and left out of the index.
+fn create_local_index(
+    root: &str,
+    now: u64,
+    encoding: &Encoding<'_>,
+    hasher: Option<&ContentHasher>,
+    failed: &mut Vec<(String, String)>,
+) -> Result<Index> {
     let mut index = Index::new();
 
     let walker = walkdir::WalkDir::new(root);
     let walker = walker.follow_links(false);
 
     for entry in walker {
-        let entry = entry?;
+        let entry = match entry {
+            Ok(entry) => entry,
+            // Nothing can be backed up without the root
+            Err(e) if e.depth() == 0 => return Err(e.into()),
+            Err(e) => {
+                let path = match e.path().and_then(|path| path.to_str()) {
+                    Some(path) => index_path(root, path),
+                    None => return Err(e.into()),
+                };
+                log::error!("Unable to read {}: {}", path, e);
+                failed.push((path, e.to_string()));
+                continue;
+            }
+        };
         let file_type = entry.file_type();
 
         if file_type.is_file() || file_type.is_symlink() || file_type.is_dir() {
             let path = entry.path().to_str();
             match path {
                 Some(path) => {
-                    let path = path.to_string();
-                    // Strip the root path, make sure we have a leading slash
-                    let mut path: String = path.chars().skip(root.len()).collect();
-                    if path.is_empty() || path.as_bytes()[0] != b'/' {
-                        path = "/".to_string() + &path;
+                    let path = index_path(root, path);
+
+                    if dedup::is_reserved(&path) {
+                        if path == dedup::RESERVED_PATH {
+                            log::warn!("Skipping {}, the path is reserved for internal data", path);
+                        }
+                        continue;
                     }
 
-                    index.files.insert(path, vec![entry.try_into()?]);
+                    let version = match local_version(&entry, &path, now, encoding, hasher) {
+                        Ok(version) => version,
+                        Err(e) => {
+                            log::error!("Unable to read {}: {}", path, e);
+                            failed.push((path, e.to_string()));
+                            continue;
+                        }
+                    };
+                    index.files.insert(path, vec![version]);
                 }
                 None => {
-                    return Err(anyhow!(
-                        "Found a path with non unicode characters: {:?}",
-                        entry.path()
-                    ));
+                    let path = entry.path().to_string_lossy().to_string();
+                    log::error!("Skipping {}, the path has non unicode characters", path);
+                    failed.push((path, "The path has non unicode characters".to_string()));
                 }
             }
         }
@@ -128,65 +254,124 @@ fn create_local_index(root: &str) -> Result<Index> {
     Ok(index)
 }
 
-async fn create_remote_index(sas_url: &str) -> Result<Index> {
-    let mut index = Index::new();
-    let client = ContainerClient::from_sas_url(&url::Url::parse(sas_url)?)?;
-
-    let list_builder = client.list_blobs();
-    let mut list_stream = list_builder.into_stream();
-
-    while let Some(page) = list_stream.next().await {
-        for blob in page?.blobs.blobs() {
-            let path = "/".to_string() + &blob.name;
-            let last_delim = path.rfind('/');
-
-            if last_delim.is_none() {
-                return Err(anyhow!("Malformed remote path: {}", path));
-            }
-            let last_delim = last_delim.unwrap();
-
-            if last_delim + 1 >= path.len() {
-                return Err(anyhow!("Malformed remote path (trailing slash): {}", path));
-            }
-
-            let version =
-                Version::try_from(std::str::from_utf8(&path.as_bytes()[last_delim + 1..])?)?;
-            let file_path = std::str::from_utf8(&path.as_bytes()[..last_delim])?.to_string();
+/// Strips the root from a local path, the result has a leading slash.
+fn index_path(root: &str, path: &str) -> String {
+    let path: String = path.chars().skip(root.len()).collect();
+    if path.is_empty() || path.as_bytes()[0] != b'/' {
+        return "/".to_string() + &path;
+    }
+    path
+}
 
-            let versions = index.files.get_mut(&file_path);
-            match versions {
-                Some(versions) => {
-                    versions.push(version);
-                }
-                None => {
-                    index.files.insert(file_path, vec![version]);
-                }
-            }
-        }
+/// The version of the local file at `entry`, flagged with the way it is going to be encoded.
+fn local_version(
+    entry: &walkdir::DirEntry,
+    path: &str,
+    now: u64,
+    encoding: &Encoding<'_>,
+    hasher: Option<&ContentHasher>,
+) -> Result<Version> {
+    let mut version = Version::from_dir_entry(entry, now)?;
+    version.deduplicated = encoding
+        .chunks
+        .is_some_and(|chunks| chunks.applies_to(&version));
+    version.compressed = version.file_type == FileType::Regular
+        && !version.deduplicated
+        && encoding
+            .compression
+            .is_some_and(|compression| compression.applies_to(path));
+    version.encrypted = encoding.encryption.is_some()
+        && matches!(version.file_type, FileType::Regular | FileType::Symlink);
+    if let (Some(hasher), FileType::Regular) = (hasher, &version.file_type) {
+        version.content_hash = Some(hasher.hash_file(entry.path())?);
     }
+    Ok(version)
+}
 
-    Ok(index)
+/// What `upload_file` read from the local file.
+#[derive(Default)]
+struct Uploaded {
+    /// The hash of the uploaded content, if it was hashed while uploading
+    checksum: Option<String>,
+    /// How many bytes of content were uploaded, if the file was read block by block
+    size: Option<u64>,
 }
 
+/// Uploads `version` of the file at `path`. With block incremental uploads, the blocks `previous`
+/// has in common with it are copied instead of uploaded.
 async fn upload_file(
     version: &Version,
+    previous: Option<&Version>,
     path: &str,
     local_root: &str,
-    client: &mut ContainerClient,
-) -> Result<()> {
-    let remote_path = path.to_owned() + "/" + &version.serialize();
+    storage: &dyn Storage,
+    encoding: &Encoding<'_>,
+) -> Result<Uploaded> {
+    let remote_path = blob_name(path, version);
     let local_path = local_root.to_string() + path;
 
-    let blob = client.blob_client(&remote_path);
+    let compression = match (version.compressed, encoding.compression) {
+        (false, _) => None,
+        (true, Some(compression)) => Some(compression),
+        (true, None) => {
+            return Err(anyhow!(
+                "{} is to be compressed, but compression is disabled",
+                path
+            ))
+        }
+    };
+    let mut encryptor = match (version.encrypted, encoding.encryption) {
+        (false, _) => None,
+        (true, Some(key)) => Some(key.encryptor(&remote_path)?),
+        (true, None) => {
+            return Err(anyhow!(
+                "{} is to be encrypted, but no encryption key is configured",
+                path
+            ))
+        }
+    };
 
+    let mut uploaded = Uploaded::default();
     match version.file_type {
         FileType::Symlink => {
             let link = std::fs::read_link(local_path)?;
-            let link = link.to_string_lossy().to_string().into_bytes();
-            blob.put_block_blob(link).await?;
+            let mut link = link.to_string_lossy().to_string().into_bytes();
+            let mut checksum = encoding.checksums.hasher();
+            checksum.update(&link);
+            if let Some(encryptor) = &mut encryptor {
+                link = encryptor.encrypt_block(&link, true)?;
+            }
+            let checksum = checksum.finalize();
+            put_with_checksum(storage, &remote_path, link, &checksum).await?;
+            uploaded.checksum = Some(checksum);
         }
         FileType::Folder => {
-            blob.put_block_blob(vec![]).await?;
+            storage.put(&remote_path, Bytes::new()).await?;
+        }
+        FileType::Regular if version.deduplicated => {
+            let chunks = encoding.chunks.ok_or_else(|| {
+                anyhow!(
+                    "{} is to be deduplicated, but deduplication is disabled",
+                    path
+                )
+            })?;
+
+            // Chunks are compressed on their own, the manifest is only encrypted
+            let compression = encoding
+                .compression
+                .filter(|compression| compression.applies_to(path));
+            let file = std::fs::File::open(&local_path)?;
+            let mut checksum = encoding.checksums.hasher();
+            let mut manifest = chunks
+                .upload(file, compression, encoding.throttle, &mut checksum)
+                .await?;
+
+            if let Some(encryptor) = &mut encryptor {
+                manifest = encryptor.encrypt_block(&manifest, true)?;
+            }
+            let checksum = checksum.finalize();
+            put_with_checksum(storage, &remote_path, manifest, &checksum).await?;
+            uploaded.checksum = Some(checksum);
         }
         FileType::Regular => {
             // Stream up the file
@@ -195,81 +380,396 @@ async fn upload_file(
             let len = file.seek(std::io::SeekFrom::End(0))?;
             file.seek(std::io::SeekFrom::Start(0))?;
 
-            // Azure block storage expects a whole bunch of blocks to be uploaded and then merged into a blob.
-            // We choose at least 4MiB per block, or try to aim for 25000 blocks per blob (half of the max of 50000).
-            let block_size = std::cmp::max(4 << 20, len / 25000);
+            let block_size = block_size(len, storage.max_blocks());
 
             // If our file size is not a multiple of the block size we need a partially filled block
             let mut num_blocks = len / block_size;
             if len % block_size != 0 {
                 num_blocks += 1;
             }
+            // Encrypted and compressed files need at least one block to carry the header or the
+            // zstd frame, so their blob can be checked like any other
+            if (encryptor.is_some() || compression.is_some()) && num_blocks == 0 {
+                num_blocks = 1;
+            }
 
-            // Every block needs an id, so we use a combination of the block index in the blob and a hash of the filename
-            let id_suffix = sha256::digest(remote_path);
-
-            let mut block_buf: Vec<u8> = Vec::new();
-            block_buf.resize(block_size as usize, 0);
+            let mut block_buf: Vec<u8> = vec![0; block_size as usize];
+
+            let mut block_list = Vec::<String>::new();
+            let mut checksum = encoding.checksums.hasher();
+
+            // Encrypted blocks are bound to the data key and position of their version, so they
+            // can't be reused. Files of a single block are cheaper to upload than to keep a map of.
+            // Storages that can't copy blocks get every block uploaded from the local file.
+            let mut block_map = (encoding.incremental
+                && storage.can_copy_blocks()
+                && encryptor.is_none()
+                && num_blocks > 1)
+                .then(|| BlockMap::new(block_size));
+            let mut previous_map = None;
+            if let (Some(_), Some(previous)) = (&block_map, previous) {
+                previous_map = BlockMap::load(storage, encoding.block_maps, path, previous)
+                    .await?
+                    .filter(|map| map.block_size == block_size)
+                    .map(|map| (blob_name(path, previous), map));
+            }
 
-            let mut block_list = Vec::<BlobBlockType>::new();
+            // Uploads of several blocks can be resumed by a later run, see resume.rs. Encrypted
+            // blocks can't, every upload encrypts them with a new data key.
+            let marker =
+                (encryptor.is_none() && num_blocks > 1).then(|| resume::marker_name(path, version));
+            let mut staged = HashMap::new();
+            if let Some(marker) = &marker {
+                storage.put(marker, Bytes::new()).await?;
+                staged = storage.staged_blocks(&remote_path).await?;
+                if !staged.is_empty() {
+                    log::info!(
+                        "Resuming the upload of {} with {} staged blocks",
+                        path,
+                        staged.len()
+                    );
+                }
+            }
 
-            for i in 0..num_blocks {
-                // Generate an id
-                let mut block_id = format!("{i:016}{id_suffix}");
-                block_id.truncate(64);
-                let block_id = BlockId::from(block_id);
+            // Blocks are read and encoded in order, but up to block_concurrency of them are uploaded
+            // at the same time
+            let block_concurrency = match storage.concurrent_blocks() {
+                true => encoding.block_concurrency,
+                false => 1,
+            };
+            let name = remote_path.as_str();
+            let mut size = 0;
+            let committed: Result<String> = async {
+                let mut pending = FuturesUnordered::new();
+
+                for i in 0..num_blocks {
+                    // Generate an id
+                    let block_id = storage::block_id(name, i);
+
+                    // load the block from disk. Blocks are only short at the end of the file, so
+                    // every block starts at a multiple of the block size. A file that shrank in the
+                    // meantime is caught by comparing the size read with the size of the version.
+                    let length = std::cmp::min(block_size, len - i * block_size) as usize;
+                    // The read blocks, so it runs on its own thread while the pending blocks
+                    // keep uploading
+                    let read = tokio::task::spawn_blocking(move || {
+                        let num_read = read_block(&mut file, &mut block_buf[0..length])?;
+                        Ok::<_, anyhow::Error>((file, block_buf, num_read))
+                    });
+                    tokio::pin!(read);
+                    let num_read;
+                    (file, block_buf, num_read) = loop {
+                        tokio::select! {
+                            read = &mut read => break read??,
+                            Some(block) = pending.next(), if !pending.is_empty() => block?,
+                        }
+                    };
+                    size += num_read as u64;
+                    checksum.update(&block_buf[0..num_read]);
+
+                    let hash = block_map
+                        .is_some()
+                        .then(|| incremental::hash_block(&block_buf[0..num_read]));
+
+                    // encode the block. Copied blocks are encoded as well, so the storage can check
+                    // the copy against the MD5 of the data it stands for.
+                    let mut payload = Vec::from(&mut block_buf[0..num_read]);
+                    if let Some(compression) = compression {
+                        payload = compression.compress_block(&payload)?;
+                    }
+                    if let Some(encryptor) = &mut encryptor {
+                        payload = encryptor.encrypt_block(&payload, i + 1 == num_blocks)?;
+                    }
+                    let size = payload.len() as u64;
+
+                    // copy the block if the previous version stored it the same way
+                    let copy_from = match (&previous_map, &hash) {
+                        (Some((source, map)), Some(hash)) => map
+                            .find(hash)
+                            .filter(|(_, length)| *length == size)
+                            .map(|(offset, _)| (source, offset, md5::compute(&payload))),
+                        _ => None,
+                    };
+                    if let (Some(block_map), Some(hash)) = (&mut block_map, hash) {
+                        block_map.push(hash, size);
+                    }
+                    if copy_from.is_some() {
+                        payload = Vec::new();
+                    }
 
-                // load the block from disk
-                let num_read = file.read(&mut block_buf[..])?;
+                    // remember its id
+                    block_list.push(block_id.clone());
 
-                // upload the block
-                let payload = Vec::from(&mut block_buf[0..num_read]);
-                blob.put_block(block_id.clone(), payload).await?;
+                    // skip the block if an interrupted upload staged it already
+                    if staged.get(&block_id) == Some(&size) {
+                        continue;
+                    }
 
-                // remember its id
-                block_list.push(BlobBlockType::Uncommitted(block_id));
+                    // wait for a slot, then upload or copy the block. Copies stay within the storage
+                    // and don't count towards the upload limit, uploads wait for the throttle
+                    // without holding up the others.
+                    if pending.len() >= block_concurrency {
+                        pending.try_next().await?;
+                    }
+                    pending.push(async move {
+                        if let (Some(throttle), None) = (encoding.throttle, copy_from) {
+                            throttle.acquire(size).await;
+                        }
+                        match copy_from {
+                            Some((source, offset, content_md5)) => {
+                                storage
+                                    .put_block_from(
+                                        name,
+                                        &block_id,
+                                        source,
+                                        offset,
+                                        size,
+                                        content_md5,
+                                    )
+                                    .await
+                            }
+                            None => storage.put_block(name, &block_id, payload.into()).await,
+                        }
+                    });
+                }
+                while pending.try_next().await?.is_some() {}
+
+                // commit the blocks
+                let checksum = checksum.finalize();
+                storage
+                    .put_block_list(&remote_path, &block_list, &checksum)
+                    .await?;
+                Ok(checksum)
+            }
+            .await;
+            // Blocks of uploads that can't be resumed are of no use to a later run
+            if let (Err(_), None) = (&committed, &marker) {
+                if let Err(e) = storage.discard_staged(name).await {
+                    log::warn!("Unable to discard the staged blocks of {}: {:#}", path, e);
+                }
+            }
+            uploaded.checksum = Some(committed?);
+            uploaded.size = Some(size);
+            // A marker left behind is cleaned up by the next run
+            if let Some(marker) = &marker {
+                if let Err(e) = storage.delete(marker).await {
+                    log::warn!("Unable to delete the upload marker of {}: {:#}", path, e);
+                }
             }
 
-            // commit the blocks
-            blob.put_block_list(BlockList { blocks: block_list })
-                .await?;
+            if let Some(block_map) = block_map {
+                storage
+                    .put(
+                        &incremental::block_map_name(path, version),
+                        block_map.serialize().into(),
+                    )
+                    .await?;
+            }
         }
         FileType::Deleted => {
-            blob.put_block_blob(vec![]).await?;
+            storage.put(&remote_path, Bytes::new()).await?;
         }
     }
 
-    Ok(())
+    Ok(uploaded)
 }
 
-async fn delete_file_version(
-    version: &Version,
-    path: &str,
-    client: &mut ContainerClient,
+/// Fills `buf` from `file`, reading less only at the end of the file. Returns the number of bytes
+/// read.
+/// Stores `data` as the blob `name` in a single block, so it is committed with the `checksum` of
+/// the content it stands for, like the blocks of regular files.
+async fn put_with_checksum(
+    storage: &dyn Storage,
+    name: &str,
+    data: Vec<u8>,
+    checksum: &str,
 ) -> Result<()> {
-    let remote_path = path.to_owned() + "/" + &version.serialize();
+    let block_id = storage::block_id(name, 0);
+    storage.put_block(name, &block_id, data.into()).await?;
+    storage.put_block_list(name, &[block_id], checksum).await
+}
+
+fn read_block(file: &mut std::fs::File, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(num_read) => filled += num_read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Uploads `version` of the file at `path` and checks that the file didn't change in the meantime,
+/// see consistency.rs. Returns the version that ended up stored, which is a newer one if the file
+/// was uploaded again.
+async fn upload_version(
+    mut version: Version,
+    previous: Option<&Version>,
+    path: &str,
+    local_root: &str,
+    storage: &dyn Storage,
+    encoding: &Encoding<'_>,
+) -> Result<Version> {
+    let local_path = local_root.to_string() + path;
+
+    for attempt in 1.. {
+        let uploaded = upload_file(&version, previous, path, local_root, storage, encoding).await?;
+        if !consistency::applies_to(&version) {
+            break;
+        }
+        let reason = match consistency::modification(
+            Path::new(&local_path),
+            &version,
+            uploaded.checksum.as_deref(),
+            uploaded.size,
+        ) {
+            Some(reason) => reason,
+            None => break,
+        };
+
+        if encoding.on_modified == Policy::Keep {
+            log::warn!("{} was modified during its upload, {}", path, reason);
+            storage
+                .put(&consistency::marker_name(path, &version), Bytes::new())
+                .await?;
+            break;
+        }
+
+        delete_file_version(&version, path, storage).await?;
+        if encoding.on_modified == Policy::Skip || attempt >= consistency::MAX_ATTEMPTS {
+            return Err(anyhow!(
+                "{} was modified during its upload, {}",
+                path,
+                reason
+            ));
+        }
+        log::warn!(
+            "{} was modified during its upload, {}. Uploading it again",
+            path,
+            reason
+        );
+
+        // Index the file again, the version is uploaded as part of the same run
+        let entry = walkdir::WalkDir::new(&local_path)
+            .max_depth(0)
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Unable to read {}", local_path))??;
+        let hasher = version.content_hash.is_some().then_some(encoding.checksums);
+        version = local_version(&entry, path, version.upload_time, encoding, hasher)?;
+    }
+
+    Ok(version)
+}
+
+/// Uploads the given versions, with the previous version of each, up to `file_concurrency` at a time.
+/// Returns the versions that failed to upload with the reason, and the versions that were replaced
+/// by a newer one, see `upload_version`.
+async fn upload_files(
+    uploads: Vec<(&str, Version, Option<Version>)>,
+    local_root: &str,
+    storage: &dyn Storage,
+    encoding: &Encoding<'_>,
+) -> Vec<(String, Version, Result<Version, String>)> {
+    let total_files = uploads.len();
+    let mut uploads = futures::stream::iter(uploads)
+        .map(|(path, version, previous)| async move {
+            let result = upload_version(
+                version.clone(),
+                previous.as_ref(),
+                path,
+                local_root,
+                storage,
+                encoding,
+            )
+            .await;
+            (path, version, result)
+        })
+        .buffer_unordered(encoding.file_concurrency);
+
+    // Files are counted once their upload completed
+    let mut outcomes = Vec::new();
+    let mut processed: usize = 0;
+    while let Some((path, version, result)) = uploads.next().await {
+        match result {
+            Ok(stored) if stored.serialize() == version.serialize() => {}
+            Ok(stored) => outcomes.push((path.to_string(), version, Ok(stored))),
+            Err(e) => {
+                log::error!("Unable to upload {}: {:#}", path, e);
+                outcomes.push((path.to_string(), version, Err(format!("{:#}", e))));
+            }
+        }
+
+        processed += 1;
+        print!("\r{processed} / {total_files}");
+
+        // If the flush fails its not the end of the world.
+        #[allow(unused_must_use)]
+        {
+            std::io::stdout().flush();
+        }
+    }
+    println!();
+
+    outcomes
+}
+
+/// Brings the remote index in line with what `upload_files` stored: versions that were replaced
+/// by a newer one are swapped, versions that failed to upload are removed again and their paths
+/// added to `failed`.
+fn record_uploads(
+    remote: &mut Index,
+    outcomes: Vec<(String, Version, Result<Version, String>)>,
+    failed: &mut Vec<(String, String)>,
+) {
+    for (path, version, outcome) in outcomes {
+        if let Some(versions) = remote.files.get_mut(&path) {
+            let name = version.serialize();
+            versions.retain(|stored| stored.serialize() != name);
+            if let Ok(stored) = &outcome {
+                versions.push(stored.clone());
+            }
+            if versions.is_empty() {
+                remote.files.remove(&path);
+            }
+        }
+        if let Err(reason) = outcome {
+            failed.push((path, reason));
+        }
+    }
+}
+
+/// Whether `path` lies inside the folder `parent`.
+fn is_below(path: &str, parent: &str) -> bool {
+    parent == "/" || path.starts_with(&(parent.to_string() + "/"))
+}
 
-    let blob = client.blob_client(remote_path);
-    blob.delete().await?;
+async fn delete_file_version(version: &Version, path: &str, storage: &dyn Storage) -> Result<()> {
+    storage.delete(&blob_name(path, version)).await?;
 
     Ok(())
 }
 
+/// Uploads the changes of `local` and applies the retention to `remote`. Paths that fail to upload
+/// are added to `failed`, which holds the paths that could not be indexed already. Neither are
+/// considered deleted.
+#[allow(clippy::too_many_arguments)]
 async fn sync_remote_index(
     local: &Index,
     remote: &mut Index,
-    sas_url: &str,
+    storage: &dyn Storage,
     local_root: &str,
-    min_update_age: u64,
-    num_daily: u64,
-    num_weekly: u64,
-    num_monthly: u64,
+    encoding: &Encoding<'_>,
+    retention: &Retention,
+    now: u64,
+    failed: &mut Vec<(String, String)>,
 ) -> Result<()> {
-    let mut client = ContainerClient::from_sas_url(&url::Url::parse(sas_url)?)?;
-
-    let mut processed: usize = 0;
-    let total_files = local.files.len();
+    let min_update_age = retention.min_update_age;
 
+    let mut uploads = Vec::new();
     log::info!("Finding new files to upload");
     for local_entry in &local.files {
         if local_entry.1.len() != 1 {
@@ -279,6 +779,7 @@ async fn sync_remote_index(
         }
 
         let mut update = true;
+        let mut previous = None;
 
         let remote_entry = remote.files.get_mut(local_entry.0);
         match remote_entry {
@@ -294,6 +795,18 @@ async fn sync_remote_index(
                     }
                 }
                 if update {
+                    // Blocks can be copied from the newest version stored the same way
+                    previous = remote_entry
+                        .iter()
+                        .filter(|version| {
+                            version.file_type == FileType::Regular
+                                && version.compressed == local_entry.1[0].compressed
+                                && !version.deduplicated
+                                && !version.encrypted
+                        })
+                        .max_by_key(|version| version.upload_time)
+                        .cloned();
+
                     // Add the new version
                     remote_entry.push(local_entry.1[0].clone());
                 }
@@ -307,22 +820,14 @@ async fn sync_remote_index(
         }
 
         if update {
-            upload_file(&local_entry.1[0], local_entry.0, local_root, &mut client).await?;
-        }
-
-        processed += 1;
-        print!("\r{processed} / {total_files}");
-
-        // If the flush fails its not the end of the world.
-        #[allow(unused_must_use)]
-        {
-            std::io::stdout().flush();
+            uploads.push((local_entry.0.as_str(), local_entry.1[0].clone(), previous));
         }
     }
-    println!("");
+    log::info!("Uploading {} files", uploads.len());
+    let outcomes = upload_files(uploads, local_root, storage, encoding).await;
+    record_uploads(remote, outcomes, failed);
 
-    let mut processed: usize = 0;
-    let total_files = remote.files.len();
+    let mut deletions = Vec::new();
     log::info!("Finding deleted files");
     // Check for remote files that were deleted locally
     for remote_entry in &mut remote.files {
@@ -331,13 +836,15 @@ async fn sync_remote_index(
                 "Malformed remote index: empty version list for {}",
                 &remote_entry.0
             );
-
-            processed += 1;
-            print!("\r{processed} / {total_files}");
             continue;
         }
 
-        if !local.files.contains_key(remote_entry.0) {
+        // Files that failed to be read are still there
+        let unreadable = failed
+            .iter()
+            .any(|(path, _)| remote_entry.0 == path || is_below(remote_entry.0, path));
+
+        if !local.files.contains_key(remote_entry.0) && !unreadable {
             // Find the newest remote version
             let mut version = remote_entry.1[0].clone();
             for i in 1..remote_entry.1.len() {
@@ -346,35 +853,33 @@ async fn sync_remote_index(
                 }
             }
 
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)?
-                .as_secs();
-
             if now < version.upload_time || now - version.upload_time < min_update_age {
                 // The latest entry is up to date enough, don't do anything
                 continue;
             }
 
+            if version.file_type == FileType::Deleted {
+                // The deletion was already recorded by an earlier run
+                continue;
+            }
+
             version.size = 0;
             version.mod_time = 0;
             version.upload_time = now;
             version.file_type = FileType::Deleted;
+            version.compressed = false;
+            version.deduplicated = false;
+            version.encrypted = false;
+            version.content_hash = None;
 
             // Create an entry on the remote for the deleted file. This is needed to gradually remove old files
-            upload_file(&version, remote_entry.0, local_root, &mut client).await?;
+            deletions.push((remote_entry.0.as_str(), version.clone(), None));
             remote_entry.1.push(version);
         }
-
-        processed += 1;
-        print!("\r{processed} / {total_files}");
-
-        // If the flush fails its not the end of the world.
-        #[allow(unused_must_use)]
-        {
-            std::io::stdout().flush();
-        }
     }
-    println!("");
+    log::info!("Recording {} deleted files", deletions.len());
+    let outcomes = upload_files(deletions, local_root, storage, encoding).await;
+    record_uploads(remote, outcomes, failed);
 
     // Remove uneeded remote versions
 
@@ -395,25 +900,22 @@ async fn sync_remote_index(
     let week = day * 7;
     let month = week * 4;
     let mut buckets = Vec::<VersionBucket>::new();
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)?
-        .as_secs();
 
-    for i in 0..num_daily {
+    for i in 0..retention.num_daily {
         buckets.push(VersionBucket {
             start: now - (i + 1) * day,
             end: now - i * day,
             versions: Vec::new(),
         });
     }
-    for i in 0..num_weekly {
+    for i in 0..retention.num_weekly {
         buckets.push(VersionBucket {
             start: now - (i + 1) * week,
             end: now - i * week,
             versions: Vec::new(),
         });
     }
-    for i in 0..num_monthly {
+    for i in 0..retention.num_monthly {
         buckets.push(VersionBucket {
             start: now - (i + 1) * month,
             end: now - i * month,
@@ -484,11 +986,11 @@ async fn sync_remote_index(
 
             // find the oldest upload time
             let mut oldest = remote_entry.1[bucket.versions[0]].upload_time;
-            let mut oldest_idx = 0;
-            for i in 1..bucket.versions.len() {
-                let upload_time = remote_entry.1[bucket.versions[i]].upload_time;
+            let mut oldest_idx = bucket.versions[0];
+            for idx in bucket.versions.iter().skip(1) {
+                let upload_time = remote_entry.1[*idx].upload_time;
                 if upload_time < oldest {
-                    oldest_idx = i;
+                    oldest_idx = *idx;
                     oldest = upload_time;
                 }
             }
@@ -502,13 +1004,42 @@ async fn sync_remote_index(
             }
         }
 
-        // Delete versions which aren't in a bucket
-        for bucketed in &bucketed_versions {
+        // Two rules on top of the buckets. The newest version is what the file currently is, so it
+        // is kept even if an older version holds its buckets. And if only deletion markers are
+        // left the file is gone, and the markers can go as well.
+        if let Some(newest) = bucketed_versions.last_mut() {
+            newest.bucket_count = std::cmp::max(newest.bucket_count, 1);
+        }
+        let only_deleted = bucketed_versions
+            .iter()
+            .filter(|bucketed| bucketed.bucket_count > 0)
+            .all(|bucketed| remote_entry.1[bucketed.version_idx].file_type == FileType::Deleted);
+        if only_deleted {
+            for bucketed in &mut bucketed_versions {
+                bucketed.bucket_count = 0;
+            }
+        }
+
+        // Delete versions which aren't in a bucket. Versions that fail to be deleted stay in the
+        // index, and are tried again by the next run.
+        for bucketed in &mut bucketed_versions {
             if bucketed.bucket_count == 0 {
                 let version = &remote_entry.1[bucketed.version_idx];
-                delete_file_version(version, remote_entry.0, &mut client).await?;
+                if let Err(e) = delete_file_version(version, remote_entry.0, storage).await {
+                    log::error!(
+                        "Unable to delete an old version of {}: {:#}",
+                        remote_entry.0,
+                        e
+                    );
+                    bucketed.bucket_count = 1;
+                }
             }
         }
+        // Leave the index with what is still stored, chunks are collected based on it
+        let mut kept = bucketed_versions
+            .iter()
+            .map(|bucketed| bucketed.bucket_count > 0);
+        remote_entry.1.retain(|_| kept.next().unwrap());
 
         processed += 1;
         print!("\r{processed} / {total_files}");
@@ -519,161 +1050,7 @@ async fn sync_remote_index(
             std::io::stdout().flush();
         }
     }
-    println!("");
+    println!();
 
     Ok(())
 }
-
-#[derive(Debug, Clone, PartialEq)]
-enum FileType {
-    Regular,
-    Symlink,
-    Folder,
-    Deleted,
-}
-
-impl FileType {
-    fn parse(raw: &str) -> Result<FileType> {
-        if raw == "Regular" {
-            Ok(FileType::Regular)
-        } else if raw == "Symlink" {
-            Ok(FileType::Symlink)
-        } else if raw == "Folder" {
-            Ok(FileType::Folder)
-        } else if raw == "Deleted" {
-            Ok(FileType::Deleted)
-        } else {
-            Err(anyhow!("{} is not a file type", raw))
-        }
-    }
-}
-
-impl Display for FileType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            FileType::Regular => f.write_str("Regular"),
-            FileType::Symlink => f.write_str("Symlink"),
-            FileType::Folder => f.write_str("Folder"),
-            FileType::Deleted => f.write_str("Deleted"),
-        }
-    }
-}
-
-#[derive(Debug, Clone)]
-struct Version {
-    mod_time: u64,
-    upload_time: u64,
-    permissions: u32,
-    size: u64,
-    file_type: FileType,
-    owner: u32,
-    group: u32,
-}
-
-impl Version {
-    fn serialize(&self) -> String {
-        format!(
-            "{}-{}-{:o}-{}-{}-{}-{}",
-            self.mod_time,
-            self.upload_time,
-            self.permissions,
-            self.size,
-            self.file_type,
-            self.owner,
-            self.group
-        )
-    }
-}
-
-impl std::fmt::Display for Version {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.serialize())?;
-
-        Ok(())
-    }
-}
-
-impl PartialEq for Version {
-    fn eq(&self, other: &Self) -> bool {
-        // Upload time is ignored as that is metadata and not part of the version
-        self.mod_time == other.mod_time
-            && self.permissions == other.permissions
-            && self.size == other.size
-            && self.file_type == other.file_type
-            && self.owner == other.owner
-            && self.group == other.group
-    }
-}
-
-impl Eq for Version {}
-
-impl PartialOrd for Version {
-    fn partial_cmp(&self, other: &Version) -> Option<std::cmp::Ordering> {
-        self.upload_time.partial_cmp(&other.upload_time)
-    }
-}
-
-impl TryFrom<walkdir::DirEntry> for Version {
-    type Error = anyhow::Error;
-
-    fn try_from(entry: walkdir::DirEntry) -> Result<Self> {
-        let metadata = entry.metadata()?;
-
-        let mut file_type = FileType::Regular;
-        if entry.file_type().is_symlink() {
-            file_type = FileType::Symlink;
-        } else if entry.file_type().is_dir() {
-            file_type = FileType::Folder;
-        }
-
-        Ok(Version {
-            mod_time: metadata
-                .modified()?
-                .duration_since(std::time::UNIX_EPOCH)?
-                .as_secs(),
-            upload_time: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)?
-                .as_secs(),
-            permissions: metadata.permissions().mode(),
-            size: metadata.size(),
-            file_type,
-            owner: metadata.uid(),
-            group: metadata.gid(),
-        })
-    }
-}
-
-impl TryFrom<&str> for Version {
-    type Error = anyhow::Error;
-
-    fn try_from(path: &str) -> Result<Self> {
-        let parts = path.split('-');
-        let collected: Vec<&str> = parts.collect();
-
-        if collected.len() != 7 {
-            return Err(anyhow!("Malformed version string {}", path));
-        }
-
-        Ok(Version {
-            mod_time: collected[0].parse()?,
-            upload_time: collected[1].parse()?,
-            permissions: u32::from_str_radix(collected[2], 8)?,
-            size: collected[3].parse()?,
-            file_type: FileType::parse(collected[4])?,
-            owner: collected[5].parse()?,
-            group: collected[6].parse()?,
-        })
-    }
-}
-
-struct Index {
-    files: HashMap<String, Vec<Version>>,
-}
-
-impl Index {
-    fn new() -> Index {
-        Index {
-            files: HashMap::new(),
-        }
-    }
-}
@@ -0,0 +1,217 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+
+use crate::index::Selection;
+use crate::list;
+use crate::restore;
+use crate::timestamp;
+use crate::verify;
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/azure_blob_backup/config.yaml";
+
+pub const USAGE: &str = "Usage: azure_blob_backup [config path] [command]
+
+Commands:
+  backup                  Upload the local root to the remote storage (default)
+  help                    Print this message
+  restore <target dir>    Restore the newest version of every file into the target dir
+    --path <path>         Only restore this path and everything below it, e.g. /etc/nginx
+    --at <time>           Restore the tree as it was at the given time. Accepts unix
+                          timestamps, RFC 3339 and YYYY-MM-DD[ HH:MM[:SS]] in local time
+    --version <n>         Restore the n-th version of every file ordered by upload time.
+                          0 is the oldest version, negative values count from the newest
+    --uid-map <from>:<to> Restore files owned by uid <from> as owned by <to>. A * as <from>
+                          maps all other uids. Can be given multiple times
+    --gid-map <from>:<to> The same as --uid-map for groups
+  list-versions <path>    List all versions of the path stored in the backup
+    --children            List the contents of the folder instead, like ls
+    --at <time>           List the children as they were at the given time
+    --version <n>         List the n-th version of every child, see restore
+  verify                  Download every stored version and check it against its checksums.
+                          Exits with 2 if corrupt, missing or unparseable blobs were found
+    --path <path>         Only verify this path and everything below it
+    --sample <percent>    Only verify a random sample of the versions, e.g. 5";
+
+pub enum Command {
+    Backup,
+    Help,
+    Restore(restore::Options),
+    ListVersions(list::Options),
+    Verify(verify::Options),
+}
+
+pub struct Invocation {
+    pub config_path: String,
+    pub command: Command,
+}
+
+/// Parses the command line arguments (without the program name). For backwards compatibility the
+/// config path is an optional leading positional argument, and running without a command runs a backup.
+pub fn parse(args: &[String]) -> Result<Invocation> {
+    let mut args = args;
+    let mut config_path = DEFAULT_CONFIG_PATH.to_string();
+
+    if let Some(first) = args.first() {
+        if !is_command(first) {
+            config_path = first.clone();
+            args = &args[1..];
+        }
+    }
+
+    let command = match args.first().map(|s| s.as_str()) {
+        None | Some("backup") => {
+            expect_no_more(args, 1)?;
+            Command::Backup
+        }
+        Some("help" | "--help" | "-h") => Command::Help,
+        Some("restore") => {
+            let mut target = None;
+            let mut path = "/".to_string();
+            let mut selection = Selection::Latest;
+            let mut uid_map = restore::IdMap::default();
+            let mut gid_map = restore::IdMap::default();
+
+            let mut rest = args[1..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--path" => path = normalize_path(option_value(arg, rest.next())?),
+                    "--at" | "--version" => parse_selection(arg, rest.next(), &mut selection)?,
+                    "--uid-map" => uid_map.add(option_value(arg, rest.next())?)?,
+                    "--gid-map" => gid_map.add(option_value(arg, rest.next())?)?,
+                    _ if target.is_none() && !arg.starts_with("--") => target = Some(arg.clone()),
+                    _ => return Err(anyhow!("Unexpected argument {}\n\n{}", arg, USAGE)),
+                }
+            }
+
+            Command::Restore(restore::Options {
+                target: target
+                    .ok_or_else(|| anyhow!("restore requires a target directory\n\n{}", USAGE))?,
+                path,
+                selection,
+                uid_map,
+                gid_map,
+            })
+        }
+        Some("list-versions") => {
+            let mut path = None;
+            let mut children = false;
+            let mut selection = Selection::Latest;
+
+            let mut rest = args[1..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--children" => children = true,
+                    "--at" | "--version" => parse_selection(arg, rest.next(), &mut selection)?,
+                    _ if path.is_none() && !arg.starts_with("--") => {
+                        path = Some(normalize_path(arg))
+                    }
+                    _ => return Err(anyhow!("Unexpected argument {}\n\n{}", arg, USAGE)),
+                }
+            }
+
+            if !children && !matches!(selection, Selection::Latest) {
+                return Err(anyhow!("--at and --version require --children"));
+            }
+
+            Command::ListVersions(list::Options {
+                path: path.ok_or_else(|| anyhow!("list-versions requires a path\n\n{}", USAGE))?,
+                children,
+                selection,
+            })
+        }
+        Some("verify") => {
+            let mut path = "/".to_string();
+            let mut sample = 100.0;
+
+            let mut rest = args[1..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--path" => path = normalize_path(option_value(arg, rest.next())?),
+                    "--sample" => {
+                        let value = option_value(arg, rest.next())?;
+                        sample = value
+                            .parse()
+                            .ok()
+                            .filter(|sample| *sample > 0.0 && *sample <= 100.0)
+                            .ok_or_else(|| {
+                                anyhow!("--sample expects a percentage in (0, 100], got {}", value)
+                            })?;
+                    }
+                    _ => return Err(anyhow!("Unexpected argument {}\n\n{}", arg, USAGE)),
+                }
+            }
+
+            Command::Verify(verify::Options { path, sample })
+        }
+        Some(other) => return Err(anyhow!("Unknown command {}\n\n{}", other, USAGE)),
+    };
+
+    Ok(Invocation {
+        config_path,
+        command,
+    })
+}
+
+fn is_command(arg: &str) -> bool {
+    matches!(
+        arg,
+        "backup" | "help" | "--help" | "-h" | "restore" | "list-versions" | "verify"
+    )
+}
+
+/// Turns a user supplied path into the form used as keys in the index, with a leading slash and
+/// without a trailing one.
+fn normalize_path(raw: &str) -> String {
+    let trimmed = raw.trim_matches('/');
+    "/".to_string() + trimmed
+}
+
+/// Parses `--at <time>` and `--version <n>`, of which at most one may be given.
+fn parse_selection(option: &str, value: Option<&String>, selection: &mut Selection) -> Result<()> {
+    if !matches!(selection, Selection::Latest) {
+        return Err(anyhow!("Only one of --at and --version may be given"));
+    }
+
+    let value = option_value(option, value)?;
+    if option == "--at" {
+        *selection = Selection::At(timestamp::parse(value)?);
+    } else {
+        let idx = value
+            .parse()
+            .map_err(|_| anyhow!("--version expects an integer, got {}", value))?;
+        *selection = Selection::Index(idx);
+    }
+    Ok(())
+}
+
+fn option_value<'a>(option: &str, value: Option<&'a String>) -> Result<&'a str> {
+    value
+        .map(|v| v.as_str())
+        .ok_or_else(|| anyhow!("{} requires a value\n\n{}", option, USAGE))
+}
+
+fn expect_no_more(args: &[String], consumed: usize) -> Result<()> {
+    if args.len() > consumed {
+        return Err(anyhow!(
+            "Unexpected argument {}\n\n{}",
+            args[consumed],
+            USAGE
+        ));
+    }
+    Ok(())
+}
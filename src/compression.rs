@@ -0,0 +1,140 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use futures::stream::{BoxStream, StreamExt};
+use std::collections::HashSet;
+use zstd::stream::raw::{Decoder, InBuffer, Operation, OutBuffer};
+
+use crate::config::Config;
+
+/// Formats that are compressed already and hardly shrink any further.
+const DEFAULT_SKIP_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jpeg", "jpg", "lz4", "lzma",
+    "mkv", "mov", "mp3", "mp4", "odt", "ogg", "png", "rar", "tgz", "webm", "webp", "xlsx", "xz",
+    "zip", "zst",
+];
+
+/// Compresses the content of regular files with zstd. Every block is compressed into its own zstd
+/// frame, so blocks can still be uploaded one at a time, and the concatenated frames of a blob form
+/// a valid zstd stream.
+pub struct Compression {
+    level: i32,
+    skip_extensions: HashSet<String>,
+}
+
+/// Reads the compression settings. Returns None if compression is disabled.
+pub fn from_config(conf: &Config) -> Option<Compression> {
+    if !conf.compression {
+        return None;
+    }
+
+    let skip_extensions = match &conf.compression_skip_extensions {
+        Some(extensions) => extensions.clone(),
+        None => DEFAULT_SKIP_EXTENSIONS
+            .iter()
+            .map(|e| e.to_string())
+            .collect(),
+    };
+
+    Some(Compression {
+        level: conf.compression_level,
+        skip_extensions: skip_extensions
+            .iter()
+            .map(|e| e.trim_start_matches('.').to_lowercase())
+            .collect(),
+    })
+}
+
+impl Compression {
+    /// Whether the file at `path` should be compressed, based on its extension.
+    pub fn applies_to(&self, path: &str) -> bool {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+        match file_name.rsplit_once('.') {
+            Some((_, extension)) => !self.skip_extensions.contains(&extension.to_lowercase()),
+            None => true,
+        }
+    }
+
+    pub fn compress_block(&self, block: &[u8]) -> Result<Vec<u8>> {
+        Ok(zstd::bulk::compress(block, self.level)?)
+    }
+}
+
+/// Decompresses a blob written with `compress_block`, regardless of how it is split into chunks.
+pub fn decompress_stream(
+    chunks: BoxStream<'static, Result<Vec<u8>>>,
+) -> BoxStream<'static, Result<Vec<u8>>> {
+    let state = Decompressor {
+        chunks,
+        decoder: Decoder::new().expect("zstd contexts can be created"),
+        frame_complete: true,
+    };
+
+    futures::stream::try_unfold(state, |mut state| async move {
+        match state.next_chunk().await? {
+            Some(chunk) => Ok(Some((chunk, state))),
+            None => Ok(None),
+        }
+    })
+    .boxed()
+}
+
+struct Decompressor {
+    chunks: BoxStream<'static, Result<Vec<u8>>>,
+    decoder: Decoder<'static>,
+    /// Whether the input so far ended with a complete frame
+    frame_complete: bool,
+}
+
+impl Decompressor {
+    async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        while let Some(chunk) = self.chunks.next().await {
+            let output = self.decompress(&chunk?)?;
+            if !output.is_empty() {
+                return Ok(Some(output));
+            }
+        }
+
+        if !self.frame_complete {
+            return Err(anyhow!("The compressed blob is truncated"));
+        }
+        Ok(None)
+    }
+
+    fn decompress(&mut self, input: &[u8]) -> Result<Vec<u8>> {
+        let mut output = Vec::new();
+        let mut buffer = vec![0; zstd::zstd_safe::DCtx::out_size()];
+        let mut input = InBuffer::around(input);
+
+        loop {
+            let mut out = OutBuffer::around(&mut buffer[..]);
+            let consumed = input.pos();
+            let hint = self.decoder.run(&mut input, &mut out)?;
+            let written = out.pos();
+            output.extend_from_slice(&buffer[..written]);
+            // Without any progress the hint is about the next frame, which may never come
+            if input.pos() > consumed || written > 0 {
+                self.frame_complete = hint == 0;
+            }
+
+            // A full output buffer means there might be more output pending for the same input
+            if input.pos() == input.src.len() && written < buffer.len() {
+                return Ok(output);
+            }
+        }
+    }
+}
@@ -14,35 +14,279 @@
     You should have received a copy of the GNU General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-use anyhow::anyhow;
-use anyhow::Context;
-use anyhow::Result;
-use yaml_rust;
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
 
-pub struct Config(yaml_rust::Yaml);
+use crate::consistency::Policy;
+use crate::storage::Backend;
+use crate::throttle::Window;
+
+/// The settings read from the config file, see config.template.yaml for their meaning. Keys that
+/// are not set get their defaults, unknown keys are rejected.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub local_root: String,
+
+    #[serde(default)]
+    pub backend: Backend,
+    pub sas_url: Option<String>,
+    pub storage_path: Option<String>,
+    pub s3_bucket: Option<String>,
+    #[serde(default = "default_s3_region")]
+    pub s3_region: String,
+    pub s3_endpoint: Option<String>,
+    pub s3_access_key: Option<String>,
+    pub s3_secret_key: Option<String>,
+
+    #[serde(default)]
+    pub content_hash: bool,
+    #[serde(default)]
+    pub compression: bool,
+    #[serde(default = "default_compression_level")]
+    pub compression_level: i32,
+    /// Defaults to common archive, image, audio and video formats, see compression.rs
+    pub compression_skip_extensions: Option<Vec<String>>,
+    #[serde(default)]
+    pub deduplication: bool,
+    #[serde(default = "default_deduplication_min_size")]
+    pub deduplication_min_size: u64,
+    #[serde(default)]
+    pub block_incremental: bool,
+
+    #[serde(default = "default_upload_concurrency")]
+    pub upload_concurrency: usize,
+    #[serde(default = "default_block_upload_concurrency")]
+    pub block_upload_concurrency: usize,
+    /// KiB per second, 0 for no limit
+    #[serde(default)]
+    pub upload_limit_kib: u64,
+    #[serde(default)]
+    pub upload_limit_schedule: Vec<Window>,
+    #[serde(default)]
+    pub modified_during_upload: Policy,
+
+    #[serde(default = "default_retry_attempts")]
+    pub retry_attempts: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    #[serde(default = "default_retry_max_backoff_ms")]
+    pub retry_max_backoff_ms: u64,
+    #[serde(default = "default_retry_jitter_percent")]
+    pub retry_jitter_percent: u32,
+
+    pub encryption_key: Option<String>,
+    pub encryption_key_file: Option<String>,
+    #[serde(default)]
+    pub encrypt_names: bool,
+
+    #[serde(default = "default_num_daily")]
+    pub num_daily: u64,
+    #[serde(default = "default_num_weekly")]
+    pub num_weekly: u64,
+    #[serde(default = "default_num_monthly")]
+    pub num_monthly: u64,
+    /// In seconds
+    #[serde(default = "default_min_update_age")]
+    pub min_update_age: u64,
+
+    /// The line every key was set on, for error messages
+    #[serde(skip)]
+    lines: HashMap<String, usize>,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_deduplication_min_size() -> u64 {
+    8 << 20
+}
+
+fn default_upload_concurrency() -> usize {
+    8
+}
+
+fn default_block_upload_concurrency() -> usize {
+    4
+}
+
+fn default_retry_attempts() -> u32 {
+    5
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    500
+}
+
+fn default_retry_max_backoff_ms() -> u64 {
+    30_000
+}
+
+fn default_retry_jitter_percent() -> u32 {
+    50
+}
+
+fn default_num_daily() -> u64 {
+    7
+}
+
+fn default_num_weekly() -> u64 {
+    4
+}
+
+fn default_num_monthly() -> u64 {
+    12
+}
+
+fn default_min_update_age() -> u64 {
+    23 * 60 * 60
+}
 
 pub fn load(path: &str) -> Result<Config> {
     let raw = std::fs::read_to_string(path).with_context(|| "unable to read the config file")?;
 
-    let docs = yaml_rust::YamlLoader::load_from_str(&raw)?;
+    parse(&raw)
+}
+
+/// Parses and validates a config.
+pub fn parse(raw: &str) -> Result<Config> {
+    if serde_yaml::from_str::<serde_yaml::Value>(raw)?.is_null() {
+        return Err(anyhow!("The config is empty"));
+    }
 
-    Ok(Config(docs[0].clone()))
+    let mut config: Config =
+        serde_yaml::from_str(raw).map_err(|e| anyhow!("Malformed config: {}", e))?;
+    config.lines = key_lines(raw);
+    config.validate()?;
+    Ok(config)
+}
+
+/// The line every top level key is set on. Configs are written in block style, where top level
+/// keys start at the beginning of their line.
+fn key_lines(raw: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    for (number, line) in raw.lines().enumerate() {
+        if line.starts_with(|c: char| c.is_whitespace() || c == '#' || c == '-') {
+            continue;
+        }
+        if let Some((key, _)) = line.split_once(':') {
+            lines.entry(key.trim().to_string()).or_insert(number + 1);
+        }
+    }
+    lines
 }
 
 impl Config {
-    pub fn get_string(&self, name: &str) -> anyhow::Result<String> {
-        let maybe_str = self.0[name].as_str();
-        match maybe_str {
-            Some(str) => Ok(str.to_string()),
-            None => Err(anyhow!("No such config key: {}", name)),
+    /// Checks the settings that can't be told apart by their type alone.
+    fn validate(&self) -> Result<()> {
+        if !self.local_root.starts_with('/') {
+            return Err(self.malformed(
+                "local_root",
+                format!("has to be an absolute path, but is {}", self.local_root),
+            ));
+        }
+        let (required, value) = match self.backend {
+            Backend::Azure => ("sas_url", &self.sas_url),
+            Backend::Local => ("storage_path", &self.storage_path),
+            Backend::S3 => ("s3_bucket", &self.s3_bucket),
+        };
+        if value.is_none() {
+            return Err(self.malformed("backend", format!("requires {} to be set", required)));
+        }
+        if let Some(storage_path) = &self.storage_path {
+            if !storage_path.starts_with('/') {
+                return Err(self.malformed(
+                    "storage_path",
+                    format!("has to be an absolute path, but is {}", storage_path),
+                ));
+            }
         }
+
+        if self.num_daily > 7 {
+            return Err(self.malformed(
+                "num_daily",
+                format!(
+                    "has to be in the interval of [0;7], but is {}",
+                    self.num_daily
+                ),
+            ));
+        }
+        if self.num_weekly > 4 {
+            return Err(self.malformed(
+                "num_weekly",
+                format!(
+                    "has to be in the interval of [0;4], but is {}",
+                    self.num_weekly
+                ),
+            ));
+        }
+        if self.num_daily == 0 && self.num_weekly == 0 && self.num_monthly == 0 {
+            return Err(anyhow!(
+                "Malformed config: requested for no backups to be kept."
+            ));
+        }
+
+        let levels = zstd::compression_level_range();
+        if !levels.contains(&self.compression_level) {
+            return Err(self.malformed(
+                "compression_level",
+                format!(
+                    "has to be in the interval of [{};{}], but is {}",
+                    levels.start(),
+                    levels.end(),
+                    self.compression_level
+                ),
+            ));
+        }
+        if self.deduplication && self.block_incremental {
+            return Err(anyhow!(
+                "Malformed config: deduplication and block_incremental can't be combined"
+            ));
+        }
+
+        for (key, value) in [
+            ("upload_concurrency", self.upload_concurrency),
+            ("block_upload_concurrency", self.block_upload_concurrency),
+            ("retry_attempts", self.retry_attempts as usize),
+        ] {
+            if value < 1 {
+                return Err(self.malformed(key, format!("has to be at least 1, but is {}", value)));
+            }
+        }
+        if self.retry_jitter_percent > 100 {
+            return Err(self.malformed(
+                "retry_jitter_percent",
+                format!(
+                    "has to be in the interval of [0;100], but is {}",
+                    self.retry_jitter_percent
+                ),
+            ));
+        }
+
+        if self.encryption_key.is_some() && self.encryption_key_file.is_some() {
+            return Err(anyhow!(
+                "Malformed config: only one of encryption_key and encryption_key_file may be set"
+            ));
+        }
+        if self.encrypt_names && self.encryption_key.is_none() && self.encryption_key_file.is_none()
+        {
+            return Err(self.malformed("encrypt_names", "requires an encryption key".to_string()));
+        }
+
+        Ok(())
     }
 
-    pub fn get_i64(&self, name: &str) -> anyhow::Result<i64> {
-        let maybe_val = self.0[name].as_i64();
-        match maybe_val {
-            Some(val) => Ok(val),
-            None => Err(anyhow!("No such config key: {}", name)),
+    /// An error about the value of `key`, pointing to the line it was set on.
+    fn malformed(&self, key: &str, message: String) -> anyhow::Error {
+        match self.lines.get(key) {
+            Some(line) => anyhow!("Malformed config: {} on line {} {}", key, line, message),
+            None => anyhow!("Malformed config: {} {}", key, message),
         }
     }
 }
@@ -0,0 +1,122 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::Result;
+use serde::Deserialize;
+use std::{collections::HashSet, os::unix::prelude::MetadataExt, path::Path};
+
+use crate::index::{blob_name, FileType, Index, Version};
+use crate::storage::Storage;
+
+// Files are indexed before they are uploaded, so a file written to in between is stored with
+// content that doesn't match the metadata in its blob name. After every upload the file is checked
+// again, and a modified file is handled according to the configured policy. Versions kept despite
+// being modified are marked by an empty blob below MODIFIED_PATH, so restores can warn about them.
+
+const MODIFIED_PATH: &str = "/.azure_blob_backup/modified";
+
+/// How often a file is uploaded in total with the retry policy before it is skipped.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// What happens to a file that was modified while it was uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Upload it again with its new metadata, and skip it if it keeps changing
+    #[default]
+    Retry,
+    /// Delete the uploaded version and report the file as failed, it is tried again on the next run
+    Skip,
+    /// Keep the uploaded version and mark it as modified
+    Keep,
+}
+
+/// The name of the marker of a `version` of the file at `path` that was modified during its upload.
+pub fn marker_name(path: &str, version: &Version) -> String {
+    MODIFIED_PATH.to_string() + &blob_name(path, version)
+}
+
+/// How the local file at `local_path` no longer matches the `version` it was uploaded as, or None
+/// if it still does. `checksum` is the hash of the uploaded content, if it was hashed, which is
+/// compared with the content hash of the version, if it has one. `uploaded_size` is the number of
+/// bytes uploaded, if they were counted, which is compared with the size of the version.
+pub fn modification(
+    local_path: &Path,
+    version: &Version,
+    checksum: Option<&str>,
+    uploaded_size: Option<u64>,
+) -> Option<String> {
+    if let Some(uploaded_size) = uploaded_size.filter(|size| *size != version.size) {
+        return Some(format!(
+            "{} of its {} bytes were uploaded",
+            uploaded_size, version.size
+        ));
+    }
+
+    let metadata = match std::fs::symlink_metadata(local_path) {
+        Ok(metadata) => metadata,
+        Err(e) => return Some(format!("it can no longer be read: {}", e)),
+    };
+
+    if metadata.size() != version.size {
+        return Some(format!(
+            "its size changed from {} to {} bytes",
+            version.size,
+            metadata.size()
+        ));
+    }
+    if metadata.mtime() as u64 != version.mod_time {
+        return Some("its modification time changed".to_string());
+    }
+    match (checksum, &version.content_hash) {
+        (Some(checksum), Some(hash)) if checksum != hash => Some("its content changed".to_string()),
+        _ => None,
+    }
+}
+
+/// Whether the content of files of this type is uploaded and can be modified during the upload.
+pub fn applies_to(version: &Version) -> bool {
+    matches!(version.file_type, FileType::Regular | FileType::Symlink)
+}
+
+/// The blob names of all versions that were kept despite being modified during their upload.
+pub async fn load_markers(storage: &dyn Storage) -> Result<HashSet<String>> {
+    Ok(storage
+        .list(&(MODIFIED_PATH.to_string() + "/"))
+        .await?
+        .into_iter()
+        .map(|name| name[MODIFIED_PATH.len()..].to_string())
+        .collect())
+}
+
+/// Deletes the markers of versions that are no longer in `remote`.
+pub async fn collect_garbage(storage: &dyn Storage, remote: &Index) -> Result<()> {
+    let versions: HashSet<String> = remote
+        .files
+        .iter()
+        .flat_map(|(path, versions)| versions.iter().map(|version| blob_name(path, version)))
+        .collect();
+
+    for version in load_markers(storage).await? {
+        if !versions.contains(&version) {
+            storage
+                .delete(&(MODIFIED_PATH.to_string() + &version))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,95 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::Result;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{io::Read, path::Path};
+
+use crate::config::Config;
+use crate::crypto::MasterKey;
+
+/// The size of the chunks files are hashed in.
+const READ_CHUNK_SIZE: usize = 1 << 20;
+
+/// Hashes the content of files. The hashes are stored with every uploaded blob, so it can be
+/// verified later, and optionally used to detect changes even if the size and modification time stay
+/// the same.
+pub struct ContentHasher {
+    /// With encryption the hash is keyed, so the hashes don't reveal whether the backup contains a
+    /// known file
+    key: Option<[u8; 32]>,
+}
+
+/// Reads the content hash settings. Returns None if files are only compared by their metadata.
+pub fn from_config(conf: &Config, encryption: Option<&MasterKey>) -> Option<ContentHasher> {
+    conf.content_hash.then(|| ContentHasher::new(encryption))
+}
+
+impl ContentHasher {
+    pub fn new(encryption: Option<&MasterKey>) -> ContentHasher {
+        ContentHasher {
+            key: encryption.map(|key| key.derive("content hashes")),
+        }
+    }
+
+    /// Starts hashing content that is passed in pieces.
+    pub fn hasher(&self) -> Hasher {
+        match &self.key {
+            Some(key) => Hasher::Keyed(
+                <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any size"),
+            ),
+            None => Hasher::Plain(Sha256::new()),
+        }
+    }
+
+    /// The hash of the content of the file at `path`.
+    pub fn hash_file(&self, path: &Path) -> Result<String> {
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = self.hasher();
+        let mut buffer = vec![0; READ_CHUNK_SIZE];
+        loop {
+            let num_read = file.read(&mut buffer)?;
+            if num_read == 0 {
+                return Ok(hasher.finalize());
+            }
+            hasher.update(&buffer[..num_read]);
+        }
+    }
+}
+
+/// The SHA-256 of some content, or its HMAC with encryption.
+pub enum Hasher {
+    Plain(Sha256),
+    Keyed(Hmac<Sha256>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Plain(hasher) => Digest::update(hasher, data),
+            Hasher::Keyed(mac) => mac.update(data),
+        }
+    }
+
+    /// The hash in hex.
+    pub fn finalize(self) -> String {
+        match self {
+            Hasher::Plain(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Keyed(mac) => hex::encode(mac.finalize().into_bytes()),
+        }
+    }
+}
@@ -0,0 +1,278 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::config::Config;
+
+// Encrypted blobs consist of a header followed by frames:
+//
+//   header: MAGIC | nonce (12 bytes) | data key encrypted with the master key (32 + 16 bytes)
+//   frame:  length of the ciphertext (u32, little endian) | ciphertext of one block (n + 16 bytes)
+//
+// Every file is encrypted with its own random data key, so frames can use their index as the
+// nonce. The last frame uses a different nonce, which makes truncated blobs fail to decrypt. The
+// blob name is authenticated with every frame, so the content of one blob can't be passed off as
+// that of another path or version.
+
+const MAGIC: &[u8] = b"ABB\x01";
+const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const HEADER_SIZE: usize = MAGIC.len() + NONCE_SIZE + 32 + TAG_SIZE;
+const FRAME_LEN_SIZE: usize = 4;
+
+/// The key configured by the user. It only encrypts the per file data keys.
+pub struct MasterKey {
+    key: Key,
+    cipher: ChaCha20Poly1305,
+}
+
+/// Reads the master key from `encryption_key` or `encryption_key_file`. Returns None if
+/// encryption is not configured.
+pub fn from_config(conf: &Config) -> Result<Option<MasterKey>> {
+    // Config::validate ensures only one of them is set
+    let hex_key = match (&conf.encryption_key, &conf.encryption_key_file) {
+        (Some(key), _) => key.clone(),
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read the encryption key file {}", path))?,
+        (None, None) => return Ok(None),
+    };
+
+    MasterKey::from_hex(hex_key.trim()).map(Some)
+}
+
+impl MasterKey {
+    /// Parses a key given as 64 hex characters, e.g. generated by `openssl rand -hex 32`.
+    pub fn from_hex(raw: &str) -> Result<MasterKey> {
+        let bytes = hex::decode(raw)
+            .ok()
+            .filter(|bytes| bytes.len() == 32)
+            .ok_or_else(|| {
+                anyhow!("Malformed config: the encryption key has to be 64 hex characters")
+            })?;
+
+        let key = *Key::from_slice(&bytes);
+        Ok(MasterKey {
+            key,
+            cipher: ChaCha20Poly1305::new(&key),
+        })
+    }
+
+    /// Derives a key for `purpose` from the master key, e.g. for encrypting blob names.
+    pub fn derive(&self, purpose: &str) -> [u8; 32] {
+        let mut mac =
+            <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(purpose.as_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Starts encrypting a new file, stored as the blob `name`, with a fresh data key.
+    pub fn encryptor(&self, name: &str) -> Result<Encryptor> {
+        let data_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let wrapped = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &data_key,
+                    aad: MAGIC,
+                },
+            )
+            .map_err(|_| anyhow!("Unable to encrypt the data key"))?;
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&nonce);
+        header.extend_from_slice(&wrapped);
+
+        Ok(Encryptor {
+            cipher: ChaCha20Poly1305::new(&data_key),
+            header: Some(header),
+            name: name.to_string(),
+            index: 0,
+        })
+    }
+
+    /// Decrypts the blob `name` written by an `Encryptor`, yielding the plaintext of one block at a
+    /// time.
+    pub fn decrypt_stream(
+        &self,
+        name: &str,
+        chunks: BoxStream<'static, Result<Vec<u8>>>,
+    ) -> BoxStream<'static, Result<Vec<u8>>> {
+        let state = Decryptor {
+            master: self.cipher.clone(),
+            name: name.to_string(),
+            chunks,
+            buffer: Vec::new(),
+            cipher: None,
+            index: 0,
+            finished: false,
+        };
+
+        futures::stream::try_unfold(state, |mut state| async move {
+            match state.next_block().await? {
+                Some(block) => Ok(Some((block, state))),
+                None => Ok(None),
+            }
+        })
+        .boxed()
+    }
+
+    /// Reads and decrypts a whole blob into memory.
+    pub async fn decrypt_all(
+        &self,
+        name: &str,
+        chunks: BoxStream<'static, Result<Vec<u8>>>,
+    ) -> Result<Vec<u8>> {
+        let blocks: Vec<Vec<u8>> = self.decrypt_stream(name, chunks).try_collect().await?;
+        Ok(blocks.concat())
+    }
+}
+
+/// Encrypts the blocks of one file. The first block carries the header.
+pub struct Encryptor {
+    cipher: ChaCha20Poly1305,
+    header: Option<Vec<u8>>,
+    name: String,
+    index: u64,
+}
+
+impl Encryptor {
+    /// Encrypts the next block of the file. The last block has to be marked, files without
+    /// content consist of a single empty last block.
+    pub fn encrypt_block(&mut self, plaintext: &[u8], last: bool) -> Result<Vec<u8>> {
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &frame_nonce(self.index, last),
+                Payload {
+                    msg: plaintext,
+                    aad: self.name.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("Unable to encrypt block {}", self.index))?;
+        self.index += 1;
+
+        let mut frame = self.header.take().unwrap_or_default();
+        frame.extend_from_slice(&(ciphertext.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&ciphertext);
+        Ok(frame)
+    }
+}
+
+fn frame_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[..8].copy_from_slice(&index.to_le_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    *Nonce::from_slice(&nonce)
+}
+
+struct Decryptor {
+    master: ChaCha20Poly1305,
+    name: String,
+    chunks: BoxStream<'static, Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+    cipher: Option<ChaCha20Poly1305>,
+    index: u64,
+    finished: bool,
+}
+
+impl Decryptor {
+    async fn next_block(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.finished {
+            if self.fill(1).await? {
+                return Err(anyhow!(
+                    "Found data after the last block of an encrypted blob"
+                ));
+            }
+            return Ok(None);
+        }
+
+        if self.cipher.is_none() {
+            if !self.fill(HEADER_SIZE).await? || !self.buffer.starts_with(MAGIC) {
+                return Err(anyhow!(
+                    "The blob is not encrypted or its header is damaged"
+                ));
+            }
+            let header: Vec<u8> = self.buffer.drain(..HEADER_SIZE).collect();
+            let nonce = Nonce::from_slice(&header[MAGIC.len()..MAGIC.len() + NONCE_SIZE]);
+            let payload = Payload {
+                msg: &header[MAGIC.len() + NONCE_SIZE..],
+                aad: MAGIC,
+            };
+            let data_key = self.master.decrypt(nonce, payload).map_err(|_| {
+                anyhow!("Unable to decrypt the blob, it was encrypted with a different key")
+            })?;
+            self.cipher = Some(ChaCha20Poly1305::new(Key::from_slice(&data_key)));
+        }
+
+        if !self.fill(FRAME_LEN_SIZE).await? {
+            return Err(anyhow!("The encrypted blob is truncated"));
+        }
+        let len = u32::from_le_bytes(self.buffer[..FRAME_LEN_SIZE].try_into()?) as usize;
+        if !self.fill(FRAME_LEN_SIZE + len).await? {
+            return Err(anyhow!("The encrypted blob is truncated"));
+        }
+        let frame: Vec<u8> = self.buffer.drain(..FRAME_LEN_SIZE + len).collect();
+        let ciphertext = &frame[FRAME_LEN_SIZE..];
+
+        // Without knowing the length of the blob, try whether this is the last frame
+        let cipher = self.cipher.as_ref().unwrap();
+        let payload = || Payload {
+            msg: ciphertext,
+            aad: self.name.as_bytes(),
+        };
+        let plaintext = match cipher.decrypt(&frame_nonce(self.index, false), payload()) {
+            Ok(plaintext) => plaintext,
+            Err(_) => {
+                let plaintext = cipher
+                    .decrypt(&frame_nonce(self.index, true), payload())
+                    .map_err(|_| {
+                        anyhow!(
+                            "Block {} of {} is damaged or belongs to another blob",
+                            self.index,
+                            self.name
+                        )
+                    })?;
+                self.finished = true;
+                plaintext
+            }
+        };
+        self.index += 1;
+
+        Ok(Some(plaintext))
+    }
+
+    /// Reads chunks until the buffer holds at least `len` bytes. Returns false if the blob ended
+    /// before that.
+    async fn fill(&mut self, len: usize) -> Result<bool> {
+        while self.buffer.len() < len {
+            match self.chunks.next().await {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk?),
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,283 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use fastcdc::v2020::StreamCDC;
+use futures::stream::TryStreamExt;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use crate::compression::{self, Compression};
+use crate::config::Config;
+use crate::content_hash::Hasher;
+use crate::crypto::MasterKey;
+use crate::index::{blob_name, FileType, Index, Version};
+use crate::storage::Storage;
+use crate::throttle::Throttle;
+
+// Deduplicated files are split into chunks at content defined boundaries, so inserting or removing
+// data only changes the chunks around the modification. Chunks are stored once, named after a hash
+// of their content, and the blob of a deduplicated version is a manifest listing the names of its
+// chunks, one per line.
+
+/// Internal data of the backup lives below this path. A file at this path is not backed up.
+pub const RESERVED_PATH: &str = "/.azure_blob_backup";
+const CHUNK_PATH: &str = "/.azure_blob_backup/chunks";
+
+const MIN_CHUNK_SIZE: u32 = 256 << 10;
+const AVG_CHUNK_SIZE: u32 = 1 << 20;
+const MAX_CHUNK_SIZE: u32 = 4 << 20;
+
+/// Whether `path` belongs to the internal data of the backup.
+pub fn is_reserved(path: &str) -> bool {
+    path == RESERVED_PATH || path.starts_with(&(RESERVED_PATH.to_string() + "/"))
+}
+
+/// Reads the deduplication settings. Returns the size from which on files are deduplicated, or
+/// None if deduplication is disabled.
+pub fn from_config(conf: &Config) -> Option<u64> {
+    conf.deduplication.then_some(conf.deduplication_min_size)
+}
+
+/// Uploads the chunks of deduplicated files, skipping the ones that are stored already.
+pub struct ChunkStore<'a> {
+    storage: &'a dyn Storage,
+    encryption: Option<&'a MasterKey>,
+    min_size: u64,
+    /// The names of the stored chunks by their id
+    chunks: Mutex<HashMap<String, String>>,
+}
+
+impl<'a> ChunkStore<'a> {
+    /// Lists the chunks in `storage`. Files of at least `min_size` bytes are deduplicated.
+    pub async fn load(
+        storage: &'a dyn Storage,
+        encryption: Option<&'a MasterKey>,
+        min_size: u64,
+    ) -> Result<ChunkStore<'a>> {
+        let chunks = list_chunks(storage).await?;
+        log::info!("Found {} stored chunks", chunks.len());
+
+        Ok(ChunkStore {
+            storage,
+            encryption,
+            min_size,
+            chunks: Mutex::new(chunks),
+        })
+    }
+
+    /// Whether `version` is stored as a manifest of chunks.
+    pub fn applies_to(&self, version: &Version) -> bool {
+        version.file_type == FileType::Regular && version.size >= self.min_size
+    }
+
+    /// Splits `file` into chunks, uploads the ones that are not stored yet and returns the manifest
+    /// listing all chunks in order. Uploads are limited by `throttle`. The content is hashed into
+    /// `checksum` on the way.
+    pub async fn upload(
+        &self,
+        file: std::fs::File,
+        compression: Option<&Compression>,
+        throttle: Option<&Throttle>,
+        checksum: &mut Hasher,
+    ) -> Result<Vec<u8>> {
+        let mut manifest = String::new();
+
+        for chunk in StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+            let chunk = chunk?;
+            checksum.update(&chunk.data);
+            let id = chunk_id(&chunk.data, self.encryption);
+
+            let stored = self.chunks.lock().unwrap().get(&id).cloned();
+            let name = match stored {
+                Some(name) => name,
+                None => {
+                    let name = self.store(&id, chunk.data, compression, throttle).await?;
+                    self.chunks.lock().unwrap().insert(id, name.clone());
+                    name
+                }
+            };
+
+            manifest += &name;
+            manifest.push('\n');
+        }
+
+        Ok(manifest.into_bytes())
+    }
+
+    /// Uploads a chunk. Like versions, the name of a chunk carries flags for how it is encoded.
+    async fn store(
+        &self,
+        id: &str,
+        mut data: Vec<u8>,
+        compression: Option<&Compression>,
+        throttle: Option<&Throttle>,
+    ) -> Result<String> {
+        let mut name = format!("{}/{}", CHUNK_PATH, id);
+
+        if let Some(compression) = compression {
+            data = compression.compress_block(&data)?;
+            name += "-zstd";
+        }
+        if let Some(key) = self.encryption {
+            name += "-enc";
+            data = key.encryptor(&name)?.encrypt_block(&data, true)?;
+        }
+
+        if let Some(throttle) = throttle {
+            throttle.acquire(data.len() as u64).await;
+        }
+        self.storage.put(&name, data.into()).await?;
+        Ok(name)
+    }
+}
+
+/// Chunks are named after the hash of their content. With encryption the hash is keyed, so the
+/// names don't reveal whether the backup contains a known file.
+fn chunk_id(data: &[u8], encryption: Option<&MasterKey>) -> String {
+    match encryption {
+        Some(key) => {
+            let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key.derive("chunk ids"))
+                .expect("HMAC takes keys of any size");
+            mac.update(data);
+            hex::encode(mac.finalize().into_bytes())
+        }
+        None => hex::encode(Sha256::digest(data)),
+    }
+}
+
+/// Lists the stored chunks, by their id.
+pub async fn list_chunks(storage: &dyn Storage) -> Result<HashMap<String, String>> {
+    let mut chunks = HashMap::new();
+    for name in storage.list(&(CHUNK_PATH.to_string() + "/")).await? {
+        let id = chunk_file_name(&name)?
+            .split('-')
+            .next()
+            .unwrap_or_default();
+        chunks.insert(id.to_string(), name);
+    }
+    Ok(chunks)
+}
+
+fn chunk_file_name(name: &str) -> Result<&str> {
+    name.strip_prefix(CHUNK_PATH)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|rest| !rest.is_empty() && !rest.contains('/'))
+        .ok_or_else(|| anyhow!("Malformed chunk name {}", name))
+}
+
+/// Reads the names of the chunks making up a deduplicated version.
+pub async fn read_manifest(
+    storage: &dyn Storage,
+    path: &str,
+    version: &Version,
+    encryption: Option<&MasterKey>,
+) -> Result<Vec<String>> {
+    let name = blob_name(path, version);
+    let chunks = storage.get(&name).await?;
+    let manifest = match (version.encrypted, encryption) {
+        (false, _) => chunks.try_collect::<Vec<Vec<u8>>>().await?.concat(),
+        (true, Some(key)) => key.decrypt_all(&name, chunks).await?,
+        (true, None) => {
+            return Err(anyhow!(
+                "{} is encrypted, but no encryption key is configured",
+                path
+            ))
+        }
+    };
+
+    let manifest = String::from_utf8(manifest)?;
+    manifest
+        .lines()
+        .map(|name| chunk_file_name(name).map(|_| name.to_string()))
+        .collect()
+}
+
+/// Reads and decodes the content of the chunk `name`.
+pub async fn read_chunk(
+    storage: &dyn Storage,
+    name: &str,
+    encryption: Option<&MasterKey>,
+) -> Result<Vec<u8>> {
+    let flags: Vec<&str> = chunk_file_name(name)?.split('-').skip(1).collect();
+
+    let mut chunks = storage.get(name).await?;
+    if flags.contains(&"enc") {
+        let key = encryption.ok_or_else(|| {
+            anyhow!(
+                "The chunk {} is encrypted, but no encryption key is configured",
+                name
+            )
+        })?;
+        chunks = key.decrypt_stream(name, chunks);
+    }
+    if flags.contains(&"zstd") {
+        chunks = compression::decompress_stream(chunks);
+    }
+
+    let content: Vec<Vec<u8>> = chunks.try_collect().await?;
+    Ok(content.concat())
+}
+
+/// Checks that the `content` read from the chunk `name` still hashes to the id it is named after.
+pub fn check_chunk(name: &str, content: &[u8], encryption: Option<&MasterKey>) -> Result<()> {
+    let mut parts = chunk_file_name(name)?.split('-');
+    let id = parts.next().unwrap_or_default();
+    // Only chunks uploaded with encryption have keyed ids
+    let key = encryption.filter(|_| parts.any(|flag| flag == "enc"));
+
+    let actual = chunk_id(content, key);
+    if actual != id {
+        return Err(anyhow!(
+            "the content of the chunk {} hashes to {}",
+            name,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Deletes all chunks that are not referenced by any version in `remote`.
+pub async fn collect_garbage(
+    storage: &dyn Storage,
+    encryption: Option<&MasterKey>,
+    remote: &Index,
+) -> Result<()> {
+    log::info!("Finding unreferenced chunks");
+
+    let mut referenced = HashSet::new();
+    for (path, versions) in &remote.files {
+        for version in versions.iter().filter(|version| version.deduplicated) {
+            referenced.extend(read_manifest(storage, path, version, encryption).await?);
+        }
+    }
+
+    let mut deleted: usize = 0;
+    // Listed by name, as the same id may be stored with different flags
+    for name in storage.list(&(CHUNK_PATH.to_string() + "/")).await? {
+        if !referenced.contains(&name) {
+            storage.delete(&name).await?;
+            deleted += 1;
+        }
+    }
+    log::info!("Deleted {} unreferenced chunks", deleted);
+
+    Ok(())
+}
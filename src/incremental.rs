@@ -0,0 +1,146 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+
+use crate::index::{blob_name, Index, Version};
+use crate::storage::Storage;
+
+// Regular files are uploaded in blocks at fixed offsets. With block_incremental, the hashes of the
+// blocks of a version are stored in a block map next to it, and the next version of the file copies
+// the blocks that didn't change from the blob of the previous version within the storage, instead
+// of uploading them again.
+
+const BLOCK_MAP_PATH: &str = "/.azure_blob_backup/blocks";
+
+/// The name of the blob storing the block map of `version` of the file at `path`.
+pub fn block_map_name(path: &str, version: &Version) -> String {
+    BLOCK_MAP_PATH.to_string() + &blob_name(path, version)
+}
+
+/// The hash blocks are compared by.
+pub fn hash_block(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// The blocks a version was uploaded in, with their position in the stored blob. Compressed blocks
+/// are shorter in the blob than in the file.
+pub struct BlockMap {
+    /// The size of the blocks in the file, only maps with the same block size can be compared
+    pub block_size: u64,
+    /// The stored offset and length of the blocks by their hash
+    blocks: HashMap<String, (u64, u64)>,
+    /// The block hashes in order, with their stored lengths
+    order: Vec<(String, u64)>,
+    stored_size: u64,
+}
+
+impl BlockMap {
+    pub fn new(block_size: u64) -> BlockMap {
+        BlockMap {
+            block_size,
+            blocks: HashMap::new(),
+            order: Vec::new(),
+            stored_size: 0,
+        }
+    }
+
+    /// Appends a block with the given hash that takes `length` bytes in the stored blob.
+    pub fn push(&mut self, hash: String, length: u64) {
+        self.blocks
+            .entry(hash.clone())
+            .or_insert((self.stored_size, length));
+        self.order.push((hash, length));
+        self.stored_size += length;
+    }
+
+    /// The offset and length of the block with the hash in the stored blob.
+    pub fn find(&self, hash: &str) -> Option<(u64, u64)> {
+        self.blocks.get(hash).copied()
+    }
+
+    /// One line with the block size, then one line per block with its hash and stored length.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut serialized = format!("{}\n", self.block_size);
+        for (hash, length) in &self.order {
+            serialized += &format!("{} {}\n", hash, length);
+        }
+        serialized.into_bytes()
+    }
+
+    pub fn parse(raw: &[u8]) -> Result<BlockMap> {
+        let raw = std::str::from_utf8(raw)?;
+        let mut lines = raw.lines();
+
+        let block_size = lines
+            .next()
+            .ok_or_else(|| anyhow!("Malformed block map: missing block size"))?
+            .parse()?;
+        let mut map = BlockMap::new(block_size);
+        for line in lines {
+            let (hash, length) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("Malformed block map line {}", line))?;
+            map.push(hash.to_string(), length.parse()?);
+        }
+        Ok(map)
+    }
+
+    /// Loads the block map of `version`. Returns None if the version was uploaded without one,
+    /// i.e. its blob name isn't in `stored`, see `load_names`.
+    pub async fn load(
+        storage: &dyn Storage,
+        stored: &HashSet<String>,
+        path: &str,
+        version: &Version,
+    ) -> Result<Option<BlockMap>> {
+        if !stored.contains(&blob_name(path, version)) {
+            return Ok(None);
+        }
+        let name = block_map_name(path, version);
+        Ok(Some(BlockMap::parse(&storage.get_content(&name).await?)?))
+    }
+}
+
+/// The blob names of all versions that have a block map, listed once per run.
+pub async fn load_names(storage: &dyn Storage) -> Result<HashSet<String>> {
+    Ok(storage
+        .list(&(BLOCK_MAP_PATH.to_string() + "/"))
+        .await?
+        .into_iter()
+        .map(|name| name[BLOCK_MAP_PATH.len()..].to_string())
+        .collect())
+}
+
+/// Deletes the block maps of versions that are no longer in `remote`.
+pub async fn collect_garbage(storage: &dyn Storage, remote: &Index) -> Result<()> {
+    let versions: HashSet<String> = remote
+        .files
+        .iter()
+        .flat_map(|(path, versions)| versions.iter().map(|version| blob_name(path, version)))
+        .collect();
+
+    for name in storage.list(&(BLOCK_MAP_PATH.to_string() + "/")).await? {
+        let version = &name[BLOCK_MAP_PATH.len()..];
+        if !versions.contains(version) {
+            storage.delete(&name).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,331 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    os::unix::prelude::{MetadataExt, PermissionsExt},
+};
+
+use crate::dedup;
+use crate::storage::Storage;
+
+/// Lists the remote storage and groups the blobs by file path. If a `prefix` other than `/` is given,
+/// only the versions of that path and the paths below it are listed.
+pub async fn create_remote_index(storage: &dyn Storage, prefix: &str) -> Result<Index> {
+    let mut index = Index::new();
+
+    let prefix = prefix.trim_end_matches('/');
+
+    for path in storage.list(prefix).await? {
+        let (file_path, version) = split_blob_name(&path)?;
+        let file_path = file_path.to_string();
+
+        // Chunks of deduplicated files are not versions
+        if dedup::is_reserved(&file_path) {
+            continue;
+        }
+
+        let version = Version::try_from(version)?;
+
+        // The blob prefix also matches siblings sharing the same name prefix, e.g. /etc/nginx2
+        if !prefix.is_empty()
+            && file_path != prefix
+            && !file_path.starts_with(&(prefix.to_string() + "/"))
+        {
+            continue;
+        }
+
+        let versions = index.files.get_mut(&file_path);
+        match versions {
+            Some(versions) => {
+                versions.push(version);
+            }
+            None => {
+                index.files.insert(file_path, vec![version]);
+            }
+        }
+    }
+
+    Ok(index)
+}
+
+/// Splits a blob name into the file path and the serialized version, see `blob_name`.
+pub fn split_blob_name(name: &str) -> Result<(&str, &str)> {
+    let last_delim = name
+        .rfind('/')
+        .ok_or_else(|| anyhow!("Malformed remote path: {}", name))?;
+
+    if last_delim + 1 >= name.len() {
+        return Err(anyhow!("Malformed remote path (trailing slash): {}", name));
+    }
+
+    Ok((&name[..last_delim], &name[last_delim + 1..]))
+}
+
+/// The name of the blob storing `version` of the file at `path`.
+pub fn blob_name(path: &str, version: &Version) -> String {
+    path.to_owned() + "/" + &version.serialize()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileType {
+    Regular,
+    Symlink,
+    Folder,
+    Deleted,
+}
+
+impl FileType {
+    pub fn parse(raw: &str) -> Result<FileType> {
+        if raw == "Regular" {
+            Ok(FileType::Regular)
+        } else if raw == "Symlink" {
+            Ok(FileType::Symlink)
+        } else if raw == "Folder" {
+            Ok(FileType::Folder)
+        } else if raw == "Deleted" {
+            Ok(FileType::Deleted)
+        } else {
+            Err(anyhow!("{} is not a file type", raw))
+        }
+    }
+}
+
+impl Display for FileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileType::Regular => f.write_str("Regular"),
+            FileType::Symlink => f.write_str("Symlink"),
+            FileType::Folder => f.write_str("Folder"),
+            FileType::Deleted => f.write_str("Deleted"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub mod_time: u64,
+    pub upload_time: u64,
+    pub permissions: u32,
+    pub size: u64,
+    pub file_type: FileType,
+    pub owner: u32,
+    pub group: u32,
+    /// The content is compressed, see compression.rs
+    pub compressed: bool,
+    /// The content is a list of chunks, see dedup.rs
+    pub deduplicated: bool,
+    /// The content is encrypted, see crypto.rs
+    pub encrypted: bool,
+    /// The hash of the content, if content hashing was enabled, see content_hash.rs
+    pub content_hash: Option<String>,
+}
+
+impl Version {
+    /// Creates the version of a local file, as it would be uploaded at `upload_time`.
+    pub fn from_dir_entry(entry: &walkdir::DirEntry, upload_time: u64) -> Result<Version> {
+        let metadata = entry.metadata()?;
+
+        let mut file_type = FileType::Regular;
+        if entry.file_type().is_symlink() {
+            file_type = FileType::Symlink;
+        } else if entry.file_type().is_dir() {
+            file_type = FileType::Folder;
+        }
+
+        Ok(Version {
+            mod_time: metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+            upload_time,
+            permissions: metadata.permissions().mode(),
+            size: metadata.size(),
+            file_type,
+            owner: metadata.uid(),
+            group: metadata.gid(),
+            compressed: false,
+            deduplicated: false,
+            encrypted: false,
+            content_hash: None,
+        })
+    }
+
+    /// Flags describing how the content is stored are appended after the metadata, e.g. `-zstd-enc`,
+    /// followed by the content hash, if there is one.
+    pub fn serialize(&self) -> String {
+        let mut serialized = format!(
+            "{}-{}-{:o}-{}-{}-{}-{}",
+            self.mod_time,
+            self.upload_time,
+            self.permissions,
+            self.size,
+            self.file_type,
+            self.owner,
+            self.group
+        );
+        if self.compressed {
+            serialized += "-zstd";
+        }
+        if self.deduplicated {
+            serialized += "-dedup";
+        }
+        if self.encrypted {
+            serialized += "-enc";
+        }
+        if let Some(hash) = &self.content_hash {
+            serialized += "-sha256_";
+            serialized += hash;
+        }
+        serialized
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.serialize())?;
+
+        Ok(())
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        // Upload time is ignored as that is metadata and not part of the version. Compression and
+        // deduplication are ignored as well, so enabling them doesn't upload every file again,
+        // whereas enabling encryption is meant to replace the plaintext versions. Content hashes
+        // are only compared if both versions have one, for the same reason.
+        let same_hash = match (&self.content_hash, &other.content_hash) {
+            (Some(hash), Some(other_hash)) => hash == other_hash,
+            _ => true,
+        };
+
+        same_hash
+            && self.mod_time == other.mod_time
+            && self.permissions == other.permissions
+            && self.size == other.size
+            && self.file_type == other.file_type
+            && self.owner == other.owner
+            && self.group == other.group
+            && self.encrypted == other.encrypted
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<std::cmp::Ordering> {
+        self.upload_time.partial_cmp(&other.upload_time)
+    }
+}
+
+impl TryFrom<&str> for Version {
+    type Error = anyhow::Error;
+
+    fn try_from(path: &str) -> Result<Self> {
+        let parts = path.split('-');
+        let collected: Vec<&str> = parts.collect();
+
+        if collected.len() < 7 {
+            return Err(anyhow!("Malformed version string {}", path));
+        }
+
+        let mut compressed = false;
+        let mut deduplicated = false;
+        let mut encrypted = false;
+        let mut content_hash = None;
+        for flag in &collected[7..] {
+            match *flag {
+                "zstd" => compressed = true,
+                "dedup" => deduplicated = true,
+                "enc" => encrypted = true,
+                _ => match flag.strip_prefix("sha256_") {
+                    Some(hash)
+                        if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) =>
+                    {
+                        content_hash = Some(hash.to_string())
+                    }
+                    _ => return Err(anyhow!("Unknown flag {} in version string {}", flag, path)),
+                },
+            }
+        }
+
+        Ok(Version {
+            mod_time: collected[0].parse()?,
+            upload_time: collected[1].parse()?,
+            permissions: u32::from_str_radix(collected[2], 8)?,
+            size: collected[3].parse()?,
+            file_type: FileType::parse(collected[4])?,
+            owner: collected[5].parse()?,
+            group: collected[6].parse()?,
+            compressed,
+            deduplicated,
+            encrypted,
+            content_hash,
+        })
+    }
+}
+
+/// Selects one version out of the history of a path.
+pub enum Selection {
+    /// The newest version
+    Latest,
+    /// The newest version uploaded no later than the given unix timestamp
+    At(u64),
+    /// The version at this position in the history of the path, ordered by upload time. Negative
+    /// positions count from the newest version, so -1 is the newest and 0 the oldest.
+    Index(i64),
+}
+
+impl Selection {
+    pub fn select<'a>(&self, versions: &'a [Version]) -> Option<&'a Version> {
+        match self {
+            Selection::Latest => versions.iter().max_by_key(|v| v.upload_time),
+            Selection::At(at) => versions
+                .iter()
+                .filter(|v| v.upload_time <= *at)
+                .max_by_key(|v| v.upload_time),
+            Selection::Index(idx) => {
+                let mut sorted: Vec<&Version> = versions.iter().collect();
+                sorted.sort_by_key(|v| v.upload_time);
+
+                let idx = if *idx < 0 {
+                    sorted.len() as i64 + idx
+                } else {
+                    *idx
+                };
+                usize::try_from(idx)
+                    .ok()
+                    .and_then(|idx| sorted.get(idx).copied())
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Index {
+    pub files: HashMap<String, Vec<Version>>,
+}
+
+impl Index {
+    pub fn new() -> Index {
+        Index {
+            files: HashMap::new(),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+pub mod backup;
+pub mod cli;
+pub mod compression;
+pub mod config;
+pub mod consistency;
+pub mod content_hash;
+pub mod crypto;
+pub mod dedup;
+pub mod incremental;
+pub mod index;
+pub mod list;
+pub mod restore;
+pub mod resume;
+pub mod storage;
+pub mod throttle;
+pub mod timestamp;
+pub mod verify;
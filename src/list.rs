@@ -0,0 +1,172 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use std::{collections::HashMap, io::Write};
+
+use crate::index::{create_remote_index, FileType, Selection, Version};
+use crate::storage::Storage;
+use crate::timestamp;
+
+pub struct Options {
+    /// The path to inspect, in the form used as index keys
+    pub path: String,
+    /// List the children of the path instead of the versions of the path itself
+    pub children: bool,
+    /// Which version of every child to show when listing children
+    pub selection: Selection,
+}
+
+/// Writes the listing to `out`, which is stdout outside of tests.
+pub async fn run(storage: &dyn Storage, options: &Options, out: &mut dyn Write) -> Result<()> {
+    let remote = create_remote_index(storage, &options.path).await?;
+
+    if options.children {
+        list_children(&remote.files, options, out)
+    } else {
+        list_versions(&remote.files, options, out)
+    }
+}
+
+fn list_versions(
+    files: &HashMap<String, Vec<Version>>,
+    options: &Options,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let versions = files
+        .get(&options.path)
+        .ok_or_else(|| anyhow!("There are no versions of {}", options.path))?;
+
+    let mut versions: Vec<&Version> = versions.iter().collect();
+    versions.sort_by_key(|v| v.upload_time);
+
+    writeln!(
+        out,
+        "{:<5} {:<19}  {:<8} {:<10} {:>11} {:>10}  {:<19}",
+        "INDEX", "UPLOADED", "TYPE", "MODE", "OWNER", "SIZE", "MODIFIED"
+    )?;
+    for (idx, version) in versions.iter().enumerate() {
+        writeln!(
+            out,
+            "{:<5} {:<19}  {:<8} {:<10} {:>11} {:>10}  {:<19}",
+            idx,
+            timestamp::format(version.upload_time),
+            version.file_type.to_string(),
+            mode_string(version),
+            format!("{}:{}", version.owner, version.group),
+            size_string(version),
+            modified_string(version),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn list_children(
+    files: &HashMap<String, Vec<Version>>,
+    options: &Options,
+    out: &mut dyn Write,
+) -> Result<()> {
+    let parent = options.path.trim_end_matches('/').to_string() + "/";
+
+    let mut children: Vec<(&str, &Version)> = files
+        .iter()
+        .filter_map(|(path, versions)| {
+            let name = path.strip_prefix(&parent)?;
+            if name.is_empty() || name.contains('/') {
+                return None;
+            }
+            match options.selection.select(versions) {
+                Some(version) if version.file_type != FileType::Deleted => Some((name, version)),
+                _ => None,
+            }
+        })
+        .collect();
+    children.sort_by_key(|c| c.0);
+
+    for (name, version) in children {
+        let mut name = name.to_string();
+        if version.file_type == FileType::Folder {
+            name += "/";
+        }
+
+        writeln!(
+            out,
+            "{:<10} {:>11} {:>10}  {:<19}  {}",
+            mode_string(version),
+            format!("{}:{}", version.owner, version.group),
+            size_string(version),
+            modified_string(version),
+            name
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Renders the type and permission bits the way `ls -l` does, e.g. `drwxr-xr-x`.
+fn mode_string(version: &Version) -> String {
+    let mut mode = String::new();
+    mode.push(match version.file_type {
+        FileType::Regular => '-',
+        FileType::Symlink => 'l',
+        FileType::Folder => 'd',
+        FileType::Deleted => return "-".to_string(),
+    });
+
+    let perms = version.permissions;
+    for (shift, special, special_char) in [(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')] {
+        let bits = (perms >> shift) & 0o7;
+        mode.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        mode.push(match (bits & 0o1 != 0, perms & special != 0) {
+            (true, true) => special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+
+    mode
+}
+
+fn size_string(version: &Version) -> String {
+    if version.file_type != FileType::Regular {
+        return "-".to_string();
+    }
+
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = version.size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", version.size, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn modified_string(version: &Version) -> String {
+    // Deletion markers don't carry a modification time
+    if version.file_type == FileType::Deleted {
+        return "-".to_string();
+    }
+    timestamp::format(version.mod_time)
+}
@@ -14,28 +14,68 @@
     You should have received a copy of the GNU General Public License
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-
-pub mod backup;
-pub mod config;
-
 use std::env::args;
 
 use anyhow::Result;
+use azure_blob_backup::{backup, cli, config, crypto, list, restore, storage, verify};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     simple_logger::init_with_level(log::Level::Info)?;
-    
+
     log::info!("{}", include_str!("../version"));
-    
-    let mut conf_path = "/etc/azure_blob_backup/config.yaml".to_string();
-    if args().len() > 1 {
-        conf_path = args().nth(1).unwrap_or(conf_path);
+
+    let args: Vec<String> = args().skip(1).collect();
+    let invocation = cli::parse(&args)?;
+
+    if let cli::Command::Help = invocation.command {
+        println!("{}", cli::USAGE);
+        return Ok(());
     }
-    
-    let conf = config::load(&conf_path)?;
-    
-    backup::run(&conf).await?;
-    
+
+    let conf = config::load(&invocation.config_path)?;
+    let storage = storage::from_config(&conf)?;
+    let storage = storage.as_ref();
+
+    match invocation.command {
+        cli::Command::Backup => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            let report = backup::run(&conf, storage, now).await?;
+            if !report.failed.is_empty() {
+                log::error!("{} paths could not be backed up:", report.failed.len());
+                for (path, reason) in &report.failed {
+                    log::error!("  {}: {}", path, reason);
+                }
+                // The rest of the tree was backed up, which is told apart from a failed run
+                std::process::exit(2);
+            }
+        }
+        cli::Command::Help => {}
+        cli::Command::Restore(options) => {
+            let encryption = crypto::from_config(&conf)?;
+            restore::run(storage, encryption.as_ref(), &options).await?
+        }
+        cli::Command::ListVersions(options) => {
+            list::run(storage, &options, &mut std::io::stdout()).await?
+        }
+        cli::Command::Verify(options) => {
+            let encryption = crypto::from_config(&conf)?;
+            let report = verify::run(storage, encryption.as_ref(), &options).await?;
+            log::info!(
+                "Verified {} versions, found {} corrupt, {} missing and {} unparseable blobs",
+                report.verified,
+                report.corrupt.len(),
+                report.missing.len(),
+                report.unparseable.len()
+            );
+            if report.has_problems() {
+                // Distinguishes damage to the backup from failing to check it at all
+                std::process::exit(2);
+            }
+        }
+    }
+
     Ok(())
 }
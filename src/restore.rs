@@ -0,0 +1,395 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Context, Result};
+use filetime::FileTime;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use std::{
+    collections::HashMap,
+    io::Write,
+    os::unix::{fs::OpenOptionsExt, prelude::PermissionsExt},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::compression;
+use crate::consistency;
+use crate::crypto::MasterKey;
+use crate::dedup;
+use crate::index::{blob_name, create_remote_index, FileType, Index, Selection, Version};
+use crate::storage::Storage;
+
+pub struct Options {
+    /// The local directory the backup is restored into
+    pub target: String,
+    /// Only restore this path and everything below it
+    pub path: String,
+    /// Which version of every path to restore
+    pub selection: Selection,
+    /// Translates the owners stored in the backup to users on this host
+    pub uid_map: IdMap,
+    /// Translates the groups stored in the backup to groups on this host
+    pub gid_map: IdMap,
+}
+
+/// Maps user or group ids stored in the backup to ids on the restoring host.
+#[derive(Default)]
+pub struct IdMap {
+    ids: HashMap<u32, u32>,
+    fallback: Option<u32>,
+}
+
+impl IdMap {
+    /// Adds a mapping of the form `<from>:<to>`. A `*` as the source maps all ids that have no
+    /// explicit mapping.
+    pub fn add(&mut self, raw: &str) -> Result<()> {
+        let (from, to) = raw
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Malformed id mapping {}, expected <from>:<to>", raw))?;
+        let to = to
+            .parse()
+            .with_context(|| format!("Malformed id mapping {}", raw))?;
+
+        if from == "*" {
+            self.fallback = Some(to);
+        } else {
+            let from = from
+                .parse()
+                .with_context(|| format!("Malformed id mapping {}", raw))?;
+            self.ids.insert(from, to);
+        }
+        Ok(())
+    }
+
+    fn get(&self, id: u32) -> Option<u32> {
+        self.ids.get(&id).copied().or(self.fallback)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ids.is_empty() && self.fallback.is_none()
+    }
+}
+
+/// Decides which owner and group restored files get.
+struct Ownership<'a> {
+    is_root: bool,
+    uid_map: &'a IdMap,
+    gid_map: &'a IdMap,
+}
+
+impl Ownership<'_> {
+    /// Only root may hand files to arbitrary users, so everybody else only applies explicitly
+    /// mapped ids and leaves the rest owned by the restoring user.
+    fn ids(&self, version: &Version) -> (Option<u32>, Option<u32>) {
+        let uid = self.uid_map.get(version.owner);
+        let gid = self.gid_map.get(version.group);
+        if self.is_root {
+            (uid.or(Some(version.owner)), gid.or(Some(version.group)))
+        } else {
+            (uid, gid)
+        }
+    }
+}
+
+pub async fn run(
+    storage: &dyn Storage,
+    encryption: Option<&MasterKey>,
+    options: &Options,
+) -> Result<()> {
+    // Strip trailing slashes, index paths always start with one
+    let target = options.target.trim_end_matches('/');
+
+    match options.selection {
+        Selection::Latest => log::info!("Restoring {} into {}", options.path, options.target),
+        Selection::At(at) => log::info!(
+            "Restoring the state of {} at {} into {}",
+            options.path,
+            at,
+            options.target
+        ),
+        Selection::Index(idx) => log::info!(
+            "Restoring version {} of {} into {}",
+            idx,
+            options.path,
+            options.target
+        ),
+    }
+
+    // Create the remote index
+    log::info!("Begin indexing of the remote storage");
+    let remote = create_remote_index(storage, &options.path).await?;
+    log::info!(
+        "Indexed the remote storage with {} files",
+        remote.files.len()
+    );
+
+    // An index beyond every history is a mistake rather than a request to restore nothing
+    if let Selection::Index(idx) = options.selection {
+        let exists = remote
+            .files
+            .values()
+            .any(|versions| options.selection.select(versions).is_some());
+        if !exists {
+            return Err(anyhow!(
+                "There is no version {} of {} or anything below it",
+                idx,
+                options.path
+            ));
+        }
+    }
+
+    let selected = select_versions(&remote, &options.selection);
+    log::info!("Restoring {} files", selected.len());
+
+    if encryption.is_none() && selected.iter().any(|(_, version)| version.encrypted) {
+        return Err(anyhow!(
+            "The backup is encrypted, but no encryption key is configured"
+        ));
+    }
+
+    let modified = consistency::load_markers(storage).await?;
+    for (path, version) in &selected {
+        if modified.contains(&blob_name(path, version)) {
+            log::warn!(
+                "{} was modified while it was backed up, its content may not match its size and modification time",
+                path
+            );
+        }
+    }
+
+    let ownership = Ownership {
+        is_root: unsafe { libc::geteuid() } == 0,
+        uid_map: &options.uid_map,
+        gid_map: &options.gid_map,
+    };
+    if !ownership.is_root && options.uid_map.is_empty() && options.gid_map.is_empty() {
+        log::warn!("Not running as root, restored files will be owned by the current user");
+    }
+
+    let mut processed: usize = 0;
+    let total_files = selected.len();
+    for (path, version) in &selected {
+        restore_file(version, path, target, storage, encryption).await?;
+
+        // Writing into a folder changes its modification time and its permissions might not allow
+        // writing at all, so folders are handled once all files are in place.
+        if version.file_type != FileType::Folder {
+            apply_metadata(version, &(target.to_string() + path), &ownership)?;
+        }
+
+        processed += 1;
+        print!("\r{processed} / {total_files}");
+
+        // If the flush fails its not the end of the world.
+        #[allow(unused_must_use)]
+        {
+            std::io::stdout().flush();
+        }
+    }
+    println!();
+
+    // Children sort after their parents, so walk backwards to finish the innermost folders first
+    for (path, version) in selected.iter().rev() {
+        if version.file_type == FileType::Folder {
+            apply_metadata(version, &(target.to_string() + path), &ownership)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Picks one version of every path in the index. Paths whose selected version is a deletion marker
+/// did not exist at that time and are skipped, as are paths without a matching version.
+fn select_versions<'a>(remote: &'a Index, selection: &Selection) -> Vec<(&'a String, &'a Version)> {
+    let mut selected = Vec::new();
+
+    for (path, versions) in &remote.files {
+        match selection.select(versions) {
+            Some(version) if version.file_type != FileType::Deleted => {
+                selected.push((path, version));
+            }
+            _ => {}
+        }
+    }
+
+    // Sorting by path ensures folders are created before their contents
+    selected.sort_by(|a, b| a.0.cmp(b.0));
+
+    selected
+}
+
+async fn restore_file(
+    version: &Version,
+    path: &str,
+    target: &str,
+    storage: &dyn Storage,
+    encryption: Option<&MasterKey>,
+) -> Result<()> {
+    // Paths come from the storage, which must not be able to place files outside of the target
+    check_path(path)?;
+    check_parents(target, path)?;
+    let local_path = target.to_string() + path;
+
+    match version.file_type {
+        FileType::Folder => {
+            remove_symlink(&local_path)?;
+            std::fs::create_dir_all(&local_path)?;
+        }
+        FileType::Symlink => {
+            create_parent(&local_path)?;
+            let chunks: Vec<Vec<u8>> = content(version, path, storage, encryption)
+                .await?
+                .try_collect()
+                .await?;
+            let link = String::from_utf8(chunks.concat())?;
+
+            // Replace whatever is in the way, symlink creation fails on existing paths
+            if std::fs::symlink_metadata(&local_path).is_ok() {
+                std::fs::remove_file(&local_path)?;
+            }
+            std::os::unix::fs::symlink(link, &local_path)?;
+        }
+        FileType::Regular => {
+            create_parent(&local_path)?;
+            remove_symlink(&local_path)?;
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&local_path)?;
+
+            // Range requests on empty blobs are rejected, and there is nothing to download anyways
+            if version.size > 0 {
+                let mut chunks = content(version, path, storage, encryption).await?;
+                while let Some(chunk) = chunks.next().await {
+                    file.write_all(&chunk?)?;
+                }
+            }
+        }
+        FileType::Deleted => {
+            return Err(anyhow!("Unable to restore deleted file {}", path));
+        }
+    }
+
+    Ok(())
+}
+
+/// Streams the content of `version`, decrypting and decompressing it if needed. Deduplicated
+/// versions are streamed chunk by chunk.
+pub(crate) async fn content<'a>(
+    version: &Version,
+    path: &str,
+    storage: &'a dyn Storage,
+    encryption: Option<&'a MasterKey>,
+) -> Result<BoxStream<'a, Result<Vec<u8>>>> {
+    if version.deduplicated {
+        let names = dedup::read_manifest(storage, path, version, encryption).await?;
+        return Ok(futures::stream::iter(names)
+            .then(move |name| async move { dedup::read_chunk(storage, &name, encryption).await })
+            .boxed());
+    }
+
+    let name = blob_name(path, version);
+    let mut chunks = storage.get(&name).await?;
+
+    if version.encrypted {
+        let key = encryption
+            .ok_or_else(|| anyhow!("{} is encrypted, but no encryption key is configured", path))?;
+        chunks = key.decrypt_stream(&name, chunks);
+    }
+    if version.compressed {
+        chunks = compression::decompress_stream(chunks);
+    }
+
+    Ok(chunks)
+}
+
+fn apply_metadata(version: &Version, local_path: &str, ownership: &Ownership) -> Result<()> {
+    let mod_time = FileTime::from_unix_time(version.mod_time as i64, 0);
+
+    // chown clears the setuid and setgid bits, so it has to happen before the mode is set
+    let (uid, gid) = ownership.ids(version);
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::lchown(local_path, uid, gid)
+            .with_context(|| format!("Unable to change the owner of {}", local_path))?;
+    }
+
+    if version.file_type == FileType::Symlink {
+        // The permissions of symlinks can't be changed on linux and are never used
+        filetime::set_symlink_file_times(local_path, mod_time, mod_time)?;
+    } else {
+        let permissions = std::fs::Permissions::from_mode(version.permissions & 0o7777);
+        std::fs::set_permissions(local_path, permissions)?;
+        filetime::set_file_times(local_path, mod_time, mod_time)?;
+    }
+
+    Ok(())
+}
+
+fn create_parent(local_path: &str) -> Result<()> {
+    if let Some(parent) = std::path::Path::new(local_path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+/// Fails unless `path` consists of a leading slash followed by plain names, so appending it to the
+/// target stays within the target.
+fn check_path(path: &str) -> Result<()> {
+    let relative = path
+        .strip_prefix('/')
+        .ok_or_else(|| anyhow!("Refusing to restore {}, it is not an absolute path", path))?;
+    let plain = Path::new(relative)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !plain {
+        return Err(anyhow!(
+            "Refusing to restore {}, it leaves the target directory",
+            path
+        ));
+    }
+    Ok(())
+}
+
+/// Fails if a folder between `target` and the file at `path` is a symlink, e.g. because another
+/// version of it was restored, as writing below it would end up outside of the target.
+fn check_parents(target: &str, path: &str) -> Result<()> {
+    let mut current = PathBuf::from(target);
+    let parents = Path::new(path.trim_start_matches('/')).parent();
+    for component in parents.iter().flat_map(|parent| parent.components()) {
+        current.push(component);
+        let is_symlink = std::fs::symlink_metadata(&current)
+            .is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if is_symlink {
+            return Err(anyhow!(
+                "Refusing to restore {}, {} is a symlink",
+                path,
+                current.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Removes a symlink at `local_path`, so it isn't followed when a file or folder is restored there.
+fn remove_symlink(local_path: &str) -> Result<()> {
+    let is_symlink = std::fs::symlink_metadata(local_path)
+        .is_ok_and(|metadata| metadata.file_type().is_symlink());
+    if is_symlink {
+        std::fs::remove_file(local_path)?;
+    }
+    Ok(())
+}
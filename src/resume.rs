@@ -0,0 +1,86 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::Result;
+use std::collections::HashSet;
+
+use crate::index::{blob_name, split_blob_name, Index, Version};
+use crate::storage::Storage;
+
+// Blocks of regular files are staged with ids derived from the blob name, and backends keep staged
+// blocks that were never committed. An upload of several blocks leaves a marker below UPLOAD_PATH
+// until it is committed. If the run is interrupted, the next run finds the marker, and if the file
+// didn't change in the meantime, uploads it under the name of the marked version again, skipping the
+// blocks that are already staged. The resumed version keeps the upload time of the interrupted run.
+
+const UPLOAD_PATH: &str = "/.azure_blob_backup/uploads";
+
+/// The name of the marker of an upload of `version` of the file at `path`.
+pub fn marker_name(path: &str, version: &Version) -> String {
+    UPLOAD_PATH.to_string() + &blob_name(path, version)
+}
+
+/// Whether an upload left behind for `marked` can be resumed to upload `version`, i.e. both only
+/// differ in their upload time.
+fn resumes(marked: &Version, version: &Version) -> bool {
+    let mut version = version.clone();
+    version.upload_time = marked.upload_time;
+    version.serialize() == marked.serialize()
+}
+
+/// Lets the versions in `local` take over the uploads of earlier runs that were interrupted.
+/// Uploads of versions that are stored in `remote` after all, or that no longer match the local
+/// file, are discarded together with their staged blocks.
+pub async fn adopt(storage: &dyn Storage, local: &mut Index, remote: &Index) -> Result<()> {
+    let mut resumed = HashSet::new();
+    for name in storage.list(&(UPLOAD_PATH.to_string() + "/")).await? {
+        let (path, marked) = split_blob_name(&name[UPLOAD_PATH.len()..])?;
+        let marked = Version::try_from(marked)?;
+
+        let committed = remote
+            .files
+            .get(path)
+            .is_some_and(|versions| versions.iter().any(|v| v.serialize() == marked.serialize()));
+        let version = local
+            .files
+            .get_mut(path)
+            .and_then(|versions| versions.first_mut())
+            .filter(|version| !committed && !resumed.contains(path) && resumes(&marked, version));
+
+        match version {
+            Some(version) => {
+                // Backends that can't resume report nothing staged, what they kept is dropped
+                let staged = blob_name(path, &marked);
+                if storage.staged_blocks(&staged).await?.is_empty() {
+                    storage.discard_staged(&staged).await?;
+                }
+                version.upload_time = marked.upload_time;
+                resumed.insert(path.to_string());
+            }
+            None => {
+                if !committed {
+                    storage.discard_staged(&blob_name(path, &marked)).await?;
+                }
+                storage.delete(&name).await?;
+            }
+        }
+    }
+
+    if !resumed.is_empty() {
+        log::info!("Resuming {} interrupted uploads", resumed.len());
+    }
+    Ok(())
+}
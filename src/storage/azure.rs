@@ -0,0 +1,320 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use azure_core::{headers, request_options::Metadata, HttpClient, Method, Request, RetryOptions};
+use azure_storage::{CloudLocation, StorageCredentials};
+use azure_storage_blobs::prelude::*;
+use base64::Engine;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use std::{collections::HashMap, sync::Arc};
+
+use super::{Md5Mismatch, Storage};
+
+/// Stores blobs in an azure blob storage container.
+pub struct AzureStorage {
+    client: ContainerClient,
+    /// The sdk lacks some operations, which are sent directly using the sas token
+    sas_token: String,
+    http_client: Arc<dyn HttpClient>,
+}
+
+impl AzureStorage {
+    /// Connects to the container using a sas url with read, write, delete and list permissions.
+    pub fn from_sas_url(sas_url: &str) -> Result<AzureStorage> {
+        let url = url::Url::parse(sas_url)?;
+        let is_azure = url.host_str().is_some_and(|host| {
+            host.ends_with(".core.windows.net") || host.ends_with(".core.chinacloudapi.cn")
+        });
+
+        let (location, container) = if is_azure {
+            sas_location(&url)?
+        } else {
+            path_style_location(&url)?
+        };
+        // Failed requests are retried by the storage wrapper in retry.rs, which covers the
+        // requests sent without the sdk as well
+        let client = ClientBuilder::with_location(location)
+            .retry(RetryOptions::none())
+            .container_client(container);
+        let sas_token = url
+            .query()
+            .ok_or_else(|| anyhow!("The sas url does not contain a sas token"))?
+            .to_string();
+
+        Ok(AzureStorage {
+            client,
+            sas_token,
+            http_client: azure_core::new_http_client(),
+        })
+    }
+
+    /// The url of the blob `name`, authorized with the sas token.
+    fn signed_url(&self, name: &str) -> Result<url::Url> {
+        let mut url = self.client.blob_client(name).url()?;
+        url.set_query(Some(&self.sas_token));
+        Ok(url)
+    }
+
+    /// A Put Block request, without its content.
+    fn put_block_request(&self, name: &str, block_id: &str) -> Result<Request> {
+        let mut url = self.signed_url(name)?;
+        url.query_pairs_mut()
+            .append_pair("comp", "block")
+            .append_pair(
+                "blockid",
+                &base64::engine::general_purpose::STANDARD.encode(block_id),
+            );
+
+        let mut request = Request::new(url, Method::Put);
+        request.insert_header(headers::VERSION, API_VERSION);
+        Ok(request)
+    }
+}
+
+/// The metadata key the checksum of a blob is stored under.
+const CHECKSUM_KEY: &str = "content_hash";
+
+/// The api version of requests sent without the sdk.
+const API_VERSION: &str = "2020-04-08";
+
+/// Azure verifies uploaded data against the MD5 sent along with it and reports the MD5 of what it
+/// received, which is checked as well, in case the service skipped the verification.
+fn check_md5(name: &str, sent: &md5::Digest, reported: Option<&[u8]>) -> Result<()> {
+    match reported {
+        Some(reported) if reported != sent.0 => Err(Md5Mismatch(format!(
+            "Azure reported a different MD5 for data uploaded to {}",
+            name
+        ))
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+fn is_not_found(error: &azure_core::error::Error) -> bool {
+    matches!(
+        error.kind(),
+        azure_core::error::ErrorKind::HttpResponse {
+            status: azure_core::StatusCode::NotFound,
+            ..
+        }
+    )
+}
+
+/// The account and container of a sas url of the form
+/// `https://<account>.blob.core.windows.net/<container>?<sas>`.
+fn sas_location(url: &url::Url) -> Result<(CloudLocation, String)> {
+    let location = CloudLocation::try_from(url)?;
+    let container = url
+        .path()
+        .split_terminator('/')
+        .nth(1)
+        .ok_or_else(|| anyhow!("Unable to find the container in the sas url"))?;
+    Ok((location, container.to_string()))
+}
+
+/// Emulators like Azurite put the account into the path instead of the host name, e.g.
+/// `http://127.0.0.1:10000/devstoreaccount1/<container>?<sas>`, which the sdk can't parse.
+fn path_style_location(url: &url::Url) -> Result<(CloudLocation, String)> {
+    let malformed = || anyhow!("Unable to find the account and container in the sas url");
+
+    let mut segments = url.path_segments().ok_or_else(malformed)?;
+    let account = segments
+        .next()
+        .filter(|s| !s.is_empty())
+        .ok_or_else(malformed)?;
+    let container = segments
+        .next()
+        .filter(|s| !s.is_empty())
+        .ok_or_else(malformed)?;
+    let token = url
+        .query()
+        .ok_or_else(|| anyhow!("The sas url does not contain a sas token"))?;
+
+    let location = CloudLocation::Custom {
+        uri: format!("{}/{}", url.origin().ascii_serialization(), account),
+        credentials: StorageCredentials::sas_token(token)?,
+    };
+    Ok((location, container.to_string()))
+}
+
+#[async_trait]
+impl Storage for AzureStorage {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        // The client strips the leading slash of blob names, so the stored names lack it
+        let mut list_builder = self.client.list_blobs();
+        let prefix = prefix.strip_prefix('/').unwrap_or(prefix);
+        if !prefix.is_empty() {
+            list_builder = list_builder.prefix(prefix.to_string());
+        }
+
+        let mut list_stream = list_builder.into_stream();
+        while let Some(page) = list_stream.next().await {
+            for blob in page?.blobs.blobs() {
+                names.push("/".to_string() + &blob.name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    async fn put(&self, name: &str, data: Bytes) -> Result<()> {
+        let md5 = md5::compute(&data);
+        let response = self
+            .client
+            .blob_client(name)
+            .put_block_blob(data)
+            .hash(Hash::MD5(md5.0))
+            .await?;
+        let reported = response.content_md5.as_ref().map(|md5| &md5.as_slice()[..]);
+        check_md5(name, &md5, reported)
+    }
+
+    async fn put_block(&self, name: &str, block_id: &str, data: Bytes) -> Result<()> {
+        // The sdk drops the hash of blocks, so the request is sent directly
+        let md5 = md5::compute(&data);
+        let mut request = self.put_block_request(name, block_id)?;
+        request.insert_header(headers::CONTENT_LENGTH, data.len().to_string());
+        request.insert_header(
+            headers::CONTENT_MD5,
+            base64::engine::general_purpose::STANDARD.encode(md5.0),
+        );
+        request.set_body(data);
+
+        let response = self
+            .http_client
+            .execute_request_check_status(&request)
+            .await?;
+        let reported = response
+            .headers()
+            .get_optional_str(&headers::CONTENT_MD5)
+            .map(|md5| base64::engine::general_purpose::STANDARD.decode(md5))
+            .transpose()?;
+        check_md5(name, &md5, reported.as_deref())
+    }
+
+    async fn staged_blocks(&self, name: &str) -> Result<HashMap<String, u64>> {
+        let response = self
+            .client
+            .blob_client(name)
+            .get_block_list()
+            .block_list_type(BlockListType::Uncommitted)
+            .await;
+        // A blob that was neither committed nor staged doesn't exist yet
+        let response = match response {
+            Ok(response) => response,
+            Err(e) if is_not_found(&e) => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut blocks = HashMap::new();
+        for block in response.block_with_size_list.blocks {
+            if let BlobBlockType::Uncommitted(block_id) = block.block_list_type {
+                let block_id = String::from_utf8_lossy(&block_id.bytes()).to_string();
+                blocks.insert(block_id, block.size_in_bytes);
+            }
+        }
+        Ok(blocks)
+    }
+
+    fn can_copy_blocks(&self) -> bool {
+        true
+    }
+
+    async fn put_block_from(
+        &self,
+        name: &str,
+        block_id: &str,
+        source: &str,
+        offset: u64,
+        length: u64,
+        content_md5: md5::Digest,
+    ) -> Result<()> {
+        if length == 0 {
+            return self.put_block(name, block_id, Bytes::new()).await;
+        }
+
+        // Put Block From URL, the data is copied within azure
+        let mut request = self.put_block_request(name, block_id)?;
+        request.insert_header(headers::CONTENT_LENGTH, "0");
+        request.insert_header(headers::COPY_SOURCE, self.signed_url(source)?.to_string());
+        request.insert_header(
+            "x-ms-source-range",
+            format!("bytes={}-{}", offset, offset + length - 1),
+        );
+        // Azure checks the range against the MD5 of the local block before staging it
+        request.insert_header(
+            "x-ms-source-content-md5",
+            base64::engine::general_purpose::STANDARD.encode(content_md5.0),
+        );
+
+        self.http_client
+            .execute_request_check_status(&request)
+            .await?;
+        Ok(())
+    }
+
+    async fn put_block_list(&self, name: &str, block_ids: &[String], checksum: &str) -> Result<()> {
+        // An earlier attempt may have committed the blocks without its response arriving. The
+        // latest version of a block is the staged one, or the committed one if there is none.
+        let blocks = block_ids
+            .iter()
+            .map(|id| BlobBlockType::Latest(BlockId::from(id.clone())))
+            .collect();
+        let mut metadata = Metadata::new();
+        metadata.insert(CHECKSUM_KEY, checksum.to_string());
+
+        let blob = self.client.blob_client(name);
+        blob.put_block_list(BlockList { blocks })
+            .metadata(metadata)
+            .await?;
+        Ok(())
+    }
+
+    async fn checksum(&self, name: &str) -> Result<Option<String>> {
+        let properties = self.client.blob_client(name).get_properties().await?;
+        Ok(properties
+            .blob
+            .metadata
+            .and_then(|mut metadata| metadata.remove(CHECKSUM_KEY)))
+    }
+
+    async fn get(&self, name: &str) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        let blob = self.client.blob_client(name);
+
+        // The stream only sends its requests once it is polled. The first one is sent right away,
+        // so failing to reach the blob fails the call, which can be retried.
+        let mut pages = blob.get().into_stream();
+        let first = pages.next().await.transpose()?;
+        let chunks = futures::stream::iter(first.map(Ok))
+            .chain(pages)
+            .map_ok(|response| response.data)
+            .try_flatten()
+            .map_ok(|bytes| bytes.to_vec())
+            .map_err(anyhow::Error::from);
+
+        Ok(chunks.boxed())
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        self.client.blob_client(name).delete().await?;
+        Ok(())
+    }
+}
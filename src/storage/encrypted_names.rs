@@ -0,0 +1,258 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use aes_gcm_siv::{
+    aead::{Aead, KeyInit},
+    Aes256GcmSiv, Nonce,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{BTreeSet, HashMap};
+use tokio::sync::Mutex;
+
+use super::{block_id, block_index, Storage};
+use crate::crypto::MasterKey;
+
+/// The length of the path id in bytes. Its first bytes double as the nonce of the name cipher.
+const ID_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+/// File systems limit the length of a single path component, so long encrypted names are split.
+const COMPONENT_SIZE: usize = 200;
+
+/// Hides file paths and version metadata from the storage. The blob `<path>/<version>` is stored as
+/// `/<id>/<encrypted name>`, where the id is a keyed hash of the path, so all versions of a file
+/// share it, and the encrypted name holds the path and version. Names are encrypted
+/// deterministically with AES-GCM-SIV, so the stored name of a blob can be computed again for
+/// deleting or reading it. Block ids contain a hash of the blob name, see `block_id`, so they are
+/// staged with the hash of the stored name instead.
+/// The stored names don't preserve prefixes, so the whole container is listed and decrypted once,
+/// and later listings are answered from the decrypted names, which writes keep up to date.
+pub struct EncryptedNames {
+    inner: Box<dyn Storage>,
+    id_key: Hmac<Sha256>,
+    cipher: Aes256GcmSiv,
+    names: Mutex<Option<BTreeSet<String>>>,
+}
+
+impl EncryptedNames {
+    pub fn new(inner: Box<dyn Storage>, key: &MasterKey) -> EncryptedNames {
+        EncryptedNames {
+            inner,
+            id_key: <Hmac<Sha256> as Mac>::new_from_slice(&key.derive("blob name ids"))
+                .expect("HMAC takes keys of any size"),
+            cipher: Aes256GcmSiv::new(&key.derive("blob names").into()),
+            names: Mutex::new(None),
+        }
+    }
+
+    /// Adds or removes `name` in the decrypted listing, if it was listed already.
+    async fn record(&self, name: &str, stored: bool) {
+        if let Some(names) = self.names.lock().await.as_mut() {
+            if stored {
+                names.insert(name.to_string());
+            } else {
+                names.remove(name);
+            }
+        }
+    }
+
+    /// Maps the name `<path>/<version>` used by the rest of the program to the stored name.
+    fn encrypt(&self, name: &str) -> Result<String> {
+        let (path, version) = name
+            .rsplit_once('/')
+            .ok_or_else(|| anyhow!("Malformed blob name {}", name))?;
+
+        let mut mac = self.id_key.clone();
+        mac.update(path.as_bytes());
+        let id = mac.finalize().into_bytes();
+        let id = &id[..ID_SIZE];
+
+        // The version never contains a slash, so it is put first to be split off again
+        let plaintext = version.to_string() + "/" + path;
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&id[..NONCE_SIZE]), plaintext.as_bytes())
+            .map_err(|_| anyhow!("Unable to encrypt the blob name {}", name))?;
+        let encoded = URL_SAFE_NO_PAD.encode(ciphertext);
+
+        let mut stored = "/".to_string() + &hex::encode(id);
+        for component in encoded.as_bytes().chunks(COMPONENT_SIZE) {
+            stored.push('/');
+            stored += std::str::from_utf8(component)?;
+        }
+        Ok(stored)
+    }
+
+    /// Maps the id of a block of `name` to the id it is staged with below the stored name.
+    fn encrypt_block_id(&self, stored: &str, id: &str) -> Result<String> {
+        let index = block_index(id).ok_or_else(|| anyhow!("Malformed block id {}", id))?;
+        Ok(block_id(stored, index))
+    }
+
+    /// Maps a stored name back to `<path>/<version>`.
+    fn decrypt(&self, stored: &str) -> Result<String> {
+        let malformed = || {
+            anyhow!(
+                "The blob {} is not an encrypted name. Names can only be encrypted in an empty container",
+                stored
+            )
+        };
+
+        let (id, encoded) = stored
+            .strip_prefix('/')
+            .and_then(|rest| rest.split_once('/'))
+            .ok_or_else(malformed)?;
+        let id = hex::decode(id).map_err(|_| malformed())?;
+        if id.len() != ID_SIZE {
+            return Err(malformed());
+        }
+        let ciphertext = URL_SAFE_NO_PAD
+            .decode(encoded.replace('/', ""))
+            .map_err(|_| malformed())?;
+
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(&id[..NONCE_SIZE]), ciphertext.as_ref())
+            .map_err(|_| {
+                anyhow!(
+                    "Unable to decrypt the blob name {}, it was encrypted with a different key",
+                    stored
+                )
+            })?;
+        let plaintext = String::from_utf8(plaintext)?;
+        let (version, path) = plaintext.split_once('/').ok_or_else(malformed)?;
+
+        Ok(path.to_string() + "/" + version)
+    }
+}
+
+#[async_trait]
+impl Storage for EncryptedNames {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        // Held while listing, so no write is missed by a listing that is still in progress
+        let mut names = self.names.lock().await;
+        if names.is_none() {
+            let mut decrypted = BTreeSet::new();
+            for stored in self.inner.list("/").await? {
+                decrypted.insert(self.decrypt(&stored)?);
+            }
+            *names = Some(decrypted);
+        }
+
+        let names = names.as_ref().unwrap();
+        Ok(names
+            .range(prefix.to_string()..)
+            .take_while(|name| name.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn put(&self, name: &str, data: Bytes) -> Result<()> {
+        self.inner.put(&self.encrypt(name)?, data).await?;
+        self.record(name, true).await;
+        Ok(())
+    }
+
+    async fn put_block(&self, name: &str, block_id: &str, data: Bytes) -> Result<()> {
+        let stored = self.encrypt(name)?;
+        let block_id = self.encrypt_block_id(&stored, block_id)?;
+        self.inner.put_block(&stored, &block_id, data).await
+    }
+
+    async fn put_block_from(
+        &self,
+        name: &str,
+        block_id: &str,
+        source: &str,
+        offset: u64,
+        length: u64,
+        content_md5: md5::Digest,
+    ) -> Result<()> {
+        let stored = self.encrypt(name)?;
+        let block_id = self.encrypt_block_id(&stored, block_id)?;
+        self.inner
+            .put_block_from(
+                &stored,
+                &block_id,
+                &self.encrypt(source)?,
+                offset,
+                length,
+                content_md5,
+            )
+            .await
+    }
+
+    async fn put_block_list(&self, name: &str, block_ids: &[String], checksum: &str) -> Result<()> {
+        let stored = self.encrypt(name)?;
+        let block_ids = block_ids
+            .iter()
+            .map(|id| self.encrypt_block_id(&stored, id))
+            .collect::<Result<Vec<_>>>()?;
+        self.inner
+            .put_block_list(&stored, &block_ids, checksum)
+            .await?;
+        self.record(name, true).await;
+        Ok(())
+    }
+
+    async fn staged_blocks(&self, name: &str) -> Result<HashMap<String, u64>> {
+        let stored = self.encrypt(name)?;
+        let staged = self.inner.staged_blocks(&stored).await?;
+        Ok(staged
+            .into_iter()
+            .filter_map(|(id, size)| {
+                let index = block_index(&id).filter(|index| id == block_id(&stored, *index))?;
+                Some((block_id(name, index), size))
+            })
+            .collect())
+    }
+
+    async fn discard_staged(&self, name: &str) -> Result<()> {
+        self.inner.discard_staged(&self.encrypt(name)?).await
+    }
+
+    fn concurrent_blocks(&self) -> bool {
+        self.inner.concurrent_blocks()
+    }
+
+    fn can_copy_blocks(&self) -> bool {
+        self.inner.can_copy_blocks()
+    }
+
+    fn max_blocks(&self) -> u64 {
+        self.inner.max_blocks()
+    }
+
+    async fn checksum(&self, name: &str) -> Result<Option<String>> {
+        self.inner.checksum(&self.encrypt(name)?).await
+    }
+
+    async fn get(&self, name: &str) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        self.inner.get(&self.encrypt(name)?).await
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        self.inner.delete(&self.encrypt(name)?).await?;
+        self.record(name, false).await;
+        Ok(())
+    }
+}
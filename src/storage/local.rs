@@ -0,0 +1,308 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use super::{check_copy, Storage};
+
+/// The size of the chunks files are streamed in.
+const READ_CHUNK_SIZE: usize = 4 << 20;
+
+/// Appended to the names of staged blocks while they are written. Block ids never contain dots.
+const PARTIAL_SUFFIX: &str = ".tmp";
+
+/// Stores blobs as files in a local directory, e.g. a mounted disk or NAS share. The blobs are kept
+/// below `<root>/blobs` using their names as relative paths, so every backed up file becomes a
+/// directory containing one file per version. Blocks are staged in `<root>/staging` until they are
+/// committed, which keeps half written blobs out of listings. Staged blocks survive the process, so
+/// interrupted uploads can be resumed. Checksums are kept in the same layout
+/// below `<root>/checksums`.
+pub struct LocalStorage {
+    blobs: PathBuf,
+    staging: PathBuf,
+    checksums: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> Result<LocalStorage> {
+        let root = Path::new(root);
+        if !root.is_dir() {
+            return Err(anyhow!(
+                "The storage path {} is not a directory",
+                root.display()
+            ));
+        }
+
+        let storage = LocalStorage {
+            blobs: root.join("blobs"),
+            staging: root.join("staging"),
+            checksums: root.join("checksums"),
+        };
+        std::fs::create_dir_all(&storage.blobs)?;
+        std::fs::create_dir_all(&storage.staging)?;
+
+        Ok(storage)
+    }
+
+    fn blob_path(&self, name: &str) -> PathBuf {
+        self.blobs.join(name.trim_start_matches('/'))
+    }
+
+    fn checksum_path(&self, name: &str) -> PathBuf {
+        self.checksums.join(name.trim_start_matches('/'))
+    }
+
+    /// Removes the checksum of `name`, if it has one.
+    fn remove_checksum(&self, name: &str) -> Result<()> {
+        let path = self.checksum_path(name);
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+            remove_empty_dirs(&path, &self.checksums);
+        }
+        Ok(())
+    }
+
+    /// The directory the blocks of the blob `name` are staged in.
+    fn block_dir(&self, name: &str) -> PathBuf {
+        self.staging.join(sha256::digest(name))
+    }
+
+    /// Moves a fully written file from the staging area to its final location, so readers never
+    /// see partial blobs.
+    fn commit(&self, staged: &Path, name: &str) -> Result<()> {
+        let target = self.blob_path(name);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(staged, &target)
+            .with_context(|| format!("Unable to move {} into place", target.display()))?;
+        Ok(())
+    }
+
+    fn staged_file(&self, name: &str) -> PathBuf {
+        self.staging.join(sha256::digest(name) + ".tmp")
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        // Only walk the directory the prefix points into
+        let dir = match prefix.rfind('/') {
+            Some(idx) => self.blob_path(&prefix[..idx]),
+            None => self.blobs.clone(),
+        };
+        if !dir.exists() {
+            return Ok(names);
+        }
+
+        for entry in walkdir::WalkDir::new(dir).follow_links(false) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+
+            let relative = entry.path().strip_prefix(&self.blobs)?;
+            let relative = relative.to_str().ok_or_else(|| {
+                anyhow!("Found a path with non unicode characters: {:?}", relative)
+            })?;
+
+            // Files directly in the blobs directory belong to the backup root, whose blob names
+            // start with two slashes, see index::blob_name
+            let name = if relative.contains('/') {
+                "/".to_string() + relative
+            } else {
+                "//".to_string() + relative
+            };
+
+            if name.starts_with(prefix) {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    async fn put(&self, name: &str, data: Bytes) -> Result<()> {
+        let staged = self.staged_file(name);
+        let mut file = std::fs::File::create(&staged)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+
+        self.remove_checksum(name)?;
+        self.commit(&staged, name)
+    }
+
+    async fn put_block(&self, name: &str, block_id: &str, data: Bytes) -> Result<()> {
+        let dir = self.block_dir(name);
+        std::fs::create_dir_all(&dir)?;
+
+        // Written next to its final name first, so a crash can't leave a torn block behind
+        let partial = dir.join(block_id.to_string() + PARTIAL_SUFFIX);
+        let mut file = std::fs::File::create(&partial)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        std::fs::rename(&partial, dir.join(block_id))?;
+
+        Ok(())
+    }
+
+    async fn staged_blocks(&self, name: &str) -> Result<HashMap<String, u64>> {
+        let mut blocks = HashMap::new();
+        let dir = self.block_dir(name);
+        if !dir.exists() {
+            return Ok(blocks);
+        }
+
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            match entry.file_name().to_str() {
+                Some(block_id) if !block_id.ends_with(PARTIAL_SUFFIX) => {
+                    blocks.insert(block_id.to_string(), entry.metadata()?.len());
+                }
+                _ => {}
+            }
+        }
+        Ok(blocks)
+    }
+
+    async fn discard_staged(&self, name: &str) -> Result<()> {
+        let dir = self.block_dir(name);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+        Ok(())
+    }
+
+    fn can_copy_blocks(&self) -> bool {
+        true
+    }
+
+    async fn put_block_from(
+        &self,
+        name: &str,
+        block_id: &str,
+        source: &str,
+        offset: u64,
+        length: u64,
+        content_md5: md5::Digest,
+    ) -> Result<()> {
+        let path = self.blob_path(source);
+        let mut source_file = std::fs::File::open(&path)
+            .with_context(|| format!("Unable to open {}", path.display()))?;
+        source_file.seek(std::io::SeekFrom::Start(offset))?;
+
+        // Blocks are small enough to be checked in memory before they are staged
+        let mut data = Vec::with_capacity(length as usize);
+        source_file.take(length).read_to_end(&mut data)?;
+        if data.len() as u64 != length {
+            return Err(anyhow!(
+                "{} is too short to copy {} bytes at {}",
+                path.display(),
+                length,
+                offset
+            ));
+        }
+        check_copy(source, offset, &data, content_md5)?;
+
+        self.put_block(name, block_id, data.into()).await
+    }
+
+    async fn put_block_list(&self, name: &str, block_ids: &[String], checksum: &str) -> Result<()> {
+        let dir = self.block_dir(name);
+        let staged = self.staged_file(name);
+
+        let mut file = std::fs::File::create(&staged)?;
+        for block_id in block_ids {
+            let mut block = std::fs::File::open(dir.join(block_id))
+                .with_context(|| format!("Block {} of {} was never staged", block_id, name))?;
+            std::io::copy(&mut block, &mut file)?;
+        }
+        file.sync_all()?;
+
+        // The checksum goes first, so a committed blob always has it
+        let checksum_path = self.checksum_path(name);
+        if let Some(parent) = checksum_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&checksum_path, checksum)?;
+
+        self.commit(&staged, name)?;
+
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)?;
+        }
+
+        Ok(())
+    }
+
+    async fn checksum(&self, name: &str) -> Result<Option<String>> {
+        let path = self.checksum_path(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(std::fs::read_to_string(path)?))
+    }
+
+    async fn get(&self, name: &str) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        let path = self.blob_path(name);
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Unable to open {}", path.display()))?;
+
+        let chunks = futures::stream::try_unfold(file, |mut file| async move {
+            let mut chunk = vec![0; READ_CHUNK_SIZE];
+            let num_read = file.read(&mut chunk)?;
+            if num_read == 0 {
+                return Ok(None);
+            }
+            chunk.truncate(num_read);
+            Ok(Some((chunk, file)))
+        });
+
+        Ok(chunks.boxed())
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let path = self.blob_path(name);
+        std::fs::remove_file(&path)
+            .with_context(|| format!("Unable to delete {}", path.display()))?;
+        remove_empty_dirs(&path, &self.blobs);
+
+        self.remove_checksum(name)
+    }
+}
+
+/// Removes the directories above `path` that became empty, up to `root`, so deleted files don't
+/// leave a trail of folders.
+fn remove_empty_dirs(path: &Path, root: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == root || std::fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
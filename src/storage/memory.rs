@@ -0,0 +1,181 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use super::{check_copy, Storage};
+
+/// Keeps blobs in memory. Meant for tests of the sync engine, nothing survives the process.
+#[derive(Default)]
+pub struct MemoryStorage {
+    blobs: Mutex<BTreeMap<String, Vec<u8>>>,
+    staged_blocks: Mutex<HashMap<String, HashMap<String, Vec<u8>>>>,
+    checksums: Mutex<HashMap<String, String>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    /// The names of all stored blobs in lexicographic order.
+    pub fn names(&self) -> Vec<String> {
+        self.blobs.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// The content of the blob `name`, if it exists.
+    pub fn content(&self, name: &str) -> Option<Vec<u8>> {
+        self.blobs.lock().unwrap().get(name).cloned()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    async fn put(&self, name: &str, data: Bytes) -> Result<()> {
+        self.checksums.lock().unwrap().remove(name);
+        self.blobs
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), data.into());
+        Ok(())
+    }
+
+    async fn put_block(&self, name: &str, block_id: &str, data: Bytes) -> Result<()> {
+        self.staged_blocks
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .insert(block_id.to_string(), data.into());
+        Ok(())
+    }
+
+    async fn staged_blocks(&self, name: &str) -> Result<HashMap<String, u64>> {
+        Ok(self
+            .staged_blocks
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|blocks| {
+                blocks
+                    .iter()
+                    .map(|(block_id, data)| (block_id.clone(), data.len() as u64))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn discard_staged(&self, name: &str) -> Result<()> {
+        self.staged_blocks.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    fn can_copy_blocks(&self) -> bool {
+        true
+    }
+
+    async fn put_block_from(
+        &self,
+        name: &str,
+        block_id: &str,
+        source: &str,
+        offset: u64,
+        length: u64,
+        content_md5: md5::Digest,
+    ) -> Result<()> {
+        let content = self.content(source).ok_or_else(|| no_such_blob(source))?;
+        let data = content
+            .get(offset as usize..(offset + length) as usize)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} is too short to copy {} bytes at {}",
+                    source,
+                    length,
+                    offset
+                )
+            })?;
+        check_copy(source, offset, data, content_md5)?;
+        self.put_block(name, block_id, Bytes::copy_from_slice(data))
+            .await
+    }
+
+    async fn put_block_list(&self, name: &str, block_ids: &[String], checksum: &str) -> Result<()> {
+        let staged = self
+            .staged_blocks
+            .lock()
+            .unwrap()
+            .remove(name)
+            .unwrap_or_default();
+
+        let mut content = Vec::new();
+        for block_id in block_ids {
+            let block = staged
+                .get(block_id)
+                .ok_or_else(|| anyhow!("Block {} of {} was never staged", block_id, name))?;
+            content.extend_from_slice(block);
+        }
+
+        self.put(name, content.into()).await?;
+        self.checksums
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), checksum.to_string());
+        Ok(())
+    }
+
+    async fn checksum(&self, name: &str) -> Result<Option<String>> {
+        Ok(self.checksums.lock().unwrap().get(name).cloned())
+    }
+
+    async fn get(&self, name: &str) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        let content = self.content(name).ok_or_else(|| no_such_blob(name))?;
+        Ok(futures::stream::once(async move { Ok(content) }).boxed())
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        self.checksums.lock().unwrap().remove(name);
+        match self.blobs.lock().unwrap().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(no_such_blob(name)),
+        }
+    }
+}
+
+/// Missing blobs are reported like the local backend does, so they are recognized as missing, see
+/// `retry::is_not_found`.
+fn no_such_blob(name: &str) -> anyhow::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("No such blob: {}", name),
+    )
+    .into()
+}
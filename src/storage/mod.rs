@@ -0,0 +1,233 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+pub mod azure;
+pub mod encrypted_names;
+pub mod local;
+pub mod memory;
+pub mod retry;
+pub mod s3;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::crypto;
+
+/// Where the backups are stored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Azure,
+    Local,
+    S3,
+}
+
+/// A place blobs can be stored in. Blob names are the remote paths built by the sync engine, i.e.
+/// `<file path>/<serialized version>` including the leading slash of the file path. Every backend
+/// maps them to its own key space and returns them in the same form when listing.
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Lists the names of all blobs starting with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Stores `data` as the whole content of the blob `name`. Blobs committed with a checksum by
+    /// `put_block_list` are never stored again with this, so backends don't need to remove it.
+    async fn put(&self, name: &str, data: Bytes) -> Result<()>;
+
+    /// Stages one block of the blob `name`. Staged blocks become visible once they are committed
+    /// with `put_block_list`.
+    async fn put_block(&self, name: &str, block_id: &str, data: Bytes) -> Result<()>;
+
+    /// The blocks staged for the blob `name` that were not committed yet, with their sizes by id.
+    /// Backends that keep staged blocks across runs override this, so interrupted uploads can be
+    /// resumed, see resume.rs. By default nothing is reported.
+    async fn staged_blocks(&self, _name: &str) -> Result<HashMap<String, u64>> {
+        Ok(HashMap::new())
+    }
+
+    /// Drops the blocks staged for the blob `name` without committing them. By default they are
+    /// left to the backend, e.g. azure discards uncommitted blocks after a week.
+    async fn discard_staged(&self, _name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether blocks of the same blob may be staged concurrently. Backends that need to receive
+    /// the blocks of a blob in order override this.
+    fn concurrent_blocks(&self) -> bool {
+        true
+    }
+
+    /// How many blocks a blob may consist of. Files are split into blocks large enough to stay below
+    /// this. Azure allows 50000 blocks per blob, backends with a lower limit override this.
+    fn max_blocks(&self) -> u64 {
+        50_000
+    }
+
+    /// Whether `put_block_from` copies within the storage. Backends without such an operation would
+    /// download the range instead, which costs more than uploading the local data again.
+    fn can_copy_blocks(&self) -> bool {
+        false
+    }
+
+    /// Stages a block of the blob `name` consisting of `length` bytes at `offset` of the existing
+    /// blob `source`. `content_md5` is the MD5 of the data the block is expected to have, the copy
+    /// fails if the range differs. Backends that can copy data within the storage override this
+    /// together with `can_copy_blocks`, by default the range is downloaded and uploaded again.
+    async fn put_block_from(
+        &self,
+        name: &str,
+        block_id: &str,
+        source: &str,
+        offset: u64,
+        length: u64,
+        content_md5: md5::Digest,
+    ) -> Result<()> {
+        let mut data = Vec::with_capacity(length as usize);
+        let mut skip = offset as usize;
+        let mut chunks = self.get(source).await?;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            if skip >= chunk.len() {
+                skip -= chunk.len();
+                continue;
+            }
+            let end = std::cmp::min(chunk.len(), skip + length as usize - data.len());
+            data.extend_from_slice(&chunk[skip..end]);
+            skip = 0;
+            if data.len() == length as usize {
+                break;
+            }
+        }
+
+        if data.len() != length as usize {
+            return Err(anyhow!(
+                "{} is too short to copy {} bytes at {}",
+                source,
+                length,
+                offset
+            ));
+        }
+        check_copy(source, offset, &data, content_md5)?;
+        self.put_block(name, block_id, data.into()).await
+    }
+
+    /// Commits previously staged blocks, in the given order, as the content of the blob `name`.
+    /// `checksum` is the hash of the content before it was encoded, see content_hash.rs, and is
+    /// stored with the blob so it can be verified later.
+    async fn put_block_list(&self, name: &str, block_ids: &[String], checksum: &str) -> Result<()>;
+
+    /// The checksum the blob `name` was committed with, or None if it was stored without one.
+    async fn checksum(&self, name: &str) -> Result<Option<String>>;
+
+    /// Streams the content of the blob `name` in chunks.
+    async fn get(&self, name: &str) -> Result<BoxStream<'static, Result<Vec<u8>>>>;
+
+    /// Deletes the blob `name`.
+    async fn delete(&self, name: &str) -> Result<()>;
+
+    /// Reads the whole content of the blob `name` into memory.
+    async fn get_content(&self, name: &str) -> Result<Vec<u8>> {
+        let chunks: Vec<Vec<u8>> = self.get(name).await?.try_collect().await?;
+        Ok(chunks.concat())
+    }
+}
+
+/// The storage reported a different MD5 for uploaded data than the one of the data that was sent,
+/// so it was damaged on the way. Sending it again may succeed, see retry.rs.
+#[derive(Debug)]
+pub struct Md5Mismatch(pub String);
+
+impl std::fmt::Display for Md5Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Md5Mismatch {}
+
+/// Fails if the data copied from `offset` of `source` is not the data the block is expected to have.
+fn check_copy(source: &str, offset: u64, data: &[u8], content_md5: md5::Digest) -> Result<()> {
+    if md5::compute(data) != content_md5 {
+        return Err(anyhow!(
+            "The {} bytes at {} of {} differ from the block to copy",
+            data.len(),
+            offset,
+            source
+        ));
+    }
+    Ok(())
+}
+
+/// The id of the block at `index` of the blob `name`. Azure requires all block ids of a blob to
+/// have the same length, so the zero padded index is combined with a hash of the blob name.
+pub fn block_id(name: &str, index: u64) -> String {
+    let mut id = format!("{index:016}{}", sha256::digest(name));
+    id.truncate(64);
+    id
+}
+
+/// The index of a block id built by `block_id`.
+pub fn block_index(id: &str) -> Option<u64> {
+    id.get(..16)?.parse().ok()
+}
+
+/// Creates the storage backend configured in `conf`.
+pub fn from_config(conf: &Config) -> Result<Box<dyn Storage>> {
+    let storage = Box::new(retry::Retrying::new(
+        backend_from_config(conf)?,
+        retry::from_config(conf),
+    ));
+
+    if !conf.encrypt_names {
+        return Ok(storage);
+    }
+    let key = crypto::from_config(conf)?.expect("Config::validate ensures there is a key");
+    Ok(Box::new(encrypted_names::EncryptedNames::new(
+        storage, &key,
+    )))
+}
+
+/// Creates the configured backend. Config::validate ensures the keys it requires are set.
+fn backend_from_config(conf: &Config) -> Result<Box<dyn Storage>> {
+    let required = |value: &Option<String>| value.clone().expect("validated by Config::validate");
+
+    match conf.backend {
+        Backend::Azure => {
+            let sas_url = required(&conf.sas_url);
+            Ok(Box::new(azure::AzureStorage::from_sas_url(&sas_url)?))
+        }
+        Backend::Local => {
+            let storage_path = required(&conf.storage_path);
+            Ok(Box::new(local::LocalStorage::new(&storage_path)?))
+        }
+        Backend::S3 => {
+            let s3_conf = s3::S3Config {
+                bucket: required(&conf.s3_bucket),
+                region: conf.s3_region.clone(),
+                endpoint: conf.s3_endpoint.clone(),
+                access_key: conf.s3_access_key.clone(),
+                secret_key: conf.s3_secret_key.clone(),
+            };
+            Ok(Box::new(s3::S3Storage::new(&s3_conf)?))
+        }
+    }
+}
@@ -0,0 +1,273 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::Result;
+use async_trait::async_trait;
+use azure_core::error::ErrorKind;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use s3::error::S3Error;
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use super::{Md5Mismatch, Storage};
+use crate::config::Config;
+
+/// How often and how patiently failed storage calls are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How often a call is made in total, 1 disables retries
+    pub attempts: u32,
+    /// The delay before the first retry, doubled for every further one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    /// Up to this percentage of every delay is skipped at random, so clients that failed together
+    /// don't retry together
+    pub jitter_percent: u32,
+}
+
+/// Reads the retry settings.
+pub fn from_config(conf: &Config) -> RetryPolicy {
+    RetryPolicy {
+        attempts: conf.retry_attempts,
+        backoff: Duration::from_millis(conf.retry_backoff_ms),
+        max_backoff: Duration::from_millis(conf.retry_max_backoff_ms),
+        jitter_percent: conf.retry_jitter_percent,
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retrying a call that failed `failures` times.
+    fn delay(&self, failures: u32) -> Duration {
+        let backoff = self
+            .backoff
+            .saturating_mul(1 << std::cmp::min(failures - 1, 16))
+            .min(self.max_backoff);
+
+        backoff.mul_f64(1.0 - fastrand::f64() * self.jitter_percent as f64 / 100.0)
+    }
+}
+
+/// Whether a failed storage call may succeed when made again: timeouts, throttling, server errors,
+/// broken connections and data damaged on the way. Anything else, like a missing blob or denied
+/// access, fails the same way again.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if cause.is::<Md5Mismatch>() {
+            return true;
+        }
+        if let Some(error) = cause.downcast_ref::<azure_core::error::Error>() {
+            return match error.kind() {
+                ErrorKind::HttpResponse {
+                    error_code: Some(code),
+                    ..
+                } if code == "Md5Mismatch" => true,
+                ErrorKind::HttpResponse { status, .. } => is_transient_status(u16::from(*status)),
+                ErrorKind::Io => true,
+                _ => false,
+            };
+        }
+        if let Some(error) = cause.downcast_ref::<S3Error>() {
+            return match error {
+                // The Content-MD5 sent along doesn't match the data S3 received
+                S3Error::HttpFailWithBody(400, body) => body.contains("<Code>BadDigest</Code>"),
+                S3Error::HttpFailWithBody(status, _) => is_transient_status(*status),
+                S3Error::Reqwest(_) | S3Error::Io(_) => true,
+                _ => false,
+            };
+        }
+        if let Some(error) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                error.kind(),
+                std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            );
+        }
+        false
+    })
+}
+
+fn is_transient_status(status: u16) -> bool {
+    matches!(status, 408 | 429 | 500 | 502 | 503 | 504)
+}
+
+/// Whether a storage call failed because the blob it refers to doesn't exist.
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(error) = cause.downcast_ref::<azure_core::error::Error>() {
+            return matches!(
+                error.kind(),
+                ErrorKind::HttpResponse {
+                    status: azure_core::StatusCode::NotFound,
+                    ..
+                }
+            );
+        }
+        if let Some(error) = cause.downcast_ref::<S3Error>() {
+            return matches!(error, S3Error::HttpFailWithBody(404, _));
+        }
+        if let Some(error) = cause.downcast_ref::<std::io::Error>() {
+            return error.kind() == std::io::ErrorKind::NotFound;
+        }
+        false
+    })
+}
+
+/// Retries the calls to another storage that fail with transient errors, see `is_transient`.
+/// Downloads are only retried until the storage responds, a stream failing later fails the read.
+pub struct Retrying {
+    inner: Box<dyn Storage>,
+    policy: RetryPolicy,
+}
+
+impl Retrying {
+    pub fn new(inner: Box<dyn Storage>, policy: RetryPolicy) -> Retrying {
+        Retrying { inner, policy }
+    }
+
+    async fn retry<T, F, Fut>(&self, operation: &str, name: &str, mut call: F) -> Result<T>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T>> + Send,
+    {
+        let mut failures = 0;
+        loop {
+            match call().await {
+                Ok(result) => return Ok(result),
+                Err(e) if failures + 1 < self.policy.attempts && is_transient(&e) => {
+                    failures += 1;
+                    let delay = self.policy.delay(failures);
+                    log::warn!(
+                        "{} {} failed, retrying in {}ms: {:#}",
+                        operation,
+                        name,
+                        delay.as_millis(),
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for Retrying {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.retry("Listing", prefix, || self.inner.list(prefix))
+            .await
+    }
+
+    // Clones of the data share its buffer, so retrying doesn't copy it
+    async fn put(&self, name: &str, data: Bytes) -> Result<()> {
+        self.retry("Uploading", name, || self.inner.put(name, data.clone()))
+            .await
+    }
+
+    async fn put_block(&self, name: &str, block_id: &str, data: Bytes) -> Result<()> {
+        self.retry("Uploading a block of", name, || {
+            self.inner.put_block(name, block_id, data.clone())
+        })
+        .await
+    }
+
+    async fn staged_blocks(&self, name: &str) -> Result<HashMap<String, u64>> {
+        self.retry("Listing the staged blocks of", name, || {
+            self.inner.staged_blocks(name)
+        })
+        .await
+    }
+
+    async fn discard_staged(&self, name: &str) -> Result<()> {
+        self.retry("Discarding the staged blocks of", name, || {
+            self.inner.discard_staged(name)
+        })
+        .await
+    }
+
+    fn concurrent_blocks(&self) -> bool {
+        self.inner.concurrent_blocks()
+    }
+
+    fn can_copy_blocks(&self) -> bool {
+        self.inner.can_copy_blocks()
+    }
+
+    fn max_blocks(&self) -> u64 {
+        self.inner.max_blocks()
+    }
+
+    async fn put_block_from(
+        &self,
+        name: &str,
+        block_id: &str,
+        source: &str,
+        offset: u64,
+        length: u64,
+        content_md5: md5::Digest,
+    ) -> Result<()> {
+        self.retry("Copying a block of", name, || {
+            self.inner
+                .put_block_from(name, block_id, source, offset, length, content_md5)
+        })
+        .await
+    }
+
+    async fn put_block_list(&self, name: &str, block_ids: &[String], checksum: &str) -> Result<()> {
+        self.retry("Committing", name, || {
+            self.inner.put_block_list(name, block_ids, checksum)
+        })
+        .await
+    }
+
+    async fn checksum(&self, name: &str) -> Result<Option<String>> {
+        self.retry("Reading the checksum of", name, || {
+            self.inner.checksum(name)
+        })
+        .await
+    }
+
+    async fn get(&self, name: &str) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        self.retry("Downloading", name, || self.inner.get(name))
+            .await
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let mut attempted = false;
+        self.retry("Deleting", name, || {
+            let retried = std::mem::replace(&mut attempted, true);
+            async move {
+                match self.inner.delete(name).await {
+                    // An earlier attempt may have deleted the blob without its response arriving
+                    Err(e) if retried && is_not_found(&e) => Ok(()),
+                    result => result,
+                }
+            }
+        })
+        .await
+    }
+
+    // Small blobs are read as a whole, so the whole read can be retried
+    async fn get_content(&self, name: &str) -> Result<Vec<u8>> {
+        self.retry("Downloading", name, || self.inner.get_content(name))
+            .await
+    }
+}
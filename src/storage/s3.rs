@@ -0,0 +1,337 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt, TryStreamExt};
+use s3::{creds::Credentials, error::S3Error, serde_types::Part, Bucket, Region};
+use std::{collections::HashMap, sync::Mutex};
+
+use super::{Md5Mismatch, Storage};
+
+/// S3 rejects multipart uploads with parts smaller than 5MiB, except for the last one.
+const MIN_PART_SIZE: usize = 5 << 20;
+
+/// S3 rejects multipart uploads with more parts than this.
+pub const MAX_PARTS: u64 = 10_000;
+
+const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The checksum of the blob `<name>` is stored in the object `<CHECKSUM_PATH><name>`.
+const CHECKSUM_PATH: &str = "/.azure_blob_backup/checksums";
+
+pub struct S3Config {
+    pub bucket: String,
+    pub region: String,
+    /// The endpoint of an S3 compatible service, e.g. `http://localhost:9000` for MinIO. AWS is
+    /// used if this is not set.
+    pub endpoint: Option<String>,
+    /// Credentials are taken from the environment or the aws profile if these are not set.
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+}
+
+/// Stores blobs as objects in an S3 compatible bucket. Blocks are mapped to the parts of a
+/// multipart upload, and the ETag S3 reports for every part is checked against its MD5. Checksums
+/// are stored in objects of their own below `CHECKSUM_PATH`, which are left out of listings, as
+/// metadata can't be added once a multipart upload has started, and objects can't be copied onto
+/// themselves with new metadata beyond 5GB.
+/// Multipart uploads that are not completed are billed until they are aborted, so they are aborted
+/// when they fail and when their blocks are discarded.
+pub struct S3Storage {
+    bucket: Box<Bucket>,
+    uploads: Mutex<HashMap<String, MultipartUpload>>,
+}
+
+/// The state of a multipart upload that has not been completed yet. Blocks are buffered until they
+/// are large enough to form a part, as blocks may be smaller than the minimum part size.
+struct MultipartUpload {
+    upload_id: String,
+    parts: Vec<Part>,
+    pending: Vec<u8>,
+    block_ids: Vec<String>,
+    /// Whether completing the upload was attempted before
+    completing: bool,
+}
+
+impl S3Storage {
+    pub fn new(conf: &S3Config) -> Result<S3Storage> {
+        let credentials = Credentials::new(
+            conf.access_key.as_deref(),
+            conf.secret_key.as_deref(),
+            None,
+            None,
+            None,
+        )?;
+
+        let bucket = match &conf.endpoint {
+            // Compatible services usually don't support virtual host style addressing
+            Some(endpoint) => {
+                let region = Region::Custom {
+                    region: conf.region.clone(),
+                    endpoint: endpoint.clone(),
+                };
+                Bucket::new(&conf.bucket, region, credentials)?.with_path_style()
+            }
+            None => Bucket::new(&conf.bucket, conf.region.parse()?, credentials)?,
+        };
+
+        Ok(S3Storage {
+            bucket,
+            uploads: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes the upload state of `name` out of the map, so no lock is held while talking to S3.
+    fn take_upload(&self, name: &str) -> Option<MultipartUpload> {
+        self.uploads.lock().unwrap().remove(name)
+    }
+
+    fn checksum_name(name: &str) -> String {
+        CHECKSUM_PATH.to_string() + name
+    }
+
+    fn return_upload(&self, name: &str, upload: MultipartUpload) {
+        self.uploads
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), upload);
+    }
+
+    /// Whether the object `name` exists as completed from `parts`. S3 gives objects completed from
+    /// a multipart upload the MD5 of the MD5s of their parts and the number of parts as ETag.
+    async fn is_completed(&self, name: &str, parts: &[Part]) -> Result<bool> {
+        let mut md5s = Vec::new();
+        for part in parts {
+            md5s.extend(hex::decode(part.etag.trim_matches('"'))?);
+        }
+        let expected = format!("{:x}-{}", md5::compute(md5s), parts.len());
+
+        let head = match self.bucket.head_object(name).await {
+            Ok((head, 200)) => head,
+            Ok(_) | Err(S3Error::HttpFailWithBody(404, _)) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(head.e_tag.as_deref().map(|e_tag| e_tag.trim_matches('"')) == Some(expected.as_str()))
+    }
+
+    /// Uploads the pending data as the next part. The data stays pending if the upload fails, so
+    /// it can be tried again.
+    async fn upload_part(&self, name: &str, upload: &mut MultipartUpload) -> Result<()> {
+        let chunk = upload.pending.clone();
+        let md5 = format!("{:x}", md5::compute(&chunk));
+        let part_number = upload.parts.len() as u32 + 1;
+        let part = self
+            .bucket
+            .put_multipart_chunk(chunk, name, part_number, &upload.upload_id, CONTENT_TYPE)
+            .await?;
+
+        // The client sends the MD5 of the part along, which S3 reports back as its ETag
+        if part.etag.trim_matches('"') != md5 {
+            return Err(Md5Mismatch(format!(
+                "S3 reported a different MD5 for part {} of {}",
+                part_number, name
+            ))
+            .into());
+        }
+        upload.parts.push(part);
+        upload.pending.clear();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        // Like the blob client, the S3 client strips the leading slash of object keys
+        let prefix = prefix.strip_prefix('/').unwrap_or(prefix);
+
+        let mut names = Vec::new();
+        for page in self.bucket.list(prefix.to_string(), None).await? {
+            for object in page.contents {
+                let name = "/".to_string() + &object.key;
+                if !name.starts_with(&(CHECKSUM_PATH.to_string() + "/")) {
+                    names.push(name);
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    async fn put(&self, name: &str, data: Bytes) -> Result<()> {
+        self.bucket
+            .put_object_with_content_type(name, &data, CONTENT_TYPE)
+            .await?;
+        // Names committed with a checksum are never stored again with put, so there is no checksum
+        // object to delete, which would cost a request for every put
+        Ok(())
+    }
+
+    async fn put_block(&self, name: &str, block_id: &str, data: Bytes) -> Result<()> {
+        let mut upload = match self.take_upload(name) {
+            Some(upload) => upload,
+            None => {
+                let response = self
+                    .bucket
+                    .initiate_multipart_upload(name, CONTENT_TYPE)
+                    .await?;
+                MultipartUpload {
+                    upload_id: response.upload_id,
+                    parts: Vec::new(),
+                    pending: Vec::new(),
+                    block_ids: Vec::new(),
+                    completing: false,
+                }
+            }
+        };
+
+        upload.pending.extend_from_slice(&data);
+        upload.block_ids.push(block_id.to_string());
+
+        if upload.pending.len() >= MIN_PART_SIZE {
+            if let Err(e) = self.upload_part(name, &mut upload).await {
+                // Forget the block, so staging it again doesn't add it twice
+                upload.pending.truncate(upload.pending.len() - data.len());
+                upload.block_ids.pop();
+                self.return_upload(name, upload);
+                return Err(e);
+            }
+        }
+
+        self.return_upload(name, upload);
+        Ok(())
+    }
+
+    // Blocks are appended to the pending part in the order they arrive
+    fn concurrent_blocks(&self) -> bool {
+        false
+    }
+
+    // Every part holds at least one block, so there are never more parts than blocks
+    fn max_blocks(&self) -> u64 {
+        MAX_PARTS
+    }
+
+    async fn put_block_list(&self, name: &str, block_ids: &[String], checksum: &str) -> Result<()> {
+        // The checksum goes first, so a completed object always has it
+        let store_checksum = || {
+            self.bucket.put_object_with_content_type(
+                Self::checksum_name(name),
+                checksum.as_bytes(),
+                "text/plain",
+            )
+        };
+
+        let mut upload = match self.take_upload(name) {
+            Some(upload) => upload,
+            None if block_ids.is_empty() => {
+                store_checksum().await?;
+                self.bucket
+                    .put_object_with_content_type(name, &[], CONTENT_TYPE)
+                    .await?;
+                return Ok(());
+            }
+            None => return Err(anyhow!("No blocks were staged for {}", name)),
+        };
+
+        // Parts can't be reordered after the fact, so blocks have to be committed as staged
+        if upload.block_ids != block_ids {
+            self.bucket.abort_upload(name, &upload.upload_id).await?;
+            return Err(anyhow!(
+                "The block list of {} does not match the staged blocks",
+                name
+            ));
+        }
+
+        // The upload is kept on failure, so the commit can be tried again
+        if !upload.pending.is_empty() || upload.parts.is_empty() {
+            if let Err(e) = self.upload_part(name, &mut upload).await {
+                self.return_upload(name, upload);
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = store_checksum().await {
+            self.return_upload(name, upload);
+            return Err(e.into());
+        }
+        let retried = std::mem::replace(&mut upload.completing, true);
+        let completed = self
+            .bucket
+            .complete_multipart_upload(name, &upload.upload_id, upload.parts.clone())
+            .await;
+        match completed {
+            Ok(_) => Ok(()),
+            // An earlier attempt may have completed the upload without its response arriving
+            Err(S3Error::HttpFailWithBody(404, body))
+                if retried && body.contains("NoSuchUpload") =>
+            {
+                if self.is_completed(name, &upload.parts).await? {
+                    return Ok(());
+                }
+                Err(anyhow!("The multipart upload of {} vanished", name))
+            }
+            Err(e) => {
+                self.return_upload(name, upload);
+                Err(e.into())
+            }
+        }
+    }
+
+    // Parts can't be listed by block id, so uploads are never resumed. The parts of the in-memory
+    // upload and any upload an interrupted run left open are aborted.
+    async fn discard_staged(&self, name: &str) -> Result<()> {
+        if let Some(upload) = self.take_upload(name) {
+            self.bucket.abort_upload(name, &upload.upload_id).await?;
+        }
+
+        let key = name.strip_prefix('/').unwrap_or(name);
+        for page in self.bucket.list_multiparts_uploads(Some(key), None).await? {
+            for open in page.uploads.iter().filter(|open| open.key == key) {
+                self.bucket.abort_upload(&open.key, &open.id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn checksum(&self, name: &str) -> Result<Option<String>> {
+        match self.bucket.get_object(Self::checksum_name(name)).await {
+            Ok(response) => Ok(Some(String::from_utf8(response.to_vec())?)),
+            // Objects stored without a checksum have no checksum object
+            Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get(&self, name: &str) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        let response = self.bucket.get_object_stream(name).await?;
+
+        let chunks = response
+            .bytes
+            .map_ok(|bytes| bytes.to_vec())
+            .map_err(anyhow::Error::from);
+
+        Ok(chunks.boxed())
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        self.bucket.delete_object(name).await?;
+        self.bucket.delete_object(Self::checksum_name(name)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Error};
+use chrono::{Local, NaiveTime};
+use serde::Deserialize;
+use std::{sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+// Uploads are limited to a rate shared by all files and blocks in flight. Before a block is sent,
+// it reserves the time it takes to send it at the current rate, following the blocks reserved
+// before it, and waits for its turn. The rate can depend on the time of day.
+
+/// A limit that applies between two times of the day, in local time. Configured like
+/// `08:00-18:00 1024`, the limit is in KiB per second.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+    /// Bytes per second, None for no limit
+    limit: Option<u64>,
+}
+
+impl Window {
+    /// Windows ending before they start span midnight.
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start < self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Limits the rate data is uploaded at.
+pub struct Throttle {
+    /// Bytes per second outside of the scheduled windows, None for no limit
+    limit: Option<u64>,
+    schedule: Vec<Window>,
+    /// When the data reserved so far has been sent
+    next: Mutex<Instant>,
+}
+
+/// Reads the upload limits. Returns None if uploads are not limited at all.
+pub fn from_config(conf: &Config) -> Option<Throttle> {
+    if conf.upload_limit_kib == 0 && conf.upload_limit_schedule.is_empty() {
+        return None;
+    }
+    Some(Throttle {
+        limit: kib_per_second(conf.upload_limit_kib),
+        schedule: conf.upload_limit_schedule.clone(),
+        next: Mutex::new(Instant::now()),
+    })
+}
+
+/// 0 stands for no limit.
+fn kib_per_second(kib: u64) -> Option<u64> {
+    (kib > 0).then_some(kib * 1024)
+}
+
+impl TryFrom<String> for Window {
+    type Error = Error;
+
+    fn try_from(raw: String) -> Result<Window, Error> {
+        let malformed = || anyhow!("the entry {} has to look like 08:00-18:00 1024", raw);
+
+        let (times, limit) = raw.trim().split_once(' ').ok_or_else(malformed)?;
+        let (start, end) = times.split_once('-').ok_or_else(malformed)?;
+        let time = |raw: &str| NaiveTime::parse_from_str(raw, "%H:%M").map_err(|_| malformed());
+
+        Ok(Window {
+            start: time(start)?,
+            end: time(end)?,
+            limit: kib_per_second(limit.trim().parse().map_err(|_| malformed())?),
+        })
+    }
+}
+
+impl Throttle {
+    /// The limit in bytes per second at `time`. The first scheduled window containing it applies,
+    /// otherwise the global limit.
+    pub fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        match self.schedule.iter().find(|window| window.contains(time)) {
+            Some(window) => window.limit,
+            None => self.limit,
+        }
+    }
+
+    /// Waits until `bytes` may be sent at the current limit.
+    pub async fn acquire(&self, bytes: u64) {
+        let limit = match self.limit_at(Local::now().time()) {
+            Some(limit) => limit,
+            None => return,
+        };
+
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = std::cmp::max(*next, Instant::now());
+            *next = start + Duration::from_secs_f64(bytes as f64 / limit as f64);
+            start
+        };
+        tokio::time::sleep_until(start).await;
+    }
+}
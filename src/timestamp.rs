@@ -0,0 +1,62 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+
+/// Parses a point in time given on the command line into seconds since the unix epoch.
+/// Accepts raw unix timestamps, RFC 3339 and `YYYY-MM-DD[ HH:MM[:SS]]` in local time.
+pub fn parse(raw: &str) -> Result<u64> {
+    if let Ok(secs) = raw.parse::<u64>() {
+        return Ok(secs);
+    }
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(raw) {
+        return to_unix(time.timestamp(), raw);
+    }
+
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(raw, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(raw, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        });
+
+    match naive {
+        Some(naive) => match Local.from_local_datetime(&naive).earliest() {
+            Some(time) => to_unix(time.timestamp(), raw),
+            None => Err(anyhow!("{} does not exist in the local time zone", raw)),
+        },
+        None => Err(anyhow!(
+            "Unable to parse {} as a point in time. Expected a unix timestamp, RFC 3339 or YYYY-MM-DD[ HH:MM[:SS]]",
+            raw
+        )),
+    }
+}
+
+fn to_unix(secs: i64, raw: &str) -> Result<u64> {
+    u64::try_from(secs).map_err(|_| anyhow!("{} lies before the unix epoch", raw))
+}
+
+/// Formats a unix timestamp as a human readable date in local time.
+pub fn format(secs: u64) -> String {
+    match Local.timestamp_opt(secs as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => secs.to_string(),
+    }
+}
@@ -0,0 +1,254 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::{anyhow, Result};
+use futures::stream::StreamExt;
+use std::{
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::BuildHasher,
+    io::Write,
+};
+
+use crate::content_hash::ContentHasher;
+use crate::crypto::MasterKey;
+use crate::dedup;
+use crate::index::{blob_name, split_blob_name, FileType, Version};
+use crate::restore;
+use crate::storage::Storage;
+
+pub struct Options {
+    /// The path to verify, in the form used as index keys
+    pub path: String,
+    /// The percentage of versions to download, between 0 (exclusive) and 100
+    pub sample: f64,
+}
+
+/// The outcome of a verification run.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The number of versions that were downloaded and matched their checksums
+    pub verified: usize,
+    /// Versions whose content could not be decoded or doesn't match its size or checksums, with
+    /// the reason
+    pub corrupt: Vec<(String, String)>,
+    /// Versions referencing chunks that are not stored
+    pub missing: Vec<String>,
+    /// Blob names that are not of the form `path/version`
+    pub unparseable: Vec<String>,
+}
+
+impl Report {
+    pub fn has_problems(&self) -> bool {
+        !self.corrupt.is_empty() || !self.missing.is_empty() || !self.unparseable.is_empty()
+    }
+}
+
+/// Downloads the versions below `options.path` and checks them against the checksums they were
+/// uploaded with. Problems are collected in the report, errors are only returned if the backup
+/// can't be read at all.
+pub async fn run(
+    storage: &dyn Storage,
+    encryption: Option<&MasterKey>,
+    options: &Options,
+) -> Result<Report> {
+    let mut report = Report::default();
+
+    log::info!("Begin listing of the remote storage");
+    let prefix = options.path.trim_end_matches('/');
+    let mut versions = Vec::new();
+    for name in storage.list(prefix).await? {
+        let (path, version) = match split_blob_name(&name) {
+            Ok(split) => split,
+            Err(_) => {
+                report.unparseable.push(name);
+                continue;
+            }
+        };
+
+        // The same filters as for the remote index, see index.rs
+        if dedup::is_reserved(path)
+            || !prefix.is_empty()
+                && path != prefix
+                && !path.starts_with(&(prefix.to_string() + "/"))
+        {
+            continue;
+        }
+
+        match Version::try_from(version) {
+            Ok(version) => versions.push((path.to_string(), version)),
+            Err(_) => report.unparseable.push(name),
+        }
+    }
+
+    // Only regular files and symlinks have content
+    versions
+        .retain(|(_, version)| matches!(version.file_type, FileType::Regular | FileType::Symlink));
+    if options.sample < 100.0 {
+        // Seeded randomly, so every run checks a different sample
+        let state = RandomState::new();
+        let threshold = (options.sample / 100.0 * u64::MAX as f64) as u64;
+        versions.retain(|(path, version)| state.hash_one(blob_name(path, version)) <= threshold);
+    }
+    versions.sort_by(|a, b| a.0.cmp(&b.0));
+    log::info!("Verifying {} versions", versions.len());
+
+    if encryption.is_none() && versions.iter().any(|(_, version)| version.encrypted) {
+        return Err(anyhow!(
+            "The backup is encrypted, but no encryption key is configured"
+        ));
+    }
+
+    let chunks: HashSet<String> = if versions.iter().any(|(_, version)| version.deduplicated) {
+        dedup::list_chunks(storage).await?.into_values().collect()
+    } else {
+        HashSet::new()
+    };
+
+    // Chunks are shared between versions, so each one is only checked against its id once
+    let mut checked_chunks: HashMap<String, Result<(), String>> = HashMap::new();
+
+    let mut processed: usize = 0;
+    let total_versions = versions.len();
+    for (path, version) in &versions {
+        let name = blob_name(path, version);
+
+        processed += 1;
+        print!("\r{processed} / {total_versions}");
+
+        // If the flush fails its not the end of the world.
+        #[allow(unused_must_use)]
+        {
+            std::io::stdout().flush();
+        }
+
+        let manifest = if version.deduplicated {
+            match dedup::read_manifest(storage, path, version, encryption).await {
+                Ok(manifest) if manifest.iter().any(|chunk| !chunks.contains(chunk)) => {
+                    log::error!("{} references chunks that are not stored", name);
+                    report.missing.push(name);
+                    continue;
+                }
+                Ok(manifest) => Some(manifest),
+                Err(e) => {
+                    log::error!("{} is corrupt: {}", name, e);
+                    report.corrupt.push((name, e.to_string()));
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+
+        let checked = verify_version(
+            storage,
+            encryption,
+            path,
+            version,
+            manifest.as_deref(),
+            &mut checked_chunks,
+        )
+        .await;
+        match checked {
+            Ok(()) => report.verified += 1,
+            Err(e) => {
+                log::error!("{} is corrupt: {}", name, e);
+                report.corrupt.push((name, e.to_string()));
+            }
+        }
+    }
+    println!();
+
+    Ok(report)
+}
+
+/// Downloads the content of `version` and compares it with its size and checksums. The chunks of
+/// deduplicated versions, listed in their `manifest`, are checked against their ids on the way.
+async fn verify_version(
+    storage: &dyn Storage,
+    encryption: Option<&MasterKey>,
+    path: &str,
+    version: &Version,
+    manifest: Option<&[String]>,
+    checked_chunks: &mut HashMap<String, Result<(), String>>,
+) -> Result<()> {
+    // Checksums of encrypted versions are keyed, see content_hash.rs
+    let key = encryption.filter(|_| version.encrypted);
+    let mut hasher = ContentHasher::new(key).hasher();
+    let mut size: u64 = 0;
+
+    if let Some(manifest) = manifest {
+        for chunk in manifest {
+            // Chunks known to be damaged aren't downloaded again
+            if let Some(Err(e)) = checked_chunks.get(chunk) {
+                return Err(anyhow!("{}", e));
+            }
+            let content = dedup::read_chunk(storage, chunk, encryption)
+                .await
+                .and_then(|content| {
+                    if !checked_chunks.contains_key(chunk) {
+                        dedup::check_chunk(chunk, &content, encryption)?;
+                    }
+                    Ok(content)
+                });
+            let content = match content {
+                Ok(content) => content,
+                Err(e) => {
+                    checked_chunks.insert(chunk.clone(), Err(e.to_string()));
+                    return Err(e);
+                }
+            };
+            checked_chunks.insert(chunk.clone(), Ok(()));
+
+            hasher.update(&content);
+            size += content.len() as u64;
+        }
+    } else if version.file_type == FileType::Symlink
+        || version.size > 0
+        || version.encrypted
+        || version.compressed
+    {
+        // Range requests on empty blobs are rejected, see restore.rs. Encoded files are never
+        // empty, they carry at least a header or a zstd frame.
+        let mut chunks = restore::content(version, path, storage, encryption).await?;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+        }
+    }
+    let hash = hasher.finalize();
+
+    if version.file_type == FileType::Regular && size != version.size {
+        return Err(anyhow!(
+            "the content is {} bytes long, but the version has {}",
+            size,
+            version.size
+        ));
+    }
+
+    let stored = storage.checksum(&blob_name(path, version)).await?;
+    for expected in stored.iter().chain(version.content_hash.iter()) {
+        if *expected != hash {
+            return Err(anyhow!(
+                "the content hashes to {}, but {} was expected",
+                hash,
+                expected
+            ));
+        }
+    }
+
+    Ok(())
+}
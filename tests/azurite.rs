@@ -0,0 +1,215 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup,
+    config::Config,
+    index::{blob_name, FileType},
+    restore,
+    storage::{self, Storage},
+};
+use azure_storage::{
+    clients::{EMULATOR_ACCOUNT, EMULATOR_ACCOUNT_KEY},
+    shared_access_signature::service_sas::BlobSasPermissions,
+    CloudLocation, StorageCredentials,
+};
+use azure_storage_blobs::prelude::*;
+use common::{config, large_content, restore_options, versions, Tree, DAY, HOUR, START};
+
+/// The blob endpoint of the emulator including the account, e.g.
+/// `http://127.0.0.1:10000/devstoreaccount1`. The tests are ignored unless run with `--ignored`,
+/// and then require it to be set.
+const ENDPOINT_VAR: &str = "AZURITE_BLOB_ENDPOINT";
+
+/// Creates an empty container in the emulator and returns a config backing up `root` into it.
+async fn azurite_config(test: &str, root: &str, extra: &str) -> Config {
+    let endpoint = std::env::var(ENDPOINT_VAR)
+        .unwrap_or_else(|_| panic!("{ENDPOINT_VAR} has to be set to run {test}"));
+
+    // Every test gets its own container, so tests can run in parallel and reruns start empty
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let location = CloudLocation::Custom {
+        uri: endpoint,
+        credentials: StorageCredentials::access_key(EMULATOR_ACCOUNT, EMULATOR_ACCOUNT_KEY),
+    };
+    let container = ClientBuilder::with_location(location).container_client(format!(
+        "{}-{}",
+        test.replace('_', "-"),
+        nanos
+    ));
+    container.create().await.unwrap();
+
+    let permissions = BlobSasPermissions {
+        read: true,
+        write: true,
+        delete: true,
+        list: true,
+        ..Default::default()
+    };
+    let expiry = time::OffsetDateTime::now_utc() + time::Duration::hours(1);
+    let sas = container
+        .shared_access_signature(permissions, expiry)
+        .unwrap();
+    let sas_url = container.generate_signed_container_url(&sas).unwrap();
+
+    config(root, &format!("sas_url: \"{sas_url}\"\n{extra}"))
+}
+
+async fn names(storage: &dyn Storage) -> Vec<String> {
+    let mut names = storage.list("/").await.unwrap();
+    names.sort();
+    names
+}
+
+#[tokio::test]
+#[ignore = "needs Azurite, see AZURITE_BLOB_ENDPOINT"]
+async fn backup_cycle() {
+    let tree = Tree::new();
+    let conf = azurite_config(
+        "backup_cycle",
+        &tree.root(),
+        "num_daily: 2\nnum_weekly: 0\nnum_monthly: 0\n",
+    )
+    .await;
+    let storage = storage::from_config(&conf).unwrap();
+    let storage = storage.as_ref();
+
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/large.bin", &large_content(), START - DAY);
+    tree.symlink("/link", "a.txt");
+
+    // Everything is uploaded on the first run
+    backup::run(&conf, storage, START).await.unwrap();
+    for path in ["/", "/a.txt", "/dir", "/dir/large.bin", "/link"] {
+        assert_eq!(versions(storage, path).await.len(), 1, "{path}");
+    }
+    let large = &versions(storage, "/dir/large.bin").await[0];
+    let content = storage
+        .get_content(&blob_name("/dir/large.bin", large))
+        .await
+        .unwrap();
+    assert_eq!(content, large_content().into_bytes());
+    let link = &versions(storage, "/link").await[0];
+    let content = storage
+        .get_content(&blob_name("/link", link))
+        .await
+        .unwrap();
+    assert_eq!(content, b"a.txt");
+
+    // Nothing changed, nothing is uploaded
+    let before = names(storage).await;
+    backup::run(&conf, storage, START + DAY).await.unwrap();
+    assert_eq!(names(storage).await, before);
+
+    // A modified file gets a second version
+    tree.write("/a.txt", "hello again", START + DAY + HOUR);
+    backup::run(&conf, storage, START + 2 * DAY).await.unwrap();
+    let modified = versions(storage, "/a.txt").await;
+    assert_eq!(modified.len(), 2);
+    let content = storage
+        .get_content(&blob_name("/a.txt", &modified[1]))
+        .await
+        .unwrap();
+    assert_eq!(content, b"hello again");
+
+    // A deleted file gets a deletion marker, and the first version of a.txt is older than the
+    // two retained days
+    tree.remove("/dir/large.bin");
+    backup::run(&conf, storage, START + 3 * DAY).await.unwrap();
+    let deleted = versions(storage, "/dir/large.bin").await;
+    assert_eq!(deleted.len(), 2);
+    assert_eq!(deleted[1].file_type, FileType::Deleted);
+    let pruned = versions(storage, "/a.txt").await;
+    assert_eq!(pruned.len(), 1);
+    assert_eq!(pruned[0].upload_time, START + 2 * DAY);
+
+    // Once only the deletion marker would be retained the file disappears from the container
+    backup::run(&conf, storage, START + 4 * DAY).await.unwrap();
+    assert!(versions(storage, "/dir/large.bin").await.is_empty());
+    assert!(names(storage)
+        .await
+        .iter()
+        .all(|name| !name.contains("large.bin")));
+}
+
+#[tokio::test]
+#[ignore = "needs Azurite, see AZURITE_BLOB_ENDPOINT"]
+async fn restore_from_azurite() {
+    let tree = Tree::new();
+    let conf = azurite_config("restore_from_azurite", &tree.root(), "").await;
+    let storage = storage::from_config(&conf).unwrap();
+
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/large.bin", &large_content(), START - DAY);
+    backup::run(&conf, storage.as_ref(), START).await.unwrap();
+
+    let target = Tree::new();
+    let options = restore_options(&target);
+    restore::run(storage.as_ref(), None, &options)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(target.path("/a.txt")).unwrap(),
+        "hello"
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.path("/dir/large.bin")).unwrap(),
+        large_content()
+    );
+
+    // The checksum is stored as blob metadata
+    let large = &versions(storage.as_ref(), "/dir/large.bin").await[0];
+    let checksum = storage
+        .checksum(&blob_name("/dir/large.bin", large))
+        .await
+        .unwrap();
+    assert_eq!(checksum, Some(sha256::digest(large_content())));
+}
+
+#[tokio::test]
+#[ignore = "needs Azurite, see AZURITE_BLOB_ENDPOINT"]
+async fn blocks_are_copied_within_azurite() {
+    let tree = Tree::new();
+    let conf = azurite_config(
+        "blocks_are_copied_within_azurite",
+        &tree.root(),
+        "block_incremental: true\n",
+    )
+    .await;
+    let storage = storage::from_config(&conf).unwrap();
+    let storage = storage.as_ref();
+
+    tree.write("/large.bin", &large_content(), START - DAY);
+    backup::run(&conf, storage, START).await.unwrap();
+
+    let modified = "modified".to_string() + &large_content()[8..];
+    tree.write("/large.bin", &modified, START);
+    backup::run(&conf, storage, START + DAY).await.unwrap();
+
+    let latest = &versions(storage, "/large.bin").await[1];
+    let content = storage
+        .get_content(&blob_name("/large.bin", latest))
+        .await
+        .unwrap();
+    assert!(content == modified.into_bytes());
+}
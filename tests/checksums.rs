@@ -0,0 +1,108 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup,
+    index::blob_name,
+    storage::{self, memory::MemoryStorage, Storage},
+};
+use common::{config, versions, Tree, DAY, START};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+/// sha256("hello")
+const HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+async fn checksum(storage: &dyn Storage, path: &str) -> Option<String> {
+    let version = &versions(storage, path).await[0];
+    storage.checksum(&blob_name(path, version)).await.unwrap()
+}
+
+#[tokio::test]
+async fn files_are_committed_with_their_checksum() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/compressed.log", "hello", START - DAY);
+    tree.symlink("/link", "a.txt");
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "compression: true\n");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    // The checksum covers the content of the file, not the encoded blob
+    assert_eq!(checksum(&storage, "/a.txt").await.as_deref(), Some(HELLO));
+    assert_eq!(
+        checksum(&storage, "/compressed.log").await.as_deref(),
+        Some(HELLO)
+    );
+    // Links are checked against their target
+    assert_eq!(
+        checksum(&storage, "/link").await,
+        Some(sha256::digest("a.txt"))
+    );
+    assert!(checksum(&storage, "/").await.is_none());
+}
+
+#[tokio::test]
+async fn checksums_are_keyed_with_encryption() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), &format!("encryption_key: \"{KEY}\"\n"));
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    let checksum = checksum(&storage, "/a.txt").await.unwrap();
+    assert_eq!(checksum.len(), 64);
+    assert_ne!(checksum, HELLO);
+}
+
+#[tokio::test]
+async fn the_local_backend_keeps_checksums_with_their_blobs() {
+    let tree = Tree::new();
+    tree.write("/dir/a.txt", "hello", START - DAY);
+
+    let storage_dir = Tree::new();
+    let conf = config(
+        &tree.root(),
+        &format!("backend: local\nstorage_path: {}\n", storage_dir.root()),
+    );
+    let storage = storage::from_config(&conf).unwrap();
+    let storage = storage.as_ref();
+    backup::run(&conf, storage, START).await.unwrap();
+    assert_eq!(
+        checksum(storage, "/dir/a.txt").await.as_deref(),
+        Some(HELLO)
+    );
+
+    // Checksums are not listed as blobs, and go away with their blob
+    assert!(storage
+        .list("/")
+        .await
+        .unwrap()
+        .iter()
+        .all(|name| !name.contains("checksums")));
+    tree.remove("/dir/a.txt");
+    backup::run(&conf, storage, START + DAY).await.unwrap();
+    backup::run(&conf, storage, START + 400 * DAY)
+        .await
+        .unwrap();
+    assert!(versions(storage, "/dir/a.txt").await.is_empty());
+    assert!(!storage_dir.path("/checksums/dir").exists());
+}
@@ -0,0 +1,123 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    cli::{self, Command, Invocation, DEFAULT_CONFIG_PATH},
+    index::Selection,
+};
+use common::START;
+
+fn parse(args: &str) -> anyhow::Result<Invocation> {
+    let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+    cli::parse(&args)
+}
+
+#[test]
+fn the_config_path_and_command_are_optional() {
+    let invocation = parse("").unwrap();
+    assert_eq!(invocation.config_path, DEFAULT_CONFIG_PATH);
+    assert!(matches!(invocation.command, Command::Backup));
+
+    let invocation = parse("/tmp/config.yaml").unwrap();
+    assert_eq!(invocation.config_path, "/tmp/config.yaml");
+    assert!(matches!(invocation.command, Command::Backup));
+
+    let invocation = parse("/tmp/config.yaml backup").unwrap();
+    assert!(matches!(invocation.command, Command::Backup));
+    assert!(matches!(parse("--help").unwrap().command, Command::Help));
+}
+
+#[test]
+fn restore_options_are_parsed() {
+    let Command::Restore(options) = parse("restore /mnt/restore").unwrap().command else {
+        panic!("not a restore");
+    };
+    assert_eq!(options.target, "/mnt/restore");
+    assert_eq!(options.path, "/");
+    assert!(matches!(options.selection, Selection::Latest));
+
+    let invocation =
+        parse("/tmp/config.yaml restore --path etc/nginx/ /mnt/restore --at 2023-11-14T22:13:20Z")
+            .unwrap();
+    assert_eq!(invocation.config_path, "/tmp/config.yaml");
+    let Command::Restore(options) = invocation.command else {
+        panic!("not a restore");
+    };
+    assert_eq!(options.target, "/mnt/restore");
+    assert_eq!(options.path, "/etc/nginx");
+    assert!(matches!(options.selection, Selection::At(START)));
+
+    let Command::Restore(options) = parse("restore /mnt/restore --version -2 --uid-map *:1000")
+        .unwrap()
+        .command
+    else {
+        panic!("not a restore");
+    };
+    assert!(matches!(options.selection, Selection::Index(-2)));
+}
+
+#[test]
+fn list_versions_options_are_parsed() {
+    let Command::ListVersions(options) = parse("list-versions /a.txt").unwrap().command else {
+        panic!("not list-versions");
+    };
+    assert_eq!(options.path, "/a.txt");
+    assert!(!options.children);
+
+    let Command::ListVersions(options) = parse("list-versions dir/ --children --at 1700000000")
+        .unwrap()
+        .command
+    else {
+        panic!("not list-versions");
+    };
+    assert_eq!(options.path, "/dir");
+    assert!(options.children);
+    assert!(matches!(options.selection, Selection::At(START)));
+}
+
+#[test]
+fn verify_options_are_parsed() {
+    let Command::Verify(options) = parse("verify --path /etc --sample 5").unwrap().command else {
+        panic!("not verify");
+    };
+    assert_eq!(options.path, "/etc");
+    assert_eq!(options.sample, 5.0);
+}
+
+#[test]
+fn invalid_arguments_are_rejected() {
+    for args in [
+        "backup extra",
+        "/tmp/config.yaml unknown",
+        "restore",
+        "restore /mnt/restore /mnt/other",
+        "restore /mnt/restore --path",
+        "restore /mnt/restore --at yesterday",
+        "restore /mnt/restore --version latest",
+        "restore /mnt/restore --at 1700000000 --version 1",
+        "restore /mnt/restore --uid-map 1000",
+        "list-versions",
+        "list-versions /a.txt --version 1",
+        "verify --sample 0",
+        "verify --sample 101",
+        "verify --unknown",
+    ] {
+        assert!(parse(args).is_err(), "{args}");
+    }
+}
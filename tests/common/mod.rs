@@ -0,0 +1,341 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use anyhow::Result;
+use async_trait::async_trait;
+use azure_blob_backup::{
+    config::{self, Config},
+    index::{create_remote_index, Selection, Version},
+    restore,
+    storage::{memory::MemoryStorage, Storage},
+};
+use bytes::Bytes;
+use filetime::FileTime;
+use futures::stream::BoxStream;
+use std::{collections::HashMap, path::PathBuf};
+
+pub const HOUR: u64 = 60 * 60;
+pub const DAY: u64 = 24 * HOUR;
+
+/// 2023-11-14 22:13:20 UTC, the simulated time of the first run.
+pub const START: u64 = 1_700_000_000;
+
+/// Larger than two blocks and two S3 parts and not a multiple of either, so uploads of several
+/// blocks or parts with a partial last one are covered.
+pub const LARGE_FILE_SIZE: usize = (11 << 20) + 123;
+
+/// A temporary directory tree that is backed up by the tests.
+pub struct Tree {
+    dir: tempfile::TempDir,
+}
+
+impl Tree {
+    pub fn new() -> Tree {
+        Tree {
+            dir: tempfile::tempdir().unwrap(),
+        }
+    }
+
+    pub fn root(&self) -> String {
+        self.dir.path().to_str().unwrap().to_string()
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.path().join(path.trim_start_matches('/'))
+    }
+
+    /// Writes `content` to `path`, and sets its modification time, as tests don't run in
+    /// simulated time.
+    pub fn write(&self, path: &str, content: &str, mod_time: u64) {
+        let full = self.path(path);
+        std::fs::create_dir_all(full.parent().unwrap()).unwrap();
+        std::fs::write(&full, content).unwrap();
+        filetime::set_file_mtime(&full, FileTime::from_unix_time(mod_time as i64, 0)).unwrap();
+    }
+
+    pub fn symlink(&self, path: &str, target: &str) {
+        std::os::unix::fs::symlink(target, self.path(path)).unwrap();
+    }
+
+    pub fn remove(&self, path: &str) {
+        std::fs::remove_file(self.path(path)).unwrap();
+    }
+}
+
+/// A config backing up `root` with the default retention, extended by `extra` yaml lines.
+pub fn config(root: &str, extra: &str) -> Config {
+    try_config(root, extra).unwrap()
+}
+
+/// Like `config`, but returns the error of an invalid config.
+pub fn try_config(root: &str, extra: &str) -> anyhow::Result<Config> {
+    let mut raw = format!("local_root: {root}\n");
+    // Keys may only be set once, so `extra` replaces the defaults
+    for default in [
+        "num_daily: 7",
+        "num_weekly: 4",
+        "num_monthly: 12",
+        "min_update_age: 82800",
+        "sas_url: \"https://account.blob.core.windows.net/backup?sig=test\"",
+    ] {
+        let (key, _) = default.split_once(':').unwrap();
+        if !extra
+            .lines()
+            .any(|line| line.starts_with(&format!("{key}:")))
+        {
+            raw += default;
+            raw.push('\n');
+        }
+    }
+    raw += extra;
+    config::parse(&raw)
+}
+
+/// `LARGE_FILE_SIZE` bytes of text.
+pub fn large_content() -> String {
+    (0..LARGE_FILE_SIZE)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect()
+}
+
+/// Options restoring the latest version of everything into `target`.
+pub fn restore_options(target: &Tree) -> restore::Options {
+    restore::Options {
+        target: target.root(),
+        path: "/".to_string(),
+        selection: Selection::Latest,
+        uid_map: restore::IdMap::default(),
+        gid_map: restore::IdMap::default(),
+    }
+}
+
+/// All versions of `path` stored in `storage`, ordered by upload time.
+pub async fn versions(storage: &dyn Storage, path: &str) -> Vec<Version> {
+    let index = create_remote_index(storage, path).await.unwrap();
+    let mut versions = index.files.get(path).cloned().unwrap_or_default();
+    versions.sort_by_key(|version| version.upload_time);
+    versions
+}
+
+/// Intercepts the calls to a `Hooked` storage. Every call is forwarded to the wrapped storage
+/// unless overridden, so tests only implement the calls they are interested in.
+#[async_trait]
+pub trait Hooks: Send + Sync {
+    /// Runs before every call that goes to the storage, with the name of the method and the blob
+    /// name or prefix it is called with. An error fails the call without forwarding it.
+    fn before(&self, _call: &str, _name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn list(&self, inner: &dyn Storage, prefix: &str) -> Result<Vec<String>> {
+        inner.list(prefix).await
+    }
+
+    async fn put(&self, inner: &dyn Storage, name: &str, data: Bytes) -> Result<()> {
+        inner.put(name, data).await
+    }
+
+    async fn put_block(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+        block_id: &str,
+        data: Bytes,
+    ) -> Result<()> {
+        inner.put_block(name, block_id, data).await
+    }
+
+    async fn staged_blocks(&self, inner: &dyn Storage, name: &str) -> Result<HashMap<String, u64>> {
+        inner.staged_blocks(name).await
+    }
+
+    async fn discard_staged(&self, inner: &dyn Storage, name: &str) -> Result<()> {
+        inner.discard_staged(name).await
+    }
+
+    fn concurrent_blocks(&self, inner: &dyn Storage) -> bool {
+        inner.concurrent_blocks()
+    }
+
+    fn can_copy_blocks(&self, inner: &dyn Storage) -> bool {
+        inner.can_copy_blocks()
+    }
+
+    fn max_blocks(&self, inner: &dyn Storage) -> u64 {
+        inner.max_blocks()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn put_block_from(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+        block_id: &str,
+        source: &str,
+        offset: u64,
+        length: u64,
+        content_md5: md5::Digest,
+    ) -> Result<()> {
+        inner
+            .put_block_from(name, block_id, source, offset, length, content_md5)
+            .await
+    }
+
+    async fn put_block_list(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+        block_ids: &[String],
+        checksum: &str,
+    ) -> Result<()> {
+        inner.put_block_list(name, block_ids, checksum).await
+    }
+
+    async fn checksum(&self, inner: &dyn Storage, name: &str) -> Result<Option<String>> {
+        inner.checksum(name).await
+    }
+
+    async fn get(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+    ) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        inner.get(name).await
+    }
+
+    async fn delete(&self, inner: &dyn Storage, name: &str) -> Result<()> {
+        inner.delete(name).await
+    }
+}
+
+/// A storage a `Hooked` storage can wrap.
+pub trait Inner: Send + Sync {
+    fn storage(&self) -> &dyn Storage;
+}
+
+impl Inner for MemoryStorage {
+    fn storage(&self) -> &dyn Storage {
+        self
+    }
+}
+
+impl Inner for &dyn Storage {
+    fn storage(&self) -> &dyn Storage {
+        *self
+    }
+}
+
+/// Passes every call to `inner` through `hooks`. Wraps a fresh memory storage by default.
+pub struct Hooked<H, S = MemoryStorage> {
+    pub inner: S,
+    pub hooks: H,
+}
+
+impl<H: Hooks> Hooked<H> {
+    pub fn new(hooks: H) -> Hooked<H> {
+        Hooked {
+            inner: MemoryStorage::new(),
+            hooks,
+        }
+    }
+}
+
+#[async_trait]
+impl<H: Hooks, S: Inner> Storage for Hooked<H, S> {
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.hooks.before("list", prefix)?;
+        self.hooks.list(self.inner.storage(), prefix).await
+    }
+
+    async fn put(&self, name: &str, data: Bytes) -> Result<()> {
+        self.hooks.before("put", name)?;
+        self.hooks.put(self.inner.storage(), name, data).await
+    }
+
+    async fn put_block(&self, name: &str, block_id: &str, data: Bytes) -> Result<()> {
+        self.hooks.before("put_block", name)?;
+        self.hooks
+            .put_block(self.inner.storage(), name, block_id, data)
+            .await
+    }
+
+    async fn staged_blocks(&self, name: &str) -> Result<HashMap<String, u64>> {
+        self.hooks.before("staged_blocks", name)?;
+        self.hooks.staged_blocks(self.inner.storage(), name).await
+    }
+
+    async fn discard_staged(&self, name: &str) -> Result<()> {
+        self.hooks.before("discard_staged", name)?;
+        self.hooks.discard_staged(self.inner.storage(), name).await
+    }
+
+    fn concurrent_blocks(&self) -> bool {
+        self.hooks.concurrent_blocks(self.inner.storage())
+    }
+
+    fn can_copy_blocks(&self) -> bool {
+        self.hooks.can_copy_blocks(self.inner.storage())
+    }
+
+    fn max_blocks(&self) -> u64 {
+        self.hooks.max_blocks(self.inner.storage())
+    }
+
+    async fn put_block_from(
+        &self,
+        name: &str,
+        block_id: &str,
+        source: &str,
+        offset: u64,
+        length: u64,
+        content_md5: md5::Digest,
+    ) -> Result<()> {
+        self.hooks.before("put_block_from", name)?;
+        self.hooks
+            .put_block_from(
+                self.inner.storage(),
+                name,
+                block_id,
+                source,
+                offset,
+                length,
+                content_md5,
+            )
+            .await
+    }
+
+    async fn put_block_list(&self, name: &str, block_ids: &[String], checksum: &str) -> Result<()> {
+        self.hooks.before("put_block_list", name)?;
+        self.hooks
+            .put_block_list(self.inner.storage(), name, block_ids, checksum)
+            .await
+    }
+
+    async fn checksum(&self, name: &str) -> Result<Option<String>> {
+        self.hooks.before("checksum", name)?;
+        self.hooks.checksum(self.inner.storage(), name).await
+    }
+
+    async fn get(&self, name: &str) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        self.hooks.before("get", name)?;
+        self.hooks.get(self.inner.storage(), name).await
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        self.hooks.before("delete", name)?;
+        self.hooks.delete(self.inner.storage(), name).await
+    }
+}
@@ -0,0 +1,168 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup,
+    compression::{self, Compression},
+    crypto,
+    index::blob_name,
+    restore,
+    storage::{self, memory::MemoryStorage},
+};
+use common::{config, restore_options, try_config, versions, Tree, DAY, START};
+use futures::stream::{StreamExt, TryStreamExt};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+/// A log file spanning several blocks.
+fn log_content() -> String {
+    (0..200_000)
+        .map(|i| format!("2023-11-14 22:13:20 INFO request {i} handled\n"))
+        .collect()
+}
+
+fn compression(extra: &str) -> Compression {
+    compression::from_config(&config("/", &format!("compression: true\n{extra}"))).unwrap()
+}
+
+#[tokio::test]
+async fn compressed_files_restore_through_the_local_backend() {
+    for extra in ["", &format!("encryption_key: \"{KEY}\"\n")] {
+        let tree = Tree::new();
+        tree.write("/app.log", &log_content(), START - DAY);
+        tree.write("/archive.GZ", &log_content(), START - DAY);
+        tree.write("/empty.txt", "", START - DAY);
+
+        let storage_dir = Tree::new();
+        let conf = config(
+            &tree.root(),
+            &format!(
+                "backend: local\nstorage_path: {}\ncompression: true\n{extra}",
+                storage_dir.root()
+            ),
+        );
+        let storage = storage::from_config(&conf).unwrap();
+        let storage = storage.as_ref();
+        backup::run(&conf, storage, START).await.unwrap();
+
+        let log = &versions(storage, "/app.log").await[0];
+        assert!(log.compressed);
+        let stored = storage
+            .get_content(&blob_name("/app.log", log))
+            .await
+            .unwrap();
+        assert!(stored.len() * 5 < log_content().len());
+        assert!(!versions(storage, "/archive.GZ").await[0].compressed);
+
+        let target = Tree::new();
+        let options = restore_options(&target);
+        let key = crypto::from_config(&conf).unwrap();
+        restore::run(storage, key.as_ref(), &options).await.unwrap();
+
+        for path in ["/app.log", "/archive.GZ"] {
+            assert_eq!(
+                std::fs::read_to_string(target.path(path)).unwrap(),
+                log_content()
+            );
+        }
+        assert_eq!(
+            std::fs::read_to_string(target.path("/empty.txt")).unwrap(),
+            ""
+        );
+    }
+}
+
+#[tokio::test]
+async fn enabling_compression_keeps_existing_versions() {
+    let tree = Tree::new();
+    tree.write("/app.log", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+    let before = storage.names();
+
+    let conf = config(&tree.root(), "compression: true\n");
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(storage.names(), before);
+}
+
+#[tokio::test]
+async fn blocks_can_be_split_anywhere() {
+    let compression = compression("");
+    let mut compressed = compression.compress_block(b"hello ").unwrap();
+    compressed.extend(compression.compress_block(b"world").unwrap());
+
+    // Feed the frames one byte at a time
+    let bytes = |len: usize| {
+        let chunks: Vec<anyhow::Result<Vec<u8>>> =
+            compressed[..len].iter().map(|b| Ok(vec![*b])).collect();
+        futures::stream::iter(chunks).boxed()
+    };
+
+    let decompressed: Vec<Vec<u8>> = compression::decompress_stream(bytes(compressed.len()))
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(decompressed.concat(), b"hello world");
+
+    let truncated: anyhow::Result<Vec<Vec<u8>>> =
+        compression::decompress_stream(bytes(compressed.len() - 1))
+            .try_collect()
+            .await;
+    assert!(truncated.is_err());
+}
+
+#[tokio::test]
+async fn blocks_filling_the_output_buffer_exactly() {
+    let block = vec![b'a'; 4 << 20];
+    let compressed = compression("").compress_block(&block).unwrap();
+
+    let decompress = |chunks: Vec<Vec<u8>>| {
+        let chunks: Vec<anyhow::Result<Vec<u8>>> = chunks.into_iter().map(Ok).collect();
+        compression::decompress_stream(futures::stream::iter(chunks).boxed())
+            .try_collect::<Vec<Vec<u8>>>()
+    };
+
+    let decompressed = decompress(vec![compressed.clone()]).await.unwrap();
+    assert!(decompressed.concat() == block);
+
+    // Calls that make no progress don't mark a cut off frame as complete
+    let truncated = compressed[..compressed.len() - 1].to_vec();
+    assert!(decompress(vec![truncated.clone()]).await.is_err());
+    assert!(decompress(vec![truncated, vec![]]).await.is_err());
+}
+
+#[test]
+fn skip_extensions_can_be_configured() {
+    let default = compression("");
+    assert!(default.applies_to("/var/log/syslog"));
+    assert!(default.applies_to("/var/log/app.log"));
+    assert!(!default.applies_to("/photos/IMG_0001.JPG"));
+    assert!(default.applies_to("/some.dir/file"));
+
+    let custom = compression("compression_skip_extensions: [\".log\", bin]\n");
+    assert!(!custom.applies_to("/var/log/app.log"));
+    assert!(!custom.applies_to("/firmware.bin"));
+    assert!(custom.applies_to("/photos/IMG_0001.JPG"));
+
+    assert!(try_config("/", "compression: true\ncompression_level: 100\n").is_err());
+    assert!(compression::from_config(&config("/", "")).is_none());
+}
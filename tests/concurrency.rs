@@ -0,0 +1,139 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use azure_blob_backup::{backup, index::blob_name, storage::Storage};
+use bytes::Bytes;
+use common::{config, try_config, versions, Hooked, Hooks, Tree, DAY, START};
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Takes a while for every upload and records how many were in flight at once.
+#[derive(Default)]
+struct Slow {
+    ordered_blocks: bool,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+}
+
+impl Slow {
+    async fn upload<F: std::future::Future<Output = Result<()>>>(&self, upload: F) -> Result<()> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let result = upload.await;
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl Hooks for Slow {
+    async fn put(&self, inner: &dyn Storage, name: &str, data: Bytes) -> Result<()> {
+        self.upload(inner.put(name, data)).await
+    }
+
+    async fn put_block(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+        block_id: &str,
+        data: Bytes,
+    ) -> Result<()> {
+        self.upload(inner.put_block(name, block_id, data)).await
+    }
+
+    fn concurrent_blocks(&self, _inner: &dyn Storage) -> bool {
+        !self.ordered_blocks
+    }
+}
+
+#[tokio::test]
+async fn files_are_uploaded_concurrently() {
+    let tree = Tree::new();
+    for i in 0..20 {
+        tree.write(&format!("/{i}.txt"), "hello", START - DAY);
+    }
+
+    let storage = Hooked::new(Slow::default());
+    let conf = config(&tree.root(), "upload_concurrency: 4\n");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    assert_eq!(storage.hooks.max_in_flight(), 4);
+    for i in 0..20 {
+        assert_eq!(versions(&storage, &format!("/{i}.txt")).await.len(), 1);
+    }
+
+    // Deletions are recorded concurrently as well
+    for i in 0..20 {
+        tree.remove(&format!("/{i}.txt"));
+    }
+    let storage = Hooked {
+        inner: storage.inner,
+        hooks: Slow::default(),
+    };
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(storage.hooks.max_in_flight(), 4);
+}
+
+#[tokio::test]
+async fn blocks_are_uploaded_concurrently_and_committed_in_order() {
+    // Five blocks of distinct content
+    let block = 4 << 20;
+    let content: String = (0..(4 * block + block / 2))
+        .map(|i| (b'a' + (i / block) as u8) as char)
+        .collect();
+
+    for (ordered_blocks, expected) in [(false, 3), (true, 1)] {
+        let tree = Tree::new();
+        tree.write("/disk.img", &content, START - DAY);
+
+        let storage = Hooked::new(Slow {
+            ordered_blocks,
+            ..Default::default()
+        });
+        let conf = config(
+            &tree.root(),
+            "upload_concurrency: 1\nblock_upload_concurrency: 3\n",
+        );
+        backup::run(&conf, &storage, START).await.unwrap();
+
+        assert_eq!(storage.hooks.max_in_flight(), expected);
+        let version = &versions(&storage, "/disk.img").await[0];
+        let stored = storage
+            .inner
+            .content(&blob_name("/disk.img", version))
+            .unwrap();
+        assert!(stored == content.as_bytes());
+    }
+}
+
+#[test]
+fn concurrency_has_to_be_positive() {
+    for extra in ["upload_concurrency: 0\n", "block_upload_concurrency: -1\n"] {
+        assert!(try_config("/", extra).is_err(), "{extra}");
+    }
+}
@@ -0,0 +1,129 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    config::{self, Config},
+    consistency::Policy,
+    storage::Backend,
+};
+use common::try_config;
+
+fn error(raw: &str) -> String {
+    config::parse(raw).unwrap_err().to_string()
+}
+
+#[test]
+fn unset_keys_get_their_defaults() {
+    let conf = config::parse("local_root: /mnt/data\nsas_url: https://example.com\n").unwrap();
+    assert!(matches!(conf.backend, Backend::Azure));
+    assert_eq!(conf.num_daily, 7);
+    assert_eq!(conf.num_weekly, 4);
+    assert_eq!(conf.num_monthly, 12);
+    assert_eq!(conf.min_update_age, 23 * 60 * 60);
+    assert_eq!(conf.upload_concurrency, 8);
+    assert_eq!(conf.modified_during_upload, Policy::Retry);
+    assert!(!conf.compression && conf.encryption_key.is_none());
+
+    let conf = config::load("config.template.yaml").unwrap();
+    assert_eq!(conf.local_root, "/mnt/data");
+}
+
+#[test]
+fn template_documents_valid_keys() {
+    // Some of the documented keys can't be combined, so they are only deserialized, not validated
+    let template = std::fs::read_to_string("config.template.yaml").unwrap();
+    let raw: String = template
+        .lines()
+        .map(|line| match line.strip_prefix("# ") {
+            Some(setting) if is_setting(setting) => format!("{setting}\n"),
+            _ => format!("{line}\n"),
+        })
+        .collect();
+
+    let conf: Config = serde_yaml::from_str(&raw).unwrap();
+    assert_eq!(conf.storage_path.as_deref(), Some("/mnt/nas/backup"));
+    assert_eq!(conf.s3_secret_key.as_deref(), Some("<secret key>"));
+    assert_eq!(conf.upload_limit_schedule.len(), 2);
+    assert_eq!(conf.retry_jitter_percent, 50);
+    assert!(conf.compression && conf.deduplication && conf.encrypt_names);
+}
+
+/// Whether a comment of the template is a commented out key, rather than an explanation.
+fn is_setting(comment: &str) -> bool {
+    match comment.split_once(": ") {
+        Some((key, _)) => key
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'),
+        None => false,
+    }
+}
+
+#[test]
+fn errors_point_to_the_line() {
+    let raw = "local_root: /mnt/data\nnum_daly: 3\n";
+    assert!(error(raw).starts_with("Malformed config: unknown field `num_daly`"));
+    assert!(error(raw).ends_with("at line 2 column 1"));
+
+    let raw = "local_root: /mnt/data\ncompression: yes please\n";
+    assert!(error(raw).starts_with("Malformed config: compression: invalid type"));
+    assert!(error(raw).ends_with("at line 2 column 14"));
+
+    let raw = "local_root: /mnt/data\n\nbackend: ftp\n";
+    assert!(error(raw).starts_with("Malformed config: backend: unknown variant `ftp`"));
+    assert!(error(raw).ends_with("at line 3 column 10"));
+
+    let raw = "local_root: /mnt/data\nupload_limit_schedule:\n  - 8am-6pm 100\n";
+    assert!(error(raw).contains("the entry 8am-6pm 100 has to look like"));
+    assert!(error(raw).ends_with("at line 3 column 3"));
+
+    assert_eq!(
+        error("num_daily: 3\n"),
+        "Malformed config: missing field `local_root`"
+    );
+    assert_eq!(error(""), "The config is empty");
+}
+
+#[test]
+fn settings_are_validated() {
+    assert_eq!(
+        try_config("/", "num_weekly: 5\n").unwrap_err().to_string(),
+        "Malformed config: num_weekly on line 6 has to be in the interval of [0;4], but is 5"
+    );
+    for extra in [
+        "num_daily: 8\n",
+        "num_monthly: -1\n",
+        "num_daily: 0\nnum_weekly: 0\nnum_monthly: 0\n",
+        "min_update_age: 1.5\n",
+        "backend: local\nstorage_path: backups\n",
+        "retry_jitter_percent: 101\n",
+    ] {
+        assert!(try_config("/", extra).is_err(), "{extra}");
+    }
+    assert!(try_config("data", "").is_err());
+
+    assert_eq!(
+        error("local_root: /mnt/data\nbackend: s3\n"),
+        "Malformed config: backend on line 2 requires s3_bucket to be set"
+    );
+    assert_eq!(
+        error("local_root: /mnt/data\n"),
+        "Malformed config: backend requires sas_url to be set"
+    );
+    assert!(try_config("/", "backend: local\n").is_err());
+}
@@ -0,0 +1,257 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use azure_blob_backup::{
+    backup,
+    index::blob_name,
+    storage::{memory::MemoryStorage, Storage},
+};
+use common::{config, versions, Hooked, Hooks, Tree, DAY, START};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Calls `modify` whenever a version of /log.txt is committed, `times` times. With
+/// `before_upload` it is called once when the storage is first listed instead, which happens after
+/// the local files were indexed.
+struct Modifying<F: Fn() + Send + Sync> {
+    modify: F,
+    times: AtomicUsize,
+    before_upload: bool,
+}
+
+impl<F: Fn() + Send + Sync> Modifying<F> {
+    fn new(modify: F, times: usize) -> Modifying<F> {
+        Modifying {
+            modify,
+            times: AtomicUsize::new(times),
+            before_upload: false,
+        }
+    }
+}
+
+#[async_trait]
+impl<F: Fn() + Send + Sync> Hooks for Modifying<F> {
+    async fn list(&self, inner: &dyn Storage, prefix: &str) -> Result<Vec<String>> {
+        if self.before_upload && self.times.swap(0, Ordering::SeqCst) > 0 {
+            (self.modify)();
+        }
+        inner.list(prefix).await
+    }
+
+    async fn put_block_list(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+        block_ids: &[String],
+        checksum: &str,
+    ) -> Result<()> {
+        inner.put_block_list(name, block_ids, checksum).await?;
+        if !name.starts_with("/log.txt/") || self.before_upload {
+            return Ok(());
+        }
+        let times = self
+            .times
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |times| {
+                times.checked_sub(1)
+            });
+        if times.is_ok() {
+            (self.modify)();
+        }
+        Ok(())
+    }
+}
+
+/// The names of the blobs of /log.txt.
+fn log_blobs(storage: &MemoryStorage) -> Vec<String> {
+    storage
+        .names()
+        .into_iter()
+        .filter(|name| name.contains("/log.txt/"))
+        .collect()
+}
+
+/// Appends a line to /log.txt.
+fn append(tree: &Tree) -> impl Fn() + Send + Sync + '_ {
+    move || {
+        let content = std::fs::read_to_string(tree.path("/log.txt")).unwrap();
+        tree.write("/log.txt", &(content + "\nstopped"), START - DAY / 2);
+    }
+}
+
+#[tokio::test]
+async fn modified_files_are_uploaded_again() {
+    let tree = Tree::new();
+    tree.write("/log.txt", "started", START - DAY);
+
+    let storage = Hooked::new(Modifying::new(append(&tree), 1));
+    let report = backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+    assert!(report.failed.is_empty());
+
+    let stored = versions(&storage, "/log.txt").await;
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].size, 15);
+    assert_eq!(stored[0].mod_time, START - DAY / 2);
+    assert_eq!(stored[0].upload_time, START);
+    assert_eq!(
+        log_blobs(&storage.inner),
+        vec![blob_name("/log.txt", &stored[0])]
+    );
+    let content = storage
+        .get_content(&blob_name("/log.txt", &stored[0]))
+        .await
+        .unwrap();
+    assert_eq!(content, b"started\nstopped");
+}
+
+/// Shortens /log.txt after it was indexed, and puts it back the way it was once it was uploaded, so
+/// only the uploaded content differs from the version.
+struct Shrinking<'a> {
+    tree: &'a Tree,
+    shrunk: AtomicUsize,
+}
+
+#[async_trait]
+impl Hooks for Shrinking<'_> {
+    async fn list(&self, inner: &dyn Storage, prefix: &str) -> Result<Vec<String>> {
+        if self.shrunk.fetch_add(1, Ordering::SeqCst) == 0 {
+            self.tree.write("/log.txt", "start", START - DAY);
+        }
+        inner.list(prefix).await
+    }
+
+    async fn put_block_list(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+        block_ids: &[String],
+        checksum: &str,
+    ) -> Result<()> {
+        inner.put_block_list(name, block_ids, checksum).await?;
+        self.tree.write("/log.txt", "started", START - DAY);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn files_read_short_are_uploaded_again() {
+    let tree = Tree::new();
+    tree.write("/log.txt", "started", START - DAY);
+
+    let storage = Hooked::new(Shrinking {
+        tree: &tree,
+        shrunk: AtomicUsize::new(0),
+    });
+    let report = backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+    assert!(report.failed.is_empty());
+
+    let stored = versions(&storage, "/log.txt").await;
+    assert_eq!(stored.len(), 1);
+    let content = storage
+        .get_content(&blob_name("/log.txt", &stored[0]))
+        .await
+        .unwrap();
+    assert_eq!(content, b"started");
+}
+
+#[tokio::test]
+async fn files_that_keep_changing_are_skipped() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    for (extra, times) in [("", usize::MAX), ("modified_during_upload: skip\n", 1)] {
+        tree.write("/log.txt", "started", START - DAY);
+        let storage = Hooked::new(Modifying::new(append(&tree), times));
+        let report = backup::run(&config(&tree.root(), extra), &storage, START)
+            .await
+            .unwrap();
+
+        assert_eq!(report.failed.len(), 1, "{extra}");
+        assert_eq!(report.failed[0].0, "/log.txt");
+        assert!(report.failed[0].1.contains("modified during its upload"));
+        assert!(log_blobs(&storage.inner).is_empty());
+        assert_eq!(versions(&storage, "/a.txt").await.len(), 1);
+    }
+}
+
+#[tokio::test]
+async fn modified_files_can_be_kept() {
+    let tree = Tree::new();
+    tree.write("/log.txt", "started", START - DAY);
+
+    let storage = Hooked::new(Modifying::new(append(&tree), 1));
+    let conf = config(&tree.root(), "modified_during_upload: keep\n");
+    let report = backup::run(&conf, &storage, START).await.unwrap();
+    assert!(report.failed.is_empty());
+
+    // The version keeps the metadata it was indexed with, and is marked
+    let stored = versions(&storage, "/log.txt").await;
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].size, 7);
+    let marker = "/.azure_blob_backup/modified".to_string() + &blob_name("/log.txt", &stored[0]);
+    assert_eq!(
+        storage.list("/.azure_blob_backup/modified/").await.unwrap(),
+        vec![marker]
+    );
+
+    // The marker goes with its version
+    tree.remove("/log.txt");
+    let conf = config(
+        &tree.root(),
+        "num_daily: 1\nnum_weekly: 0\nnum_monthly: 0\n",
+    );
+    for day in 1..4 {
+        backup::run(&conf, &storage, START + day * DAY)
+            .await
+            .unwrap();
+    }
+    assert!(versions(&storage, "/log.txt").await.is_empty());
+    assert!(storage
+        .list("/.azure_blob_backup/modified/")
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn content_hashes_catch_changes_keeping_the_metadata() {
+    let tree = Tree::new();
+    tree.write("/log.txt", "started", START - DAY);
+
+    // Same size, same modification time, between indexing and uploading
+    let storage = Hooked::new(Modifying {
+        before_upload: true,
+        ..Modifying::new(|| tree.write("/log.txt", "stopped", START - DAY), 1)
+    });
+    let conf = config(&tree.root(), "content_hash: true\n");
+    let report = backup::run(&conf, &storage, START).await.unwrap();
+    assert!(report.failed.is_empty());
+
+    let stored = versions(&storage, "/log.txt").await;
+    assert_eq!(stored.len(), 1);
+    let content = storage
+        .get_content(&blob_name("/log.txt", &stored[0]))
+        .await
+        .unwrap();
+    assert_eq!(content, b"stopped");
+}
@@ -0,0 +1,86 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{backup, index::blob_name, storage::memory::MemoryStorage};
+use common::{config, versions, Tree, DAY, START};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+/// sha256("hello")
+const HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+#[tokio::test]
+async fn rewrites_keeping_size_and_mod_time_are_detected() {
+    for (extra, expected_versions) in [("", 1), ("content_hash: true\n", 2)] {
+        let tree = Tree::new();
+        tree.write("/a.txt", "hello", START - DAY);
+
+        let storage = MemoryStorage::new();
+        let conf = config(&tree.root(), extra);
+        backup::run(&conf, &storage, START).await.unwrap();
+
+        // Same size, same modification time, like a copy with rsync -t
+        tree.write("/a.txt", "world", START - DAY);
+        backup::run(&conf, &storage, START + DAY).await.unwrap();
+
+        let versions = versions(&storage, "/a.txt").await;
+        assert_eq!(versions.len(), expected_versions, "{extra}");
+        if expected_versions == 2 {
+            assert_eq!(versions[0].content_hash.as_deref(), Some(HELLO));
+            let content = storage.content(&blob_name("/a.txt", &versions[1]));
+            assert_eq!(content.unwrap(), b"world");
+        }
+    }
+}
+
+#[tokio::test]
+async fn enabling_content_hashes_keeps_existing_versions() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.symlink("/link", "a.txt");
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+    let before = storage.names();
+
+    let conf = config(&tree.root(), "content_hash: true\n");
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(storage.names(), before);
+    assert!(versions(&storage, "/link").await[0].content_hash.is_none());
+}
+
+#[tokio::test]
+async fn hashes_are_keyed_with_encryption() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(
+        &tree.root(),
+        &format!("content_hash: true\nencryption_key: \"{KEY}\"\n"),
+    );
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    let hash = versions(&storage, "/a.txt").await[0].content_hash.clone();
+    assert_eq!(hash.as_ref().map(|hash| hash.len()), Some(64));
+    assert_ne!(hash.as_deref(), Some(HELLO));
+    assert!(storage.names().iter().all(|name| !name.contains(HELLO)));
+}
@@ -0,0 +1,171 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup, crypto, restore,
+    storage::{self, memory::MemoryStorage},
+};
+use common::{config, restore_options, versions, Tree, DAY, START};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+const DEDUP: &str = "deduplication: true\ndeduplication_min_size: 1048576\n";
+
+/// Pseudo random text, so the chunk boundaries depend on the content.
+fn noise(seed: u64, len: usize) -> String {
+    let mut state = seed;
+    (0..len)
+        .map(|_| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (b'a' + ((state >> 33) % 26) as u8) as char
+        })
+        .collect()
+}
+
+fn chunks(storage: &MemoryStorage) -> Vec<String> {
+    storage
+        .names()
+        .into_iter()
+        .filter(|name| name.starts_with("/.azure_blob_backup/chunks/"))
+        .collect()
+}
+
+#[tokio::test]
+async fn copies_share_their_chunks() {
+    let tree = Tree::new();
+    let content = noise(1, 8 << 20);
+    tree.write("/disk.img", &content, START - DAY);
+    tree.write("/small.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), DEDUP);
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    assert!(versions(&storage, "/disk.img").await[0].deduplicated);
+    assert!(!versions(&storage, "/small.txt").await[0].deduplicated);
+    let stored = chunks(&storage);
+    assert!(stored.len() > 2);
+
+    tree.write("/copy.img", &content, START - DAY);
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert!(versions(&storage, "/copy.img").await[0].deduplicated);
+    assert_eq!(chunks(&storage), stored);
+}
+
+#[tokio::test]
+async fn modifications_only_upload_the_changed_chunks() {
+    let tree = Tree::new();
+    let content = noise(2, 16 << 20);
+    tree.write("/disk.img", &content, START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), DEDUP);
+    backup::run(&conf, &storage, START).await.unwrap();
+    let before = chunks(&storage).len();
+
+    // Inserting data shifts everything behind it, which content defined chunks are robust against
+    let modified = content[..(5 << 20)].to_string() + "inserted" + &content[(5 << 20)..];
+    tree.write("/disk.img", &modified, START);
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+
+    assert_eq!(versions(&storage, "/disk.img").await.len(), 2);
+    let new_chunks = chunks(&storage).len() - before;
+    assert!((1..=2).contains(&new_chunks), "{new_chunks} new chunks");
+}
+
+#[tokio::test]
+async fn deduplicated_files_restore_through_the_local_backend() {
+    let extras = [
+        String::new(),
+        "compression: true\n".to_string(),
+        format!("compression: true\nencryption_key: \"{KEY}\"\n"),
+    ];
+    for extra in extras {
+        let tree = Tree::new();
+        tree.write("/disk.img", &noise(3, 6 << 20), START - DAY);
+        tree.write("/empty.img", "", START - DAY);
+
+        let storage_dir = Tree::new();
+        let conf = config(
+            &tree.root(),
+            &format!(
+                "backend: local\nstorage_path: {}\ndeduplication: true\ndeduplication_min_size: 0\n{extra}",
+                storage_dir.root()
+            ),
+        );
+        let storage = storage::from_config(&conf).unwrap();
+        let storage = storage.as_ref();
+        backup::run(&conf, storage, START).await.unwrap();
+        assert!(versions(storage, "/empty.img").await[0].deduplicated);
+
+        let target = Tree::new();
+        let options = restore_options(&target);
+        let key = crypto::from_config(&conf).unwrap();
+        restore::run(storage, key.as_ref(), &options).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(target.path("/disk.img")).unwrap(),
+            noise(3, 6 << 20)
+        );
+        assert_eq!(
+            std::fs::read_to_string(target.path("/empty.img")).unwrap(),
+            ""
+        );
+        assert!(!target.path("/.azure_blob_backup").exists());
+    }
+}
+
+#[tokio::test]
+async fn unreferenced_chunks_are_deleted() {
+    let tree = Tree::new();
+    tree.write("/disk.img", &noise(4, 4 << 20), START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), DEDUP);
+    backup::run(&conf, &storage, START).await.unwrap();
+    assert!(!chunks(&storage).is_empty());
+
+    tree.remove("/disk.img");
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert!(!chunks(&storage).is_empty());
+
+    // Once the file is gone from all periods its chunks go as well, even with deduplication off
+    backup::run(&config(&tree.root(), ""), &storage, START + 400 * DAY)
+        .await
+        .unwrap();
+    assert!(versions(&storage, "/disk.img").await.is_empty());
+    assert!(chunks(&storage).is_empty());
+}
+
+#[tokio::test]
+async fn the_reserved_path_is_not_backed_up() {
+    let tree = Tree::new();
+    tree.write("/.azure_blob_backup/chunks/abc", "not a chunk", START - DAY);
+    tree.write("/file.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    assert!(chunks(&storage).is_empty());
+    assert_eq!(versions(&storage, "/file.txt").await.len(), 1);
+}
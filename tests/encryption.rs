@@ -0,0 +1,358 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup,
+    crypto::{self, MasterKey},
+    index::{blob_name, FileType},
+    restore,
+    storage::{
+        self, encrypted_names::EncryptedNames, local::LocalStorage, memory::MemoryStorage, Storage,
+    },
+};
+use bytes::Bytes;
+use common::{
+    config, large_content, restore_options, try_config, versions, Hooked, Hooks, Tree, DAY, START,
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const OTHER_KEY: &str = "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff000102030405060708090a0b0c0d0e0f";
+
+/// Spans several blocks, which don't line up with the chunks the local backend reads.
+#[tokio::test]
+async fn encrypted_backups_restore_through_the_local_backend() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/empty", "", START - DAY);
+    tree.write("/dir/large.bin", &large_content(), START - DAY);
+    tree.symlink("/link", "a.txt");
+
+    let storage_dir = Tree::new();
+    let conf = config(
+        &tree.root(),
+        &format!(
+            "backend: local\nstorage_path: {}\nencryption_key: \"{KEY}\"\n",
+            storage_dir.root()
+        ),
+    );
+    let storage = storage::from_config(&conf).unwrap();
+    backup::run(&conf, storage.as_ref(), START).await.unwrap();
+
+    for (path, plaintext) in [("/a.txt", "hello"), ("/link", "a.txt")] {
+        let version = &versions(storage.as_ref(), path).await[0];
+        assert!(version.encrypted);
+        let content = storage
+            .get_content(&blob_name(path, version))
+            .await
+            .unwrap();
+        assert!(!content
+            .windows(plaintext.len())
+            .any(|window| window == plaintext.as_bytes()));
+    }
+    assert!(!versions(storage.as_ref(), "/dir").await[0].encrypted);
+
+    let target = Tree::new();
+    let key = crypto::from_config(&conf).unwrap();
+    restore::run(storage.as_ref(), key.as_ref(), &restore_options(&target))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(target.path("/a.txt")).unwrap(),
+        "hello"
+    );
+    assert_eq!(std::fs::read_to_string(target.path("/empty")).unwrap(), "");
+    assert_eq!(
+        std::fs::read_to_string(target.path("/dir/large.bin")).unwrap(),
+        large_content()
+    );
+    assert_eq!(
+        std::fs::read_link(target.path("/link")).unwrap(),
+        std::path::PathBuf::from("a.txt")
+    );
+}
+
+#[tokio::test]
+async fn restore_requires_the_right_key() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), &format!("encryption_key: \"{KEY}\"\n"));
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    let target = Tree::new();
+    let without_key = restore::run(&storage, None, &restore_options(&target)).await;
+    assert!(without_key.is_err());
+
+    let other_key = MasterKey::from_hex(OTHER_KEY).unwrap();
+    let wrong_key = restore::run(&storage, Some(&other_key), &restore_options(&target)).await;
+    assert!(wrong_key.is_err());
+}
+
+#[tokio::test]
+async fn damaged_blobs_fail_to_decrypt() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), &format!("encryption_key: \"{KEY}\"\n"));
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    let name = blob_name("/a.txt", &versions(&storage, "/a.txt").await[0]);
+    let content = storage.content(&name).unwrap();
+    let key = MasterKey::from_hex(KEY).unwrap();
+
+    let mut flipped = content.clone();
+    *flipped.last_mut().unwrap() ^= 1;
+    storage.put(&name, flipped.into()).await.unwrap();
+    assert!(key
+        .decrypt_all(&name, storage.get(&name).await.unwrap())
+        .await
+        .is_err());
+
+    storage
+        .put(&name, content[..content.len() - 1].to_vec().into())
+        .await
+        .unwrap();
+    assert!(key
+        .decrypt_all(&name, storage.get(&name).await.unwrap())
+        .await
+        .is_err());
+
+    storage.put(&name, content.into()).await.unwrap();
+    assert_eq!(
+        key.decrypt_all(&name, storage.get(&name).await.unwrap())
+            .await
+            .unwrap(),
+        b"hello"
+    );
+}
+
+#[tokio::test]
+async fn blobs_moved_to_another_name_fail_to_decrypt() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/b.txt", "world", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), &format!("encryption_key: \"{KEY}\"\n"));
+    backup::run(&conf, &storage, START).await.unwrap();
+    tree.write("/a.txt", "hello again", START + DAY);
+    backup::run(&conf, &storage, START + 2 * DAY).await.unwrap();
+
+    // The content of another path, or of another version of the same path
+    let a = versions(&storage, "/a.txt").await;
+    let b = blob_name("/b.txt", &versions(&storage, "/b.txt").await[0]);
+    for source in [blob_name("/a.txt", &a[0]), blob_name("/a.txt", &a[1])] {
+        let content = storage.content(&source).unwrap();
+        storage.put(&b, content.into()).await.unwrap();
+
+        let key = MasterKey::from_hex(KEY).unwrap();
+        assert!(key
+            .decrypt_all(&b, storage.get(&b).await.unwrap())
+            .await
+            .is_err());
+        let target = Tree::new();
+        assert!(
+            restore::run(&storage, Some(&key), &restore_options(&target))
+                .await
+                .is_err()
+        );
+    }
+}
+
+#[tokio::test]
+async fn enabling_encryption_uploads_encrypted_versions() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    let conf = config(&tree.root(), &format!("encryption_key: \"{KEY}\"\n"));
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+
+    let file_versions = versions(&storage, "/a.txt").await;
+    assert_eq!(file_versions.len(), 2);
+    assert!(!file_versions[0].encrypted);
+    assert!(file_versions[1].encrypted);
+
+    // Folders have no content to encrypt
+    assert_eq!(versions(&storage, "/").await.len(), 1);
+}
+
+#[test]
+fn the_key_can_be_read_from_a_file() {
+    let dir = Tree::new();
+    dir.write("/key", &format!("{KEY}\n"), START);
+    let key_file = dir.path("/key");
+    let key_file = key_file.to_str().unwrap();
+
+    let conf = config("/", &format!("encryption_key_file: {key_file}\n"));
+    assert!(crypto::from_config(&conf).unwrap().is_some());
+
+    assert!(try_config(
+        "/",
+        &format!("encryption_key_file: {key_file}\nencryption_key: \"{KEY}\"\n"),
+    )
+    .is_err());
+
+    let conf = config("/", "encryption_key: \"abcd\"\n");
+    assert!(crypto::from_config(&conf).is_err());
+
+    assert!(crypto::from_config(&config("/", "")).unwrap().is_none());
+}
+
+#[tokio::test]
+async fn encrypted_names_hide_paths_and_metadata() {
+    let long_name = "/".to_string() + &"nested-folder/".repeat(20) + "secret.txt";
+
+    let tree = Tree::new();
+    tree.write("/secret.txt", "hello", START - DAY);
+    tree.write(&long_name, "deep", START - DAY);
+
+    let storage_dir = Tree::new();
+    let conf = config(
+        &tree.root(),
+        &format!(
+            "backend: local\nstorage_path: {}\nencryption_key: \"{KEY}\"\nencrypt_names: true\n",
+            storage_dir.root()
+        ),
+    );
+    let storage = storage::from_config(&conf).unwrap();
+    let storage = storage.as_ref();
+    backup::run(&conf, storage, START).await.unwrap();
+
+    let raw = LocalStorage::new(&storage_dir.root()).unwrap();
+    let stored = raw.list("/").await.unwrap();
+    assert!(!stored.is_empty());
+    for name in &stored {
+        assert!(!name.contains("secret"), "{name}");
+        assert!(!name.contains("Regular"), "{name}");
+    }
+
+    // The sync engine sees the real names, so changes and deletions work as before
+    tree.write("/secret.txt", "hello again", START + DAY);
+    tree.remove(&long_name);
+    backup::run(&conf, storage, START + DAY).await.unwrap();
+    assert_eq!(versions(storage, "/secret.txt").await.len(), 2);
+    assert_eq!(versions(storage, &long_name).await.len(), 2);
+
+    let target = Tree::new();
+    let key = crypto::from_config(&conf).unwrap();
+    let mut options = restore_options(&target);
+    options.path = "/secret.txt".to_string();
+    restore::run(storage, key.as_ref(), &options).await.unwrap();
+    assert_eq!(
+        std::fs::read_to_string(target.path("/secret.txt")).unwrap(),
+        "hello again"
+    );
+
+    // Block ids contain a hash of the name they belong to, which would identify the path
+    let name = blob_name("/secret.txt", &versions(storage, "/secret.txt").await[1]);
+    let id = storage::block_id(&name, 0);
+    storage
+        .put_block(&name, &id, Bytes::from_static(b"staged"))
+        .await
+        .unwrap();
+    let name_hash = sha256::digest(name.as_str());
+    let mut staged_ids = Vec::new();
+    for dir in std::fs::read_dir(storage_dir.path("/staging")).unwrap() {
+        for block in std::fs::read_dir(dir.unwrap().path()).unwrap() {
+            staged_ids.push(block.unwrap().file_name().into_string().unwrap());
+        }
+    }
+    assert_eq!(staged_ids.len(), 1);
+    for staged_id in &staged_ids {
+        assert!(!staged_id.contains(&name_hash[..48]), "{staged_id}");
+    }
+    let staged = storage.staged_blocks(&name).await.unwrap();
+    assert_eq!(staged.get(&id), Some(&6));
+}
+
+/// Counts the listings of the wrapped storage.
+struct CountingLists(Arc<AtomicUsize>);
+
+impl Hooks for CountingLists {
+    fn before(&self, call: &str, _name: &str) -> anyhow::Result<()> {
+        if call == "list" {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn encrypted_names_are_listed_once() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/large.bin", &large_content(), START - DAY);
+
+    let conf = config(
+        &tree.root(),
+        &format!("encryption_key: \"{KEY}\"\nencrypt_names: true\nblock_incremental: true\n"),
+    );
+    let lists = Arc::new(AtomicUsize::new(0));
+    let key = crypto::from_config(&conf).unwrap().unwrap();
+    let storage = EncryptedNames::new(Box::new(Hooked::new(CountingLists(lists.clone()))), &key);
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    // Uploads and deletions show up in later listings without listing the container again
+    tree.write("/a.txt", "hello again", START + DAY);
+    tree.remove("/dir/large.bin");
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(lists.load(Ordering::SeqCst), 1);
+    assert_eq!(versions(&storage, "/a.txt").await.len(), 2);
+    let large = versions(&storage, "/dir/large.bin").await;
+    assert_eq!(large.len(), 2);
+    assert_eq!(large[1].file_type, FileType::Deleted);
+    assert_eq!(storage.list("/dir/large.bin/").await.unwrap().len(), 2);
+    assert!(storage.list("/b").await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn encrypted_names_require_an_empty_container() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage_dir = Tree::new();
+    let local = format!("backend: local\nstorage_path: {}\n", storage_dir.root());
+    let conf = config(&tree.root(), &local);
+    backup::run(&conf, storage::from_config(&conf).unwrap().as_ref(), START)
+        .await
+        .unwrap();
+
+    let conf = config(
+        &tree.root(),
+        &format!("{local}encryption_key: \"{KEY}\"\nencrypt_names: true\n"),
+    );
+    let storage = storage::from_config(&conf).unwrap();
+    assert!(backup::run(&conf, storage.as_ref(), START + DAY)
+        .await
+        .is_err());
+
+    assert!(try_config(&tree.root(), &format!("{local}encrypt_names: true\n")).is_err());
+}
@@ -0,0 +1,117 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use anyhow::{anyhow, Result};
+use azure_blob_backup::{backup, index::FileType, storage::memory::MemoryStorage};
+use common::{config, versions, Hooked, Hooks, Tree, DAY, START};
+use std::os::unix::fs::PermissionsExt;
+
+/// Rejects every upload of blobs below `/bad`.
+struct Rejecting;
+
+impl Hooks for Rejecting {
+    fn before(&self, call: &str, name: &str) -> Result<()> {
+        let upload = matches!(
+            call,
+            "put" | "put_block" | "put_block_from" | "put_block_list"
+        );
+        if upload && name.starts_with("/bad") {
+            return Err(anyhow!("The request to {} was rejected", name));
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn failed_uploads_dont_stop_the_run() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/bad.txt", "hello", START - DAY);
+    tree.write("/z.txt", "hello", START - DAY);
+
+    let storage = Hooked::new(Rejecting);
+    let conf = config(&tree.root(), "");
+    let report = backup::run(&conf, &storage, START).await.unwrap();
+
+    let failed: Vec<&str> = report
+        .failed
+        .iter()
+        .map(|(path, _)| path.as_str())
+        .collect();
+    assert_eq!(failed, vec!["/bad.txt"]);
+    assert!(report.failed[0].1.contains("rejected"));
+    assert_eq!(versions(&storage, "/a.txt").await.len(), 1);
+    assert_eq!(versions(&storage, "/z.txt").await.len(), 1);
+    assert!(versions(&storage, "/bad.txt").await.is_empty());
+}
+
+#[tokio::test]
+async fn failed_uploads_keep_the_stored_versions() {
+    let tree = Tree::new();
+    tree.write("/bad.txt", "hello", START - DAY);
+
+    // The first version goes through, its changes and its deletion don't
+    let storage = Hooked::new(Rejecting);
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, &storage.inner, START).await.unwrap();
+
+    tree.write("/bad.txt", "hello world", START);
+    let report = backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(versions(&storage, "/bad.txt").await.len(), 1);
+
+    tree.remove("/bad.txt");
+    let report = backup::run(&conf, &storage, START + 2 * DAY).await.unwrap();
+    assert_eq!(report.failed.len(), 1);
+    let stored = versions(&storage, "/bad.txt").await;
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].file_type, FileType::Regular);
+}
+
+#[tokio::test]
+async fn unreadable_folders_are_not_deleted() {
+    // Root reads the folder regardless of its permissions
+    if unsafe { libc::geteuid() } == 0 {
+        return;
+    }
+
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/private/key.txt", "secret", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    let private = tree.path("/private");
+    std::fs::set_permissions(&private, std::fs::Permissions::from_mode(0o000)).unwrap();
+    let report = backup::run(&conf, &storage, START + DAY).await;
+    std::fs::set_permissions(&private, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let failed: Vec<String> = report
+        .unwrap()
+        .failed
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect();
+    assert_eq!(failed, vec!["/private".to_string()]);
+    let stored = versions(&storage, "/private/key.txt").await;
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].file_type, FileType::Regular);
+}
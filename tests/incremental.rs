@@ -0,0 +1,301 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use azure_blob_backup::{
+    backup, crypto,
+    index::{blob_name, Selection},
+    restore,
+    storage::{self, memory::MemoryStorage, Storage},
+};
+use bytes::Bytes;
+use common::{config, restore_options, try_config, versions, Hooked, Hooks, Tree, DAY, START};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+const BLOCK: usize = 4 << 20;
+
+/// Counts how many blocks are uploaded and how many are copied, and how often blobs are listed.
+/// Without copies it poses as a backend that can't copy blocks, like S3.
+#[derive(Default)]
+struct Counting {
+    uploaded: AtomicUsize,
+    copied: AtomicUsize,
+    lists: AtomicUsize,
+    without_copies: bool,
+}
+
+#[async_trait]
+impl Hooks for Counting {
+    fn before(&self, call: &str, _name: &str) -> Result<()> {
+        if call == "list" {
+            self.lists.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    async fn put_block(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+        block_id: &str,
+        data: Bytes,
+    ) -> Result<()> {
+        self.uploaded.fetch_add(1, Ordering::SeqCst);
+        inner.put_block(name, block_id, data).await
+    }
+
+    fn can_copy_blocks(&self, inner: &dyn Storage) -> bool {
+        !self.without_copies && inner.can_copy_blocks()
+    }
+
+    async fn put_block_from(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+        block_id: &str,
+        source: &str,
+        offset: u64,
+        length: u64,
+        content_md5: md5::Digest,
+    ) -> Result<()> {
+        self.copied.fetch_add(1, Ordering::SeqCst);
+        inner
+            .put_block_from(name, block_id, source, offset, length, content_md5)
+            .await
+    }
+}
+
+impl Counting {
+    fn take(&self) -> (usize, usize) {
+        (
+            self.uploaded.swap(0, Ordering::SeqCst),
+            self.copied.swap(0, Ordering::SeqCst),
+        )
+    }
+}
+
+/// Three blocks of distinct content, the last one partially filled.
+fn image(changed_block: Option<usize>) -> String {
+    let mut content: Vec<u8> = (0..(2 * BLOCK + BLOCK / 2))
+        .map(|i| b'a' + (i / BLOCK) as u8)
+        .collect();
+    if let Some(block) = changed_block {
+        content[block * BLOCK + 10] = b'x';
+    }
+    String::from_utf8(content).unwrap()
+}
+
+async fn restore_all(
+    storage: &dyn Storage,
+    selection: Selection,
+    key: Option<&crypto::MasterKey>,
+) -> Tree {
+    let target = Tree::new();
+    let options = restore::Options {
+        selection,
+        ..restore_options(&target)
+    };
+    restore::run(storage, key, &options).await.unwrap();
+    target
+}
+
+#[tokio::test]
+async fn only_changed_blocks_are_uploaded() {
+    for extra in ["", "compression: true\n"] {
+        let tree = Tree::new();
+        tree.write("/disk.img", &image(None), START - DAY);
+
+        let storage = Hooked::new(Counting::default());
+        let conf = config(&tree.root(), &format!("block_incremental: true\n{extra}"));
+        backup::run(&conf, &storage, START).await.unwrap();
+        assert_eq!(storage.hooks.take(), (3, 0));
+
+        tree.write("/disk.img", &image(Some(1)), START);
+        backup::run(&conf, &storage, START + DAY).await.unwrap();
+        assert_eq!(storage.hooks.take(), (1, 2));
+
+        // Blocks are compared with the newest version only, so the reverted block is uploaded
+        tree.write("/disk.img", &image(Some(2)), START + DAY);
+        backup::run(&conf, &storage, START + 2 * DAY).await.unwrap();
+        assert_eq!(storage.hooks.take(), (2, 1));
+
+        for (idx, expected) in [(0, image(None)), (1, image(Some(1))), (2, image(Some(2)))] {
+            let target = restore_all(&storage, Selection::Index(idx), None).await;
+            assert!(std::fs::read_to_string(target.path("/disk.img")).unwrap() == expected);
+        }
+    }
+}
+
+#[tokio::test]
+async fn block_maps_are_listed_once_per_run() {
+    let mut lists = Vec::new();
+    for files in [1, 3] {
+        let tree = Tree::new();
+        let storage = Hooked::new(Counting::default());
+        let conf = config(&tree.root(), "block_incremental: true\n");
+        for i in 0..files {
+            tree.write(&format!("/{i}.img"), &image(None), START - DAY);
+        }
+        backup::run(&conf, &storage, START).await.unwrap();
+        storage.hooks.take();
+
+        for i in 0..files {
+            tree.write(&format!("/{i}.img"), &image(Some(1)), START);
+        }
+        storage.hooks.lists.store(0, Ordering::SeqCst);
+        backup::run(&conf, &storage, START + DAY).await.unwrap();
+        assert_eq!(storage.hooks.take(), (files, 2 * files));
+        lists.push(storage.hooks.lists.load(Ordering::SeqCst));
+    }
+
+    // The number of listings doesn't grow with the number of files
+    assert_eq!(lists[0], lists[1]);
+}
+
+#[tokio::test]
+async fn storages_that_cant_copy_get_every_block_uploaded() {
+    let tree = Tree::new();
+    tree.write("/disk.img", &image(None), START - DAY);
+
+    let storage = Hooked::new(Counting {
+        without_copies: true,
+        ..Default::default()
+    });
+    let conf = config(&tree.root(), "block_incremental: true\n");
+    backup::run(&conf, &storage, START).await.unwrap();
+    assert_eq!(storage.hooks.take(), (3, 0));
+
+    tree.write("/disk.img", &image(Some(1)), START);
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(storage.hooks.take(), (3, 0));
+    assert!(storage
+        .inner
+        .names()
+        .iter()
+        .all(|name| !name.starts_with("/.azure_blob_backup/blocks")));
+
+    let target = restore_all(&storage, Selection::Latest, None).await;
+    assert!(std::fs::read_to_string(target.path("/disk.img")).unwrap() == image(Some(1)));
+}
+
+#[tokio::test]
+async fn copies_are_checked_against_the_local_blocks() {
+    let tree = Tree::new();
+    tree.write("/disk.img", &image(None), START - DAY);
+
+    let storage = Hooked::new(Counting::default());
+    let conf = config(&tree.root(), "block_incremental: true\n");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    // The stored blob no longer holds the blocks its block map describes
+    let name = blob_name("/disk.img", &versions(&storage, "/disk.img").await[0]);
+    storage
+        .inner
+        .put(&name, image(Some(0)).into_bytes().into())
+        .await
+        .unwrap();
+
+    tree.write("/disk.img", &image(Some(1)), START);
+    let report = backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(report.failed.len(), 1);
+    assert!(
+        report.failed[0].1.contains("differ"),
+        "{}",
+        report.failed[0].1
+    );
+    assert_eq!(versions(&storage, "/disk.img").await.len(), 1);
+}
+
+#[tokio::test]
+async fn blocks_are_copied_through_the_local_backend() {
+    let tree = Tree::new();
+    tree.write("/disk.img", &image(None), START - DAY);
+
+    let storage_dir = Tree::new();
+    let conf = config(
+        &tree.root(),
+        &format!(
+            "backend: local\nstorage_path: {}\nblock_incremental: true\n",
+            storage_dir.root()
+        ),
+    );
+    let storage = storage::from_config(&conf).unwrap();
+    let storage = storage.as_ref();
+    backup::run(&conf, storage, START).await.unwrap();
+
+    tree.write("/disk.img", &image(Some(0)), START);
+    backup::run(&conf, storage, START + DAY).await.unwrap();
+
+    let target = restore_all(storage, Selection::Latest, None).await;
+    assert!(std::fs::read_to_string(target.path("/disk.img")).unwrap() == image(Some(0)));
+}
+
+#[tokio::test]
+async fn encrypted_files_are_uploaded_in_full() {
+    let tree = Tree::new();
+    tree.write("/disk.img", &image(None), START - DAY);
+
+    let storage = Hooked::new(Counting::default());
+    let conf = config(
+        &tree.root(),
+        &format!("block_incremental: true\nencryption_key: \"{KEY}\"\n"),
+    );
+    backup::run(&conf, &storage, START).await.unwrap();
+    tree.write("/disk.img", &image(Some(1)), START);
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+
+    assert_eq!(storage.hooks.take(), (6, 0));
+    let key = crypto::from_config(&conf).unwrap();
+    let target = restore_all(&storage, Selection::Latest, key.as_ref()).await;
+    assert!(std::fs::read_to_string(target.path("/disk.img")).unwrap() == image(Some(1)));
+}
+
+#[tokio::test]
+async fn block_maps_are_deleted_with_their_versions() {
+    let tree = Tree::new();
+    tree.write("/disk.img", &image(None), START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "block_incremental: true\n");
+    backup::run(&conf, &storage, START).await.unwrap();
+    let maps = |storage: &MemoryStorage| {
+        storage
+            .names()
+            .into_iter()
+            .filter(|name| name.starts_with("/.azure_blob_backup/blocks/"))
+            .count()
+    };
+    assert_eq!(maps(&storage), 1);
+
+    tree.remove("/disk.img");
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    backup::run(&conf, &storage, START + 400 * DAY)
+        .await
+        .unwrap();
+    assert!(versions(&storage, "/disk.img").await.is_empty());
+    assert_eq!(maps(&storage), 0);
+}
+
+#[test]
+fn deduplication_and_block_incremental_exclude_each_other() {
+    assert!(try_config("/", "block_incremental: true\ndeduplication: true\n").is_err());
+}
@@ -0,0 +1,142 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup, index::Selection, list, storage::memory::MemoryStorage, timestamp::format,
+};
+use common::{config, Tree, DAY, HOUR, START};
+use std::{
+    fs::Permissions,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+};
+
+async fn run(storage: &MemoryStorage, path: &str, children: bool, selection: Selection) -> String {
+    let options = list::Options {
+        path: path.to_string(),
+        children,
+        selection,
+    };
+    let mut out = Vec::new();
+    list::run(storage, &options, &mut out).await.unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+/// A tree with two versions of `/a.txt` and a folder with some unusual modes, backed up at START
+/// and START + DAY. Returns the owner of the files as listed.
+async fn backed_up(tree: &Tree, storage: &MemoryStorage) -> String {
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/big.bin", &"x".repeat(3 << 10), START - DAY);
+    tree.write("/dir/run.sh", "#!/bin/sh", START - DAY);
+    tree.write("/dir/gone.txt", "bye", START - DAY);
+    std::fs::create_dir(tree.path("/dir/shared")).unwrap();
+    tree.symlink("/dir/link", "big.bin");
+    for (path, mode) in [
+        ("/a.txt", 0o644),
+        ("/dir/run.sh", 0o4754),
+        ("/dir/shared", 0o1777),
+    ] {
+        std::fs::set_permissions(tree.path(path), Permissions::from_mode(mode)).unwrap();
+    }
+
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, storage, START).await.unwrap();
+    tree.write("/a.txt", "hello again", START + HOUR);
+    tree.remove("/dir/gone.txt");
+    backup::run(&conf, storage, START + DAY).await.unwrap();
+
+    let metadata = std::fs::metadata(tree.path("/a.txt")).unwrap();
+    format!("{}:{}", metadata.uid(), metadata.gid())
+}
+
+#[tokio::test]
+async fn versions_are_listed_oldest_first() {
+    let tree = Tree::new();
+    let storage = MemoryStorage::new();
+    let owner = backed_up(&tree, &storage).await;
+
+    let output = run(&storage, "/a.txt", false, Selection::Latest).await;
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "INDEX UPLOADED             TYPE     MODE             OWNER       SIZE  MODIFIED           "
+                .to_string(),
+            format!(
+                "0     {}  Regular  -rw-r--r-- {:>11}        5 B  {}",
+                format(START),
+                owner,
+                format(START - DAY)
+            ),
+            format!(
+                "1     {}  Regular  -rw-r--r-- {:>11}       11 B  {}",
+                format(START + DAY),
+                owner,
+                format(START + HOUR)
+            ),
+        ]
+    );
+
+    // Deletion markers are listed, but have no mode, size or modification time
+    let output = run(&storage, "/dir/gone.txt", false, Selection::Latest).await;
+    let marker = output.lines().last().unwrap();
+    assert!(marker.starts_with(&format!("1     {}  Deleted  -", format(START + DAY))));
+    assert!(marker.trim_end().ends_with("-  -"), "{marker}");
+}
+
+#[tokio::test]
+async fn children_are_listed_like_ls() {
+    let tree = Tree::new();
+    let storage = MemoryStorage::new();
+    let owner = backed_up(&tree, &storage).await;
+    let modified = format(START - DAY);
+
+    // Deleted files are left out, folders get a trailing slash
+    let output = run(&storage, "/dir", true, Selection::Latest).await;
+    let lines: Vec<&str> = output.lines().collect();
+    assert_eq!(lines.len(), 4, "{output}");
+    assert_eq!(
+        lines[0],
+        format!("-rw-r--r-- {owner:>11}    3.0 KiB  {modified}  big.bin")
+    );
+    assert!(lines[1].starts_with("lrwxrwxrwx"), "{}", lines[1]);
+    assert!(lines[1].contains("      -  "), "{}", lines[1]);
+    assert!(lines[1].ends_with("  link"), "{}", lines[1]);
+    assert!(lines[2].starts_with("-rwsr-xr-- "), "{}", lines[2]);
+    assert!(lines[2].ends_with("  run.sh"), "{}", lines[2]);
+    assert!(lines[3].starts_with("drwxrwxrwt "), "{}", lines[3]);
+    assert!(lines[3].ends_with("  shared/"), "{}", lines[3]);
+
+    // The folder as it was before the file was deleted
+    let output = run(&storage, "/dir", true, Selection::At(START)).await;
+    assert!(output.lines().any(|line| line.ends_with("  gone.txt")));
+    let output = run(&storage, "/dir", true, Selection::Index(0)).await;
+    assert!(output.lines().any(|line| line.ends_with("  gone.txt")));
+}
+
+#[tokio::test]
+async fn paths_without_versions_are_an_error() {
+    let storage = MemoryStorage::new();
+    let options = list::Options {
+        path: "/missing.txt".to_string(),
+        children: false,
+        selection: Selection::Latest,
+    };
+    let mut out = Vec::new();
+    assert!(list::run(&storage, &options, &mut out).await.is_err());
+}
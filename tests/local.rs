@@ -0,0 +1,145 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup,
+    index::{blob_name, FileType},
+    restore,
+    storage::{block_id, local::LocalStorage, Storage},
+};
+use bytes::Bytes;
+use common::{config, restore_options, versions, Tree, DAY, HOUR, START};
+
+#[tokio::test]
+async fn versions_are_stored_as_files_below_their_path() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/b.txt", "world", START - DAY);
+
+    let storage_dir = Tree::new();
+    let storage = LocalStorage::new(&storage_dir.root()).unwrap();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    for (path, content) in [("/a.txt", "hello"), ("/dir/b.txt", "world")] {
+        let version = &versions(&storage, path).await[0];
+        let file = storage_dir.path(&format!("/blobs{}", blob_name(path, version)));
+        assert_eq!(std::fs::read_to_string(file).unwrap(), content, "{path}");
+    }
+
+    // The versions of the backup root lie directly in the blobs directory, and are listed with
+    // the two leading slashes of their blob names
+    let root = &versions(&storage, "/").await[0];
+    assert_eq!(root.file_type, FileType::Folder);
+    assert!(storage_dir
+        .path(&format!("/blobs/{}", root.serialize()))
+        .is_file());
+    let names = storage.list("/").await.unwrap();
+    assert!(
+        names.contains(&format!("//{}", root.serialize())),
+        "{names:?}"
+    );
+    assert!(
+        names.iter().all(|name| !name.starts_with("///")),
+        "{names:?}"
+    );
+    assert_eq!(names.len(), 4, "{names:?}");
+}
+
+#[tokio::test]
+async fn pruned_versions_are_removed_from_disk() {
+    let tree = Tree::new();
+    tree.write("/keep.txt", "kept", START - DAY);
+    let storage_dir = Tree::new();
+    let storage = LocalStorage::new(&storage_dir.root()).unwrap();
+    let conf = config(
+        &tree.root(),
+        "num_daily: 3\nnum_weekly: 0\nnum_monthly: 0\n",
+    );
+
+    for i in 0..10 {
+        tree.write("/dir/a.txt", &format!("day {i}"), START + i * DAY - HOUR);
+        backup::run(&conf, &storage, START + i * DAY).await.unwrap();
+    }
+
+    // The same versions survive as with any other backend, see sync.rs
+    let mut stored: Vec<String> = std::fs::read_dir(storage_dir.path("/blobs/dir/a.txt"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    stored.sort();
+    let expected: Vec<String> = versions(&storage, "/dir/a.txt")
+        .await
+        .iter()
+        .map(|version| version.serialize())
+        .collect();
+    assert_eq!(stored, expected);
+    let upload_times: Vec<u64> = versions(&storage, "/dir/a.txt")
+        .await
+        .iter()
+        .map(|version| version.upload_time)
+        .collect();
+    assert_eq!(upload_times, [6, 7, 8, 9].map(|day| START + day * DAY));
+
+    let target = Tree::new();
+    restore::run(&storage, None, &restore_options(&target))
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(target.path("/dir/a.txt")).unwrap(),
+        "day 9"
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.path("/keep.txt")).unwrap(),
+        "kept"
+    );
+
+    // Once all versions of a file are gone, so is its directory
+    std::fs::remove_file(tree.path("/dir/a.txt")).unwrap();
+    std::fs::remove_dir(tree.path("/dir")).unwrap();
+    backup::run(&conf, &storage, START + 10 * DAY)
+        .await
+        .unwrap();
+    backup::run(&conf, &storage, START + 20 * DAY)
+        .await
+        .unwrap();
+    assert!(!storage_dir.path("/blobs/dir").exists());
+    assert!(storage_dir.path("/blobs/keep.txt").is_dir());
+}
+
+#[tokio::test]
+async fn partially_written_blocks_are_not_staged() {
+    let storage_dir = Tree::new();
+    let storage = LocalStorage::new(&storage_dir.root()).unwrap();
+
+    let complete = block_id("/a.txt/1", 0);
+    let torn = block_id("/a.txt/1", 1);
+    storage
+        .put_block("/a.txt/1", &complete, Bytes::from_static(b"hello"))
+        .await
+        .unwrap();
+    // What a crash while writing the second block leaves behind
+    let dir = storage_dir.path(&format!("/staging/{}", sha256::digest("/a.txt/1")));
+    std::fs::write(dir.join(torn + ".tmp"), b"wor").unwrap();
+
+    let staged = storage.staged_blocks("/a.txt/1").await.unwrap();
+    assert_eq!(staged.len(), 1);
+    assert_eq!(staged[&complete], 5);
+}
@@ -0,0 +1,190 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup,
+    config::Config,
+    index::blob_name,
+    storage::{self, s3::MAX_PARTS},
+};
+use bytes::Bytes;
+use common::{config, large_content, versions, Tree, DAY, HOUR, START};
+use s3::{bucket_ops::BucketConfiguration, creds::Credentials, Bucket, Region};
+
+/// The endpoint of the MinIO server, e.g. `http://127.0.0.1:9000`. The tests talking to it are
+/// ignored unless run with `--ignored`, and then require it to be set.
+const ENDPOINT_VAR: &str = "MINIO_ENDPOINT";
+
+/// The default credentials of a MinIO server.
+const ACCESS_KEY: &str = "minioadmin";
+const SECRET_KEY: &str = "minioadmin";
+
+fn endpoint(test: &str) -> String {
+    std::env::var(ENDPOINT_VAR)
+        .unwrap_or_else(|_| panic!("{ENDPOINT_VAR} has to be set to run {test}"))
+}
+
+fn bucket(name: &str, endpoint: &str) -> Box<Bucket> {
+    let region = Region::Custom {
+        region: "us-east-1".to_string(),
+        endpoint: endpoint.to_string(),
+    };
+    let credentials =
+        Credentials::new(Some(ACCESS_KEY), Some(SECRET_KEY), None, None, None).unwrap();
+    Bucket::new(name, region, credentials)
+        .unwrap()
+        .with_path_style()
+}
+
+/// Creates an empty bucket in MinIO and returns its name and a config backing up `root` into it.
+async fn minio_config(test: &str, root: &str, extra: &str) -> (String, Config) {
+    let endpoint = endpoint(test);
+
+    // Every test gets its own bucket, so tests can run in parallel and reruns start empty
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let name = format!("{}-{}", test.replace('_', "-"), nanos);
+    let bucket = bucket(&name, &endpoint);
+    Bucket::create_with_path_style(
+        &name,
+        bucket.region(),
+        bucket.credentials().await.unwrap(),
+        BucketConfiguration::default(),
+    )
+    .await
+    .unwrap();
+
+    let conf = config(
+        root,
+        &format!(
+            "backend: s3\ns3_bucket: {name}\ns3_endpoint: {endpoint}\n\
+             s3_access_key: {ACCESS_KEY}\ns3_secret_key: {SECRET_KEY}\n{extra}"
+        ),
+    );
+    (name, conf)
+}
+
+/// The multipart uploads in the bucket that were neither completed nor aborted.
+async fn open_uploads(test: &str, name: &str) -> usize {
+    bucket(name, &endpoint(test))
+        .list_multiparts_uploads(None, None)
+        .await
+        .unwrap()
+        .iter()
+        .map(|page| page.uploads.len())
+        .sum()
+}
+
+#[tokio::test]
+#[ignore = "needs MinIO, see MINIO_ENDPOINT"]
+async fn backup_cycle_on_minio() {
+    let test = "backup_cycle_on_minio";
+    let tree = Tree::new();
+    let (name, conf) = minio_config(test, &tree.root(), "").await;
+    let storage = storage::from_config(&conf).unwrap();
+    let storage = storage.as_ref();
+
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/empty.txt", "", START - DAY);
+    tree.write("/dir/large.bin", &large_content(), START - DAY);
+    tree.symlink("/link", "a.txt");
+
+    backup::run(&conf, storage, START).await.unwrap();
+    for path in [
+        "/",
+        "/a.txt",
+        "/empty.txt",
+        "/dir",
+        "/dir/large.bin",
+        "/link",
+    ] {
+        assert_eq!(versions(storage, path).await.len(), 1, "{path}");
+    }
+    let large = &versions(storage, "/dir/large.bin").await[0];
+    let content = storage
+        .get_content(&blob_name("/dir/large.bin", large))
+        .await
+        .unwrap();
+    assert!(content == large_content().into_bytes());
+    let checksum = storage
+        .checksum(&blob_name("/dir/large.bin", large))
+        .await
+        .unwrap();
+    assert!(checksum.is_some());
+
+    tree.write("/a.txt", "hello again", START + HOUR);
+    backup::run(&conf, storage, START + DAY).await.unwrap();
+    let modified = versions(storage, "/a.txt").await;
+    assert_eq!(modified.len(), 2);
+    let content = storage
+        .get_content(&blob_name("/a.txt", &modified[1]))
+        .await
+        .unwrap();
+    assert_eq!(content, b"hello again");
+
+    assert_eq!(open_uploads(test, &name).await, 0);
+}
+
+#[tokio::test]
+#[ignore = "needs MinIO, see MINIO_ENDPOINT"]
+async fn unfinished_uploads_are_aborted() {
+    let test = "unfinished_uploads_are_aborted";
+    let (name, conf) = minio_config(test, "/", "").await;
+    let block = Bytes::from(vec![b'a'; 6 << 20]);
+
+    // A block list that doesn't match the staged blocks fails the upload
+    let storage = storage::from_config(&conf).unwrap();
+    storage
+        .put_block("/a.bin/1", "0", block.clone())
+        .await
+        .unwrap();
+    assert_eq!(open_uploads(test, &name).await, 1);
+    assert!(storage
+        .put_block_list("/a.bin/1", &["1".to_string()], "")
+        .await
+        .is_err());
+    assert_eq!(open_uploads(test, &name).await, 0);
+
+    // An upload left open by an earlier run is discarded by the next one
+    storage.put_block("/b.bin/1", "0", block).await.unwrap();
+    drop(storage);
+    let storage = storage::from_config(&conf).unwrap();
+    storage.discard_staged("/b.bin/1").await.unwrap();
+    assert_eq!(open_uploads(test, &name).await, 0);
+}
+
+#[test]
+fn large_files_stay_within_the_part_limit() {
+    let conf = config(
+        "/",
+        "backend: s3\ns3_bucket: backup\ns3_endpoint: http://127.0.0.1:9\n\
+         s3_access_key: key\ns3_secret_key: secret\n",
+    );
+    let storage = storage::from_config(&conf).unwrap();
+    assert_eq!(storage.max_blocks(), MAX_PARTS);
+
+    // Every part holds at least one block, so files need at most as many parts as blocks. S3 objects
+    // are at most 5TiB large.
+    for len in [80u64 << 30, 1 << 40, 5 << 40] {
+        let block_size = backup::block_size(len, storage.max_blocks());
+        assert!(len.div_ceil(block_size) <= MAX_PARTS, "{len}");
+    }
+}
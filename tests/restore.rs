@@ -0,0 +1,367 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup,
+    index::{blob_name, Selection, Version},
+    restore,
+    storage::{memory::MemoryStorage, Storage},
+};
+use bytes::Bytes;
+use common::{config, restore_options, Tree, DAY, HOUR, START};
+use std::{
+    fs::Permissions,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+};
+
+#[tokio::test]
+async fn paths_leaving_the_target_are_rejected() {
+    let outer = Tree::new();
+    let target = outer.path("/target").to_str().unwrap().to_string();
+
+    for path in ["/../evil.txt", "/dir/../../evil.txt"] {
+        let storage = MemoryStorage::new();
+        let version =
+            Version::try_from(format!("{}-{}-100644-4-Regular-0-0", START, START).as_str())
+                .unwrap();
+        storage
+            .put(&blob_name(path, &version), Bytes::from_static(b"evil"))
+            .await
+            .unwrap();
+
+        let options = restore::Options {
+            target: target.clone(),
+            ..restore_options(&outer)
+        };
+        assert!(restore::run(&storage, None, &options).await.is_err());
+        assert!(!outer.path("/evil.txt").exists(), "{path}");
+    }
+}
+
+#[tokio::test]
+async fn files_are_not_restored_below_symlinks() {
+    let outside = Tree::new();
+    let tree = Tree::new();
+    tree.write("/dir/a.txt", "hello", START - DAY);
+
+    // The folder is replaced by a symlink pointing out of the tree
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, &storage, START).await.unwrap();
+    tree.remove("/dir/a.txt");
+    std::fs::remove_dir(tree.path("/dir")).unwrap();
+    tree.symlink("/dir", &outside.root());
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+
+    // Restoring the old version of the file below the new version of the folder
+    let target = Tree::new();
+    restore::run(&storage, None, &restore_options(&target))
+        .await
+        .unwrap();
+    let options = restore::Options {
+        path: "/dir/a.txt".to_string(),
+        selection: Selection::Index(0),
+        ..restore_options(&target)
+    };
+    assert!(restore::run(&storage, None, &options).await.is_err());
+    assert!(!outside.path("/a.txt").exists());
+}
+
+#[tokio::test]
+async fn symlinks_in_the_way_are_replaced_instead_of_followed() {
+    let outside = Tree::new();
+    outside.write("/victim.txt", "untouched", START - DAY);
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    let target = Tree::new();
+    target.symlink("/a.txt", outside.path("/victim.txt").to_str().unwrap());
+    restore::run(&storage, None, &restore_options(&target))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(outside.path("/victim.txt")).unwrap(),
+        "untouched"
+    );
+    assert!(!std::fs::symlink_metadata(target.path("/a.txt"))
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!(
+        std::fs::read_to_string(target.path("/a.txt")).unwrap(),
+        "hello"
+    );
+}
+
+#[tokio::test]
+async fn the_tree_is_restored_as_it_was_at_a_given_time() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "first", START - DAY);
+    tree.write("/b.txt", "removed later", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    tree.write("/a.txt", "second", START + HOUR);
+    tree.remove("/b.txt");
+    tree.write("/c.txt", "added later", START + HOUR);
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+
+    let restore_at = |target: &Tree, at| restore::Options {
+        selection: Selection::At(at),
+        ..restore_options(target)
+    };
+
+    // Anything before the second run sees the first one
+    let target = Tree::new();
+    restore::run(&storage, None, &restore_at(&target, START + DAY - 1))
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(target.path("/a.txt")).unwrap(),
+        "first"
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.path("/b.txt")).unwrap(),
+        "removed later"
+    );
+    assert!(!target.path("/c.txt").exists());
+
+    // The second run counts from its upload time on, deleted files are absent
+    let target = Tree::new();
+    restore::run(&storage, None, &restore_at(&target, START + DAY))
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(target.path("/a.txt")).unwrap(),
+        "second"
+    );
+    assert!(!target.path("/b.txt").exists());
+    assert_eq!(
+        std::fs::read_to_string(target.path("/c.txt")).unwrap(),
+        "added later"
+    );
+
+    // Nothing was backed up yet before the first run
+    let target = Tree::new();
+    restore::run(&storage, None, &restore_at(&target, START - 1))
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read_dir(target.root()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn single_files_and_subtrees_are_restored_alone() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/b.txt", "world", START - DAY);
+    tree.write("/dir/sub/c.txt", "nested", START - DAY);
+    tree.write("/dir2/d.txt", "sibling", START - DAY);
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    let restore_path = |target: &Tree, path: &str| restore::Options {
+        path: path.to_string(),
+        ..restore_options(target)
+    };
+
+    let target = Tree::new();
+    restore::run(&storage, None, &restore_path(&target, "/dir/b.txt"))
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(target.path("/dir/b.txt")).unwrap(),
+        "world"
+    );
+    assert!(!target.path("/a.txt").exists());
+    assert!(!target.path("/dir/sub").exists());
+
+    // Siblings sharing the name prefix are not below the subtree
+    let target = Tree::new();
+    restore::run(&storage, None, &restore_path(&target, "/dir"))
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(target.path("/dir/b.txt")).unwrap(),
+        "world"
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.path("/dir/sub/c.txt")).unwrap(),
+        "nested"
+    );
+    assert!(!target.path("/a.txt").exists());
+    assert!(!target.path("/dir2").exists());
+}
+
+#[tokio::test]
+async fn versions_are_selected_by_their_position_in_the_history() {
+    let tree = Tree::new();
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "");
+    for i in 0..3 {
+        tree.write("/a.txt", &format!("version {i}"), START + i * DAY - HOUR);
+        backup::run(&conf, &storage, START + i * DAY).await.unwrap();
+    }
+
+    for (idx, content) in [(0, "version 0"), (1, "version 1"), (-1, "version 2")] {
+        let target = Tree::new();
+        let options = restore::Options {
+            path: "/a.txt".to_string(),
+            selection: Selection::Index(idx),
+            ..restore_options(&target)
+        };
+        restore::run(&storage, None, &options).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(target.path("/a.txt")).unwrap(),
+            content,
+            "{idx}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn version_indexes_beyond_the_history_are_rejected() {
+    let tree = Tree::new();
+    tree.write("/dir/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    for (path, idx) in [("/dir/a.txt", 1), ("/dir/a.txt", -2), ("/dir", 1)] {
+        let target = Tree::new();
+        let options = restore::Options {
+            path: path.to_string(),
+            selection: Selection::Index(idx),
+            ..restore_options(&target)
+        };
+        assert!(
+            restore::run(&storage, None, &options).await.is_err(),
+            "{path} {idx}"
+        );
+        assert_eq!(std::fs::read_dir(target.root()).unwrap().count(), 0);
+    }
+}
+
+#[tokio::test]
+async fn permissions_are_restored() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/run.sh", "#!/bin/sh", START - DAY);
+    let modes = [("/a.txt", 0o640), ("/dir", 0o750), ("/dir/run.sh", 0o4755)];
+    for (path, mode) in modes {
+        std::fs::set_permissions(tree.path(path), Permissions::from_mode(mode)).unwrap();
+    }
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    let target = Tree::new();
+    restore::run(&storage, None, &restore_options(&target))
+        .await
+        .unwrap();
+
+    // The setuid bit survives the ownership being applied
+    for (path, mode) in modes {
+        let metadata = std::fs::metadata(target.path(path)).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o7777, mode, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn owners_and_groups_are_mapped() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/b.txt", "world", START - DAY);
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+    let owner = std::fs::metadata(tree.path("/a.txt")).unwrap();
+
+    let target = Tree::new();
+    let mut options = restore_options(&target);
+    options
+        .uid_map
+        .add(&format!("{}:4242", owner.uid()))
+        .unwrap();
+    options.gid_map.add("*:4343").unwrap();
+    let restored = restore::run(&storage, None, &options).await;
+
+    // Only root may hand files to other users
+    if unsafe { libc::geteuid() } != 0 {
+        assert!(restored.is_err());
+        return;
+    }
+    restored.unwrap();
+    for path in ["/a.txt", "/b.txt"] {
+        let metadata = std::fs::metadata(target.path(path)).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (4242, 4343), "{path}");
+    }
+
+    // Ids without a mapping are kept
+    let target = Tree::new();
+    let mut options = restore_options(&target);
+    options.uid_map.add("4444:4242").unwrap();
+    restore::run(&storage, None, &options).await.unwrap();
+
+    let metadata = std::fs::metadata(target.path("/a.txt")).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (owner.uid(), owner.gid()));
+}
+
+#[tokio::test]
+async fn unmapped_ids_are_left_to_the_restoring_user() {
+    if unsafe { libc::geteuid() } == 0 {
+        // Root restores the stored ids, see owners_and_groups_are_mapped
+        return;
+    }
+
+    // Stored as owned by ids this user can't hand files to
+    let storage = MemoryStorage::new();
+    let version =
+        Version::try_from(format!("{}-{}-100644-5-Regular-4242-4343", START, START).as_str())
+            .unwrap();
+    storage
+        .put(&blob_name("/a.txt", &version), Bytes::from_static(b"hello"))
+        .await
+        .unwrap();
+
+    let target = Tree::new();
+    restore::run(&storage, None, &restore_options(&target))
+        .await
+        .unwrap();
+
+    let metadata = std::fs::metadata(target.path("/a.txt")).unwrap();
+    let user = std::fs::metadata(target.root()).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (user.uid(), user.gid()));
+}
@@ -0,0 +1,173 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use azure_blob_backup::{
+    backup,
+    index::blob_name,
+    storage::{local::LocalStorage, memory::MemoryStorage, Storage},
+};
+use bytes::Bytes;
+use common::{config, versions, Hooked, Hooks, Tree, DAY, START};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const BLOCK: usize = 4 << 20;
+
+/// Counts the staged blocks and fails every block after the first `limit`, like a run that is
+/// interrupted halfway through a file.
+struct Interrupted {
+    limit: usize,
+    blocks: AtomicUsize,
+}
+
+#[async_trait]
+impl Hooks for Interrupted {
+    async fn put_block(
+        &self,
+        inner: &dyn Storage,
+        name: &str,
+        block_id: &str,
+        data: Bytes,
+    ) -> Result<()> {
+        if self.blocks.fetch_add(1, Ordering::SeqCst) >= self.limit {
+            return Err(anyhow!("The connection was lost"));
+        }
+        inner.put_block(name, block_id, data).await
+    }
+}
+
+impl Interrupted {
+    fn blocks(&self) -> usize {
+        self.blocks.load(Ordering::SeqCst)
+    }
+}
+
+fn interrupt_after(inner: &dyn Storage, limit: usize) -> Hooked<Interrupted, &dyn Storage> {
+    Hooked {
+        inner,
+        hooks: Interrupted {
+            limit,
+            blocks: AtomicUsize::new(0),
+        },
+    }
+}
+
+/// Five blocks of distinct content, starting with `first`.
+fn disk_image(first: u8) -> String {
+    (0..(4 * BLOCK + BLOCK / 2))
+        .map(|i| (first + (i / BLOCK) as u8) as char)
+        .collect()
+}
+
+async fn markers(storage: &dyn Storage) -> Vec<String> {
+    storage.list("/.azure_blob_backup/uploads/").await.unwrap()
+}
+
+#[tokio::test]
+async fn interrupted_uploads_are_resumed() {
+    let content = disk_image(b'a');
+    for extra in ["", "compression: true\n"] {
+        let dir = tempfile::tempdir().unwrap();
+        let backends: Vec<Box<dyn Storage>> = vec![
+            Box::new(MemoryStorage::new()),
+            Box::new(LocalStorage::new(dir.path().to_str().unwrap()).unwrap()),
+        ];
+        for backend in backends {
+            let tree = Tree::new();
+            tree.write("/disk.img", &content, START - DAY);
+            let conf = config(
+                &tree.root(),
+                &format!("block_upload_concurrency: 1\n{extra}"),
+            );
+
+            let storage = interrupt_after(backend.as_ref(), 3);
+            let report = backup::run(&conf, &storage, START).await.unwrap();
+            assert_eq!(report.failed.len(), 1);
+            assert!(versions(&storage, "/disk.img").await.is_empty());
+            assert_eq!(markers(&storage).await.len(), 1);
+
+            // The next night only uploads the missing blocks, under the interrupted version
+            let storage = interrupt_after(backend.as_ref(), usize::MAX);
+            let report = backup::run(&conf, &storage, START + DAY).await.unwrap();
+            assert!(report.failed.is_empty());
+            assert_eq!(storage.hooks.blocks(), 2);
+            assert!(markers(&storage).await.is_empty());
+
+            let stored = versions(&storage, "/disk.img").await;
+            assert_eq!(stored.len(), 1);
+            assert_eq!(stored[0].upload_time, START);
+            let restored = storage
+                .get_content(&blob_name("/disk.img", &stored[0]))
+                .await
+                .unwrap();
+            let restored = match stored[0].compressed {
+                true => zstd::decode_all(restored.as_slice()).unwrap(),
+                false => restored,
+            };
+            assert!(restored == content.as_bytes(), "{extra}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn changed_files_are_uploaded_from_scratch() {
+    let tree = Tree::new();
+    tree.write("/disk.img", &disk_image(b'a'), START - DAY);
+    let conf = config(&tree.root(), "block_upload_concurrency: 1\n");
+
+    let memory = MemoryStorage::new();
+    let storage = interrupt_after(&memory, 3);
+    backup::run(&conf, &storage, START).await.unwrap();
+    let interrupted = markers(&memory).await[0]
+        .trim_start_matches("/.azure_blob_backup/uploads")
+        .to_string();
+    assert_eq!(memory.staged_blocks(&interrupted).await.unwrap().len(), 3);
+
+    tree.write("/disk.img", &disk_image(b'f'), START);
+    let storage = interrupt_after(&memory, usize::MAX);
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(storage.hooks.blocks(), 5);
+    assert!(markers(&memory).await.is_empty());
+    assert!(memory.staged_blocks(&interrupted).await.unwrap().is_empty());
+
+    let stored = versions(&memory, "/disk.img").await;
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].upload_time, START + DAY);
+}
+
+#[tokio::test]
+async fn encrypted_uploads_are_not_resumed() {
+    let tree = Tree::new();
+    tree.write("/disk.img", &disk_image(b'a'), START - DAY);
+    let conf = config(
+        &tree.root(),
+        "block_upload_concurrency: 1\nencryption_key: \"000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\"\n",
+    );
+
+    let memory = MemoryStorage::new();
+    let storage = interrupt_after(&memory, 3);
+    backup::run(&conf, &storage, START).await.unwrap();
+    assert!(markers(&memory).await.is_empty());
+
+    let storage = interrupt_after(&memory, usize::MAX);
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(storage.hooks.blocks(), 5);
+    assert_eq!(versions(&memory, "/disk.img").await.len(), 1);
+}
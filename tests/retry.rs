@@ -0,0 +1,222 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use azure_blob_backup::{
+    backup,
+    index::blob_name,
+    storage::{
+        retry::{self, Retrying},
+        Md5Mismatch, Storage,
+    },
+};
+use bytes::Bytes;
+use common::{config, try_config, versions, Hooked, Hooks, Tree, DAY, START};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Fails every call whose number is a multiple of `fail_every` with the error made by `error`.
+struct Flaky {
+    fail_every: usize,
+    error: fn() -> anyhow::Error,
+    calls: Arc<AtomicUsize>,
+}
+
+impl Hooks for Flaky {
+    fn before(&self, _call: &str, _name: &str) -> Result<()> {
+        let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+        if call.is_multiple_of(self.fail_every) {
+            return Err((self.error)());
+        }
+        Ok(())
+    }
+}
+
+fn timeout() -> anyhow::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out").into()
+}
+
+fn damaged() -> anyhow::Error {
+    Md5Mismatch("The storage reported a different MD5".to_string()).into()
+}
+
+fn flaky(
+    fail_every: usize,
+    error: fn() -> anyhow::Error,
+    extra: &str,
+) -> (Retrying, Arc<AtomicUsize>) {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let storage = Hooked::new(Flaky {
+        fail_every,
+        error,
+        calls: calls.clone(),
+    });
+    let policy = retry::from_config(&config("/", &format!("retry_backoff_ms: 1\n{extra}")));
+    (Retrying::new(Box::new(storage), policy), calls)
+}
+
+#[tokio::test]
+async fn transient_errors_are_retried() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/b.txt", "world", START - DAY);
+
+    // Every other call times out or has its data damaged on the way
+    for error in [timeout, damaged] {
+        let (storage, calls) = flaky(2, error, "");
+        backup::run(&config(&tree.root(), ""), &storage, START)
+            .await
+            .unwrap();
+        assert!(calls.load(Ordering::SeqCst) > 10);
+
+        let version = &versions(&storage, "/dir/b.txt").await[0];
+        let content = storage
+            .get_content(&blob_name("/dir/b.txt", version))
+            .await
+            .unwrap();
+        assert_eq!(content, b"world");
+    }
+}
+
+#[tokio::test]
+async fn retries_give_up_eventually() {
+    let (storage, calls) = flaky(1, timeout, "retry_attempts: 3\n");
+    assert!(storage.list("/").await.is_err());
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    // Errors that would fail again aren't retried at all
+    let (storage, calls) = flaky(1, || anyhow!("The specified blob does not exist"), "");
+    assert!(storage.delete("/a.txt/1").await.is_err());
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+/// Deletes blobs, but loses the response to the first deletion of every blob, and rejects the
+/// deletions of blobs below `/stuck`.
+#[derive(Default)]
+struct LosingDeletes {
+    deleted: std::sync::Mutex<Vec<String>>,
+}
+
+#[async_trait]
+impl Hooks for LosingDeletes {
+    async fn delete(&self, inner: &dyn Storage, name: &str) -> Result<()> {
+        if name.starts_with("/stuck") {
+            return Err(anyhow!("The request to {} was rejected", name));
+        }
+        inner.delete(name).await?;
+        let mut deleted = self.deleted.lock().unwrap();
+        if deleted.iter().any(|blob| blob == name) {
+            return Ok(());
+        }
+        deleted.push(name.to_string());
+        Err(timeout())
+    }
+}
+
+#[tokio::test]
+async fn deletions_whose_response_was_lost_succeed() {
+    let policy = retry::from_config(&config("/", "retry_backoff_ms: 1\n"));
+    let storage = Retrying::new(Box::new(Hooked::new(LosingDeletes::default())), policy);
+    storage
+        .put("/a.txt/1", Bytes::from_static(b"hello"))
+        .await
+        .unwrap();
+
+    storage.delete("/a.txt/1").await.unwrap();
+    assert!(storage.list("/").await.unwrap().is_empty());
+
+    // A blob that never existed is still missing
+    assert!(storage.delete("/b.txt/1").await.is_err());
+}
+
+#[tokio::test]
+async fn failed_deletions_of_old_versions_dont_stop_the_run() {
+    let tree = Tree::new();
+    tree.write("/stuck.txt", "hello", START - DAY);
+    tree.write("/z.txt", "hello", START - DAY);
+
+    let conf = config(&tree.root(), "retry_backoff_ms: 1\n");
+    let policy = retry::from_config(&conf);
+    let storage = Retrying::new(Box::new(Hooked::new(LosingDeletes::default())), policy);
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    tree.remove("/stuck.txt");
+    tree.remove("/z.txt");
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    backup::run(&conf, &storage, START + 400 * DAY)
+        .await
+        .unwrap();
+    assert!(versions(&storage, "/z.txt").await.is_empty());
+    assert!(!versions(&storage, "/stuck.txt").await.is_empty());
+}
+
+#[test]
+fn errors_are_classified() {
+    use azure_core::{error::ErrorKind, StatusCode};
+    use s3::error::S3Error;
+
+    let azure = |status| -> anyhow::Error {
+        azure_core::error::Error::new(ErrorKind::http_response(status, None), "failed").into()
+    };
+    assert!(retry::is_transient(&azure(StatusCode::ServiceUnavailable)));
+    assert!(retry::is_transient(&azure(StatusCode::TooManyRequests)));
+    assert!(!retry::is_transient(&azure(StatusCode::NotFound)));
+    assert!(!retry::is_transient(&azure(StatusCode::Forbidden)));
+    assert!(retry::is_transient(
+        &azure(StatusCode::InternalServerError).context("Uploading /a.txt failed")
+    ));
+    let damaged = azure_core::error::Error::new(
+        ErrorKind::http_response(StatusCode::BadRequest, Some("Md5Mismatch".to_string())),
+        "failed",
+    );
+    assert!(retry::is_transient(&damaged.into()));
+    assert!(!retry::is_transient(&azure(StatusCode::BadRequest)));
+    assert!(retry::is_transient(
+        &Md5Mismatch("Azure reported a different MD5".to_string()).into()
+    ));
+
+    let s3 = |status| -> anyhow::Error { S3Error::HttpFailWithBody(status, String::new()).into() };
+    assert!(retry::is_transient(&s3(503)));
+    assert!(!retry::is_transient(&s3(403)));
+    let bad_digest = S3Error::HttpFailWithBody(400, "<Code>BadDigest</Code>".to_string());
+    assert!(retry::is_transient(&bad_digest.into()));
+    assert!(!retry::is_transient(&s3(400)));
+
+    assert!(retry::is_transient(&timeout()));
+    assert!(!retry::is_transient(&anyhow!("Malformed blob name")));
+
+    assert!(retry::is_not_found(&azure(StatusCode::NotFound)));
+    assert!(!retry::is_not_found(&azure(StatusCode::Forbidden)));
+    assert!(retry::is_not_found(&s3(404)));
+    assert!(!retry::is_not_found(&timeout()));
+}
+
+#[test]
+fn the_policy_is_validated() {
+    for extra in [
+        "retry_attempts: 0\n",
+        "retry_backoff_ms: -1\n",
+        "retry_jitter_percent: 101\n",
+    ] {
+        assert!(try_config("/", extra).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{
+    backup,
+    index::{blob_name, FileType},
+    restore,
+    storage::memory::MemoryStorage,
+};
+use common::{config, restore_options, versions, Tree, DAY, HOUR, START};
+
+#[tokio::test]
+async fn first_run_uploads_everything() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/b.txt", "world", START - DAY);
+    tree.symlink("/link", "a.txt");
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    for path in ["/", "/a.txt", "/dir", "/dir/b.txt", "/link"] {
+        assert_eq!(versions(&storage, path).await.len(), 1, "{path}");
+    }
+
+    let file = &versions(&storage, "/a.txt").await[0];
+    assert_eq!(file.file_type, FileType::Regular);
+    assert_eq!(file.upload_time, START);
+    assert_eq!(
+        storage.content(&blob_name("/a.txt", file)).unwrap(),
+        b"hello"
+    );
+
+    let link = &versions(&storage, "/link").await[0];
+    assert_eq!(link.file_type, FileType::Symlink);
+    assert_eq!(
+        storage.content(&blob_name("/link", link)).unwrap(),
+        b"a.txt"
+    );
+}
+
+#[tokio::test]
+async fn unchanged_files_are_not_uploaded_again() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, &storage, START).await.unwrap();
+    let before = storage.names();
+
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+    assert_eq!(storage.names(), before);
+}
+
+#[tokio::test]
+async fn changed_files_get_a_new_version() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    tree.write("/a.txt", "hello again", START + HOUR);
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+
+    let versions = versions(&storage, "/a.txt").await;
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[1].upload_time, START + DAY);
+    assert_eq!(
+        storage.content(&blob_name("/a.txt", &versions[1])).unwrap(),
+        b"hello again"
+    );
+}
+
+#[tokio::test]
+async fn changes_within_min_update_age_are_not_uploaded() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    tree.write("/a.txt", "hello again", START + HOUR);
+    backup::run(&conf, &storage, START + 2 * HOUR)
+        .await
+        .unwrap();
+
+    assert_eq!(versions(&storage, "/a.txt").await.len(), 1);
+}
+
+#[tokio::test]
+async fn deleted_files_are_marked() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    tree.remove("/a.txt");
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+
+    let marked = versions(&storage, "/a.txt").await;
+    assert_eq!(marked.len(), 2);
+    assert_eq!(marked[0].file_type, FileType::Regular);
+    assert_eq!(marked[1].file_type, FileType::Deleted);
+    assert_eq!(marked[1].upload_time, START + DAY);
+}
+
+#[tokio::test]
+async fn deletions_are_only_marked_once() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    tree.remove("/a.txt");
+    for i in 1..5 {
+        backup::run(&conf, &storage, START + i * DAY).await.unwrap();
+    }
+
+    let history: Vec<(u64, FileType)> = versions(&storage, "/a.txt")
+        .await
+        .iter()
+        .map(|v| (v.upload_time, v.file_type.clone()))
+        .collect();
+    assert_eq!(
+        history,
+        [(START, FileType::Regular), (START + DAY, FileType::Deleted)]
+    );
+}
+
+#[tokio::test]
+async fn old_versions_are_thinned_out() {
+    let tree = Tree::new();
+    let storage = MemoryStorage::new();
+    let conf = config(
+        &tree.root(),
+        "num_daily: 3\nnum_weekly: 0\nnum_monthly: 0\n",
+    );
+
+    for i in 0..10 {
+        tree.write("/a.txt", &format!("day {i}"), START + i * DAY - HOUR);
+        backup::run(&conf, &storage, START + i * DAY).await.unwrap();
+    }
+
+    // The oldest version of each of the three retained days, and always the current one
+    let versions = versions(&storage, "/a.txt").await;
+    let upload_times: Vec<u64> = versions.iter().map(|v| v.upload_time).collect();
+    assert_eq!(
+        upload_times,
+        [6, 7, 8, 9].map(|day| START + day * DAY),
+        "{versions:?}"
+    );
+    let newest = versions.last().unwrap();
+    assert_eq!(
+        storage.content(&blob_name("/a.txt", newest)).unwrap(),
+        b"day 9"
+    );
+}
+
+#[tokio::test]
+async fn weekly_and_monthly_versions_outlive_daily_ones() {
+    let tree = Tree::new();
+    let storage = MemoryStorage::new();
+    let conf = config(
+        &tree.root(),
+        "num_daily: 2\nnum_weekly: 1\nnum_monthly: 3\n",
+    );
+
+    let mut now = START;
+    for i in 0..100 {
+        now = START + i * DAY;
+        tree.write("/a.txt", &format!("day {i}"), now - HOUR);
+        backup::run(&conf, &storage, now).await.unwrap();
+    }
+
+    let upload_times: Vec<u64> = versions(&storage, "/a.txt")
+        .await
+        .iter()
+        .map(|v| v.upload_time)
+        .collect();
+    // A version reaches until the next version that is still stored, so once the versions after
+    // it are thinned out the first one is the oldest version in every week and month bucket. The
+    // last two days and the current version are kept besides it.
+    assert_eq!(
+        upload_times,
+        [0, 97, 98, 99].map(|day| START + day * DAY),
+        "{upload_times:?}"
+    );
+    assert_eq!(upload_times.last(), Some(&now));
+}
+
+#[tokio::test]
+async fn files_deleted_long_ago_are_removed_completely() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(
+        &tree.root(),
+        "num_daily: 2\nnum_weekly: 0\nnum_monthly: 0\n",
+    );
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    tree.remove("/a.txt");
+    backup::run(&conf, &storage, START + DAY).await.unwrap();
+
+    // Once the last version with content has aged out only deletion markers would be left
+    backup::run(&conf, &storage, START + 5 * DAY).await.unwrap();
+    assert!(versions(&storage, "/a.txt").await.is_empty());
+    assert!(storage.names().iter().all(|name| !name.contains("a.txt")));
+}
+
+#[tokio::test]
+async fn restore_reproduces_the_backed_up_tree() {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write("/dir/b.txt", "world", START - DAY);
+    tree.symlink("/link", "a.txt");
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    let target = Tree::new();
+    let options = restore_options(&target);
+    restore::run(&storage, None, &options).await.unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(target.path("/a.txt")).unwrap(),
+        "hello"
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.path("/dir/b.txt")).unwrap(),
+        "world"
+    );
+    assert_eq!(
+        std::fs::read_link(target.path("/link")).unwrap(),
+        std::path::PathBuf::from("a.txt")
+    );
+
+    let mod_time = std::fs::metadata(target.path("/a.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(
+        mod_time,
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(START - DAY)
+    );
+}
@@ -0,0 +1,112 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::{backup, storage::memory::MemoryStorage, throttle};
+use chrono::NaiveTime;
+use common::{config, try_config, versions, Hooked, Hooks, Tree, DAY, START};
+use std::{sync::Mutex, time::Duration};
+use tokio::time::Instant;
+
+fn at(hour: u32, minute: u32) -> NaiveTime {
+    NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+}
+
+#[test]
+fn schedules_override_the_global_limit() {
+    let conf = config(
+        "/",
+        "upload_limit_kib: 1000
+upload_limit_schedule:
+  - 08:00-18:00 100
+  - 22:00-06:00 0
+",
+    );
+    let throttle = throttle::from_config(&conf).unwrap();
+
+    assert_eq!(throttle.limit_at(at(9, 0)), Some(100 * 1024));
+    assert_eq!(throttle.limit_at(at(18, 0)), Some(1000 * 1024));
+    // Windows may span midnight, 0 lifts the limit
+    assert_eq!(throttle.limit_at(at(23, 30)), None);
+    assert_eq!(throttle.limit_at(at(5, 59)), None);
+    assert_eq!(throttle.limit_at(at(6, 0)), Some(1000 * 1024));
+}
+
+#[test]
+fn limits_are_validated() {
+    assert!(throttle::from_config(&config("/", "")).is_none());
+
+    for extra in [
+        "upload_limit_kib: -1\n",
+        "upload_limit_schedule: [\"08:00-18:00\"]\n",
+        "upload_limit_schedule: [\"8am-6pm 100\"]\n",
+        "upload_limit_schedule: [\"08:00-18:00 fast\"]\n",
+    ] {
+        assert!(try_config("/", extra).is_err(), "{extra}");
+    }
+}
+
+// The clock only advances when every task waits, so the test takes no time
+#[tokio::test(start_paused = true)]
+async fn uploads_are_limited() {
+    // Three blocks of 4MiB at 4MiB per second
+    let tree = Tree::new();
+    tree.write("/disk.img", &"x".repeat(12 << 20), START - DAY);
+
+    let storage = MemoryStorage::new();
+    let conf = config(&tree.root(), "upload_limit_kib: 4096\n");
+    let started = Instant::now();
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    // The first block goes out right away, the other two wait for a second each
+    assert_eq!(started.elapsed(), Duration::from_secs(2));
+    assert_eq!(versions(&storage, "/disk.img").await.len(), 1);
+}
+
+/// Records when each block upload reaches the storage.
+struct BlockStarts {
+    started: Instant,
+    starts: Mutex<Vec<Duration>>,
+}
+
+impl Hooks for BlockStarts {
+    fn before(&self, call: &str, _name: &str) -> anyhow::Result<()> {
+        if call == "put_block" {
+            self.starts.lock().unwrap().push(self.started.elapsed());
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn throttled_blocks_dont_hold_up_pending_ones() {
+    let tree = Tree::new();
+    tree.write("/disk.img", &"x".repeat(12 << 20), START - DAY);
+
+    let storage = Hooked::new(BlockStarts {
+        started: Instant::now(),
+        starts: Mutex::new(Vec::new()),
+    });
+    let conf = config(&tree.root(), "upload_limit_kib: 4096\n");
+    backup::run(&conf, &storage, START).await.unwrap();
+
+    // Each block goes out as soon as the throttle lets it, not after the last one was let through
+    let starts = storage.hooks.starts.lock().unwrap().clone();
+    let seconds: Vec<_> = starts.iter().map(|start| start.as_secs()).collect();
+    assert_eq!(seconds, [0, 1, 2]);
+}
@@ -0,0 +1,85 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use azure_blob_backup::timestamp;
+use chrono::{Local, NaiveDate, TimeZone};
+use common::START;
+
+/// The unix timestamp of a time of day in the local time zone, which the tests don't control.
+fn local(year: i32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> u64 {
+    let naive = NaiveDate::from_ymd_opt(year, month, day)
+        .unwrap()
+        .and_hms_opt(hour, min, sec)
+        .unwrap();
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .unwrap()
+        .timestamp() as u64
+}
+
+#[test]
+fn unix_timestamps_and_rfc_3339_are_exact() {
+    assert_eq!(timestamp::parse("1700000000").unwrap(), START);
+    assert_eq!(timestamp::parse("0").unwrap(), 0);
+    assert_eq!(timestamp::parse("2023-11-14T22:13:20Z").unwrap(), START);
+    assert_eq!(
+        timestamp::parse("2023-11-15T00:13:20+02:00").unwrap(),
+        START
+    );
+}
+
+#[test]
+fn dates_without_a_zone_are_local_time() {
+    assert_eq!(
+        timestamp::parse("2023-11-14 22:13:20").unwrap(),
+        local(2023, 11, 14, 22, 13, 20)
+    );
+    assert_eq!(
+        timestamp::parse("2023-11-14T22:13:20").unwrap(),
+        local(2023, 11, 14, 22, 13, 20)
+    );
+    assert_eq!(
+        timestamp::parse("2023-11-14 22:13").unwrap(),
+        local(2023, 11, 14, 22, 13, 0)
+    );
+    assert_eq!(
+        timestamp::parse("2023-11-14").unwrap(),
+        local(2023, 11, 14, 0, 0, 0)
+    );
+}
+
+#[test]
+fn formatted_times_parse_back() {
+    assert_eq!(timestamp::parse(&timestamp::format(START)).unwrap(), START);
+}
+
+#[test]
+fn malformed_times_are_rejected() {
+    for raw in [
+        "",
+        "yesterday",
+        "-5",
+        "2023-13-01",
+        "2023-11-14 25:00",
+        "1969-12-31T23:59:59Z",
+    ] {
+        assert!(timestamp::parse(raw).is_err(), "{raw}");
+    }
+}
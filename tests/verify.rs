@@ -0,0 +1,274 @@
+/**
+    Copyright (C) 2023  Florian Kramer
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+#[allow(dead_code)]
+mod common;
+
+use anyhow::Result;
+use azure_blob_backup::{
+    backup, crypto, dedup,
+    index::blob_name,
+    storage::{memory::MemoryStorage, Storage},
+    verify,
+};
+use bytes::Bytes;
+use common::{config, versions, Hooked, Hooks, Tree, DAY, START};
+use std::{collections::HashMap, sync::Mutex};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+const DEDUP: &str = "deduplication: true\ndeduplication_min_size: 1048576\n";
+
+fn options(sample: f64) -> verify::Options {
+    verify::Options {
+        path: "/".to_string(),
+        sample,
+    }
+}
+
+/// Backs up a few files into memory with the settings in `extra`.
+async fn backup(extra: &str) -> MemoryStorage {
+    let tree = Tree::new();
+    tree.write("/a.txt", "hello", START - DAY);
+    tree.write(
+        "/app.log",
+        &"INFO request handled\n".repeat(1000),
+        START - DAY,
+    );
+    tree.write("/empty.txt", "", START - DAY);
+    tree.write("/disk.img", &"0123456789".repeat(200_000), START - DAY);
+    tree.symlink("/link", "a.txt");
+
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), extra), &storage, START)
+        .await
+        .unwrap();
+    storage
+}
+
+/// Replaces the content of the blob, keeping the checksum it was committed with.
+async fn overwrite(storage: &MemoryStorage, name: &str, content: &[u8]) {
+    let checksum = storage.checksum(name).await.unwrap().unwrap();
+    storage
+        .put_block(name, "block", Bytes::copy_from_slice(content))
+        .await
+        .unwrap();
+    storage
+        .put_block_list(name, &["block".to_string()], &checksum)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn intact_backups_verify() {
+    for extra in [
+        "".to_string(),
+        "compression: true\ncontent_hash: true\n".to_string(),
+        format!("encryption_key: \"{KEY}\"\ncompression: true\n{DEDUP}"),
+    ] {
+        let storage = backup(&extra).await;
+        let key = crypto::from_config(&config("/", &extra)).unwrap();
+
+        let report = verify::run(&storage, key.as_ref(), &options(100.0))
+            .await
+            .unwrap();
+        assert!(!report.has_problems(), "{extra}: {report:?}");
+        assert_eq!(report.verified, 5);
+    }
+}
+
+/// Counts the downloads of every blob.
+#[derive(Default)]
+struct CountingGets {
+    gets: Mutex<HashMap<String, usize>>,
+}
+
+impl Hooks for CountingGets {
+    fn before(&self, call: &str, name: &str) -> Result<()> {
+        if call == "get" {
+            *self
+                .gets
+                .lock()
+                .unwrap()
+                .entry(name.to_string())
+                .or_default() += 1;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn chunks_are_downloaded_once_per_reference() {
+    let storage = backup(DEDUP).await;
+    let disk = &versions(&storage, "/disk.img").await[0];
+    let manifest = dedup::read_manifest(&storage, "/disk.img", disk, None)
+        .await
+        .unwrap();
+
+    let hooked = Hooked {
+        inner: &storage as &dyn Storage,
+        hooks: CountingGets::default(),
+    };
+    let report = verify::run(&hooked, None, &options(100.0)).await.unwrap();
+    assert!(!report.has_problems(), "{report:?}");
+
+    // The chunk ids and the checksum of the version are checked from the same download
+    let gets = hooked.hooks.gets.lock().unwrap();
+    for chunk in &manifest {
+        let references = manifest.iter().filter(|name| *name == chunk).count();
+        assert_eq!(gets[chunk], references, "{chunk}");
+    }
+    assert_eq!(gets[&blob_name("/disk.img", disk)], 1);
+}
+
+#[tokio::test]
+async fn corrupt_blobs_are_reported() {
+    let storage = backup("").await;
+    let version = &versions(&storage, "/a.txt").await[0];
+    let name = blob_name("/a.txt", version);
+    overwrite(&storage, &name, b"jello").await;
+
+    let report = verify::run(&storage, None, &options(100.0)).await.unwrap();
+    assert_eq!(report.verified, 4);
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].0, name);
+    assert!(report.has_problems());
+
+    // Encrypted blobs fail to decrypt instead
+    let conf = format!("encryption_key: \"{KEY}\"\n");
+    let storage = backup(&conf).await;
+    let key = crypto::from_config(&config("/", &conf)).unwrap();
+    let version = &versions(&storage, "/app.log").await[0];
+    let name = blob_name("/app.log", version);
+    let mut content = storage.content(&name).unwrap();
+    content[100] ^= 1;
+    overwrite(&storage, &name, &content).await;
+
+    let report = verify::run(&storage, key.as_ref(), &options(100.0))
+        .await
+        .unwrap();
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].0, name);
+}
+
+#[tokio::test]
+async fn corrupt_empty_files_are_reported() {
+    for extra in [
+        "compression: true\n".to_string(),
+        format!("encryption_key: \"{KEY}\"\n"),
+    ] {
+        let storage = backup(&extra).await;
+        let key = crypto::from_config(&config("/", &extra)).unwrap();
+        let name = blob_name("/empty.txt", &versions(&storage, "/empty.txt").await[0]);
+        let mut content = storage.content(&name).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 1;
+        overwrite(&storage, &name, &content).await;
+
+        let report = verify::run(&storage, key.as_ref(), &options(100.0))
+            .await
+            .unwrap();
+        assert_eq!(report.corrupt.len(), 1, "{extra}");
+        assert_eq!(report.corrupt[0].0, name);
+    }
+}
+
+#[tokio::test]
+async fn corrupt_links_and_manifests_are_reported() {
+    let tree = Tree::new();
+    tree.write("/a.img", &"a".repeat(1 << 20), START - DAY);
+    tree.write("/b.img", &"b".repeat(1 << 20), START - DAY);
+    tree.symlink("/link", "a.img");
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), DEDUP), &storage, START)
+        .await
+        .unwrap();
+
+    let link = blob_name("/link", &versions(&storage, "/link").await[0]);
+    overwrite(&storage, &link, b"b.img").await;
+    // The manifest lists stored chunks of the right size, but not the ones of the file
+    let a = blob_name("/a.img", &versions(&storage, "/a.img").await[0]);
+    let b = blob_name("/b.img", &versions(&storage, "/b.img").await[0]);
+    overwrite(&storage, &a, &storage.content(&b).unwrap()).await;
+
+    let report = verify::run(&storage, None, &options(100.0)).await.unwrap();
+    let mut corrupt: Vec<_> = report.corrupt.into_iter().map(|(name, _)| name).collect();
+    corrupt.sort();
+    assert_eq!(corrupt, [a, link]);
+    assert_eq!(report.verified, 1);
+}
+
+#[tokio::test]
+async fn corrupt_chunks_are_reported() {
+    // Chunks are checked against their names
+    let storage = backup(DEDUP).await;
+    let chunk = storage
+        .names()
+        .into_iter()
+        .find(|name| name.starts_with("/.azure_blob_backup/chunks/"))
+        .unwrap();
+    let mut content = storage.content(&chunk).unwrap();
+    content[10] ^= 1;
+    storage.put(&chunk, content.into()).await.unwrap();
+
+    let report = verify::run(&storage, None, &options(100.0)).await.unwrap();
+    let disk = &versions(&storage, "/disk.img").await[0];
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.corrupt[0].0, blob_name("/disk.img", disk));
+    assert!(
+        report.corrupt[0].1.contains(&chunk),
+        "{}",
+        report.corrupt[0].1
+    );
+    assert_eq!(report.verified, 4);
+}
+
+#[tokio::test]
+async fn missing_chunks_and_unparseable_names_are_reported() {
+    let storage = backup(DEDUP).await;
+    let chunk = storage
+        .names()
+        .into_iter()
+        .find(|name| name.starts_with("/.azure_blob_backup/chunks/"))
+        .unwrap();
+    storage.delete(&chunk).await.unwrap();
+    storage
+        .put("/notes.txt/latest", Bytes::new())
+        .await
+        .unwrap();
+
+    let report = verify::run(&storage, None, &options(100.0)).await.unwrap();
+    let disk = &versions(&storage, "/disk.img").await[0];
+    assert_eq!(report.missing, vec![blob_name("/disk.img", disk)]);
+    assert_eq!(report.unparseable, vec!["/notes.txt/latest".to_string()]);
+    assert!(report.corrupt.is_empty());
+}
+
+#[tokio::test]
+async fn only_a_sample_is_verified() {
+    let tree = Tree::new();
+    for i in 0..200 {
+        tree.write(&format!("/{i}.txt"), "hello", START - DAY);
+    }
+    let storage = MemoryStorage::new();
+    backup::run(&config(&tree.root(), ""), &storage, START)
+        .await
+        .unwrap();
+
+    let report = verify::run(&storage, None, &options(25.0)).await.unwrap();
+    assert!(report.verified > 10 && report.verified < 100);
+    assert!(!report.has_problems());
+}